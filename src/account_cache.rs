@@ -0,0 +1,245 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::time::{Duration, Instant};
+
+// a least recently used cache of the accounts, which evicts for its capacity itself so that the
+// manager databases can see which account is dropped: LruCache::insert doesn't say, and keeps its
+// order in a list it searches on every use. Here each use is stamped from a counter, the order kept
+// by stamp, so each insert, lookup and removal is O(log n). Entries untouched for longer than the
+// ttl are dropped silently, as by the LruCache.
+#[derive(Clone)]
+pub struct AccountCache<Key, Value> {
+    entries: BTreeMap<Key, Entry<Value>>,
+    // the key of each entry by the stamp of its last use, so least recently used first
+    order: BTreeMap<u64, Key>,
+    next_stamp: u64,
+    capacity: usize,
+    time_to_live: Option<Duration>,
+}
+
+#[derive(Clone)]
+struct Entry<Value> {
+    value: Value,
+    stamp: u64,
+    used: Instant,
+}
+
+// in key order, skipping expired entries without dropping them
+pub struct PeekIter<'a, Key: 'a, Value: 'a> {
+    entries: btree_map::Iter<'a, Key, Entry<Value>>,
+    time_to_live: Option<Duration>,
+}
+
+impl<'a, Key, Value> Iterator for PeekIter<'a, Key, Value> {
+    type Item = (&'a Key, &'a Value);
+
+    fn next(&mut self) -> Option<(&'a Key, &'a Value)> {
+        while let Some((key, entry)) = self.entries.next() {
+            if !expired(entry, self.time_to_live) {
+                return Some((key, &entry.value));
+            }
+        }
+        None
+    }
+}
+
+impl<Key: PartialOrd + Ord + Clone, Value> AccountCache<Key, Value> {
+    pub fn with_capacity(capacity: usize) -> AccountCache<Key, Value> {
+        AccountCache {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            next_stamp: 0,
+            capacity: capacity,
+            time_to_live: None,
+        }
+    }
+
+    pub fn with_expiry_duration_and_capacity(time_to_live: Duration, capacity: usize) -> AccountCache<Key, Value> {
+        let mut cache = AccountCache::with_capacity(capacity);
+        cache.time_to_live = Some(time_to_live);
+        cache
+    }
+
+    // if key isn't held and the cache is full, removes and returns the least recently used entry to
+    // make room for it, which insert would otherwise drop unseen
+    pub fn evict_for(&mut self, key: &Key) -> Option<(Key, Value)> {
+        self.drop_expired();
+        if self.entries.contains_key(key) || self.entries.len() < self.capacity {
+            return None;
+        }
+        let oldest = match self.order.iter().next() {
+            Some((_, oldest)) => oldest.clone(),
+            None => return None,
+        };
+        self.remove(&oldest).map(|value| (oldest, value))
+    }
+
+    // returns the value replaced under key, as LruCache::insert
+    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        let _ = self.evict_for(&key);
+        let stamp = self.stamp(&key);
+        let entry = Entry { value: value, stamp: stamp, used: Instant::now() };
+        self.entries.insert(key, entry).map(|replaced| replaced.value)
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        self.entries.remove(key).map(|entry| {
+            let _ = self.order.remove(&entry.stamp);
+            entry.value
+        })
+    }
+
+    pub fn get_mut(&mut self, key: &Key) -> Option<&mut Value> {
+        self.drop_expired();
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        let stamp = self.stamp(key);
+        self.entries.get_mut(key).map(|entry| {
+            entry.stamp = stamp;
+            entry.used = Instant::now();
+            &mut entry.value
+        })
+    }
+
+    pub fn peek(&self, key: &Key) -> Option<&Value> {
+        self.entries.get(key).and_then(|entry| {
+            if expired(entry, self.time_to_live) { None } else { Some(&entry.value) }
+        })
+    }
+
+    pub fn peek_iter(&self) -> PeekIter<Key, Value> {
+        PeekIter { entries: self.entries.iter(), time_to_live: self.time_to_live }
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.peek(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        let expired_count = self.order.values()
+                                      .take_while(|key| self.entries.get(*key)
+                                                            .map_or(false, |entry| expired(entry, self.time_to_live)))
+                                      .count();
+        self.entries.len() - expired_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // a fresh stamp for key as just used, moving it to the back of the order
+    fn stamp(&mut self, key: &Key) -> u64 {
+        if let Some(entry) = self.entries.get(key) {
+            let _ = self.order.remove(&entry.stamp);
+        }
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        let _ = self.order.insert(stamp, key.clone());
+        stamp
+    }
+
+    // entries expire in the order they were last used, so only the front need be checked
+    fn drop_expired(&mut self) {
+        loop {
+            let oldest = match self.order.iter().next() {
+                Some((_, oldest)) => oldest.clone(),
+                None => return,
+            };
+            if !self.entries.get(&oldest).map_or(false, |entry| expired(entry, self.time_to_live)) {
+                return;
+            }
+            let _ = self.remove(&oldest);
+        }
+    }
+}
+
+fn expired<Value>(entry: &Entry<Value>, time_to_live: Option<Duration>) -> bool {
+    time_to_live.map_or(false, |time_to_live| entry.used.elapsed() > time_to_live)
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccountCache;
+
+    #[test]
+    fn evict_for() {
+        let mut cache: AccountCache<u8, u64> = AccountCache::with_capacity(3);
+        for key in 0..3u8 {
+            assert_eq!(cache.evict_for(&key), None);
+            assert_eq!(cache.insert(key, key as u64 * 1024), None);
+        }
+        // a key already held needs no room, and using one moves it to the back
+        assert_eq!(cache.evict_for(&1), None);
+        assert_eq!(cache.get_mut(&0).cloned(), Some(0));
+        assert_eq!(cache.evict_for(&3), Some((1, 1024)));
+        assert_eq!(cache.insert(3, 3072), None);
+        assert_eq!(cache.len(), 3);
+
+        // insert evicts as the LruCache would, least recently used first
+        assert_eq!(cache.insert(4, 4096), None);
+        assert!(!cache.contains_key(&2));
+        assert_eq!(cache.insert(0, 1), Some(0));
+        let held: Vec<(u8, u64)> = cache.peek_iter().map(|(&key, &value)| (key, value)).collect();
+        assert_eq!(held, vec![(0, 1), (3, 3072), (4, 4096)]);
+
+        assert_eq!(cache.remove(&3), Some(3072));
+        assert_eq!(cache.evict_for(&5), None);
+        assert_eq!(cache.insert(5, 5120), None);
+        assert_eq!(cache.evict_for(&6), Some((4, 4096)));
+    }
+
+    #[test]
+    fn lru_order() {
+        let count = 10000u32;
+        let mut cache: AccountCache<u32, u32> = AccountCache::with_capacity(count as usize);
+        for key in 0..count {
+            assert_eq!(cache.insert(key, key), None);
+        }
+        // using the even keys leaves the odd ones least recently used, in the order inserted
+        for key in (0..count).filter(|key| key % 2 == 0) {
+            *cache.get_mut(&key).unwrap() += 1;
+        }
+        let oldest_first = (0..count).filter(|key| key % 2 == 1).chain((0..count).filter(|key| key % 2 == 0));
+        for (newer, key) in (count..).zip(oldest_first) {
+            let value = if key % 2 == 0 { key + 1 } else { key };
+            assert_eq!(cache.evict_for(&newer), Some((key, value)));
+            assert_eq!(cache.insert(newer, 0), None);
+        }
+        assert_eq!(cache.len(), count as usize);
+        assert_eq!(cache.peek_iter().next(), Some((&count, &0)));
+    }
+
+    #[test]
+    fn expired() {
+        let mut cache: AccountCache<u8, u64> =
+            AccountCache::with_expiry_duration_and_capacity(::std::time::Duration::from_millis(20), 2);
+        assert_eq!(cache.insert(0, 0), None);
+        assert_eq!(cache.insert(1, 1024), None);
+        ::std::thread::sleep(::std::time::Duration::from_millis(40));
+        // the expired entries make room without being returned
+        assert_eq!(cache.evict_for(&2), None);
+        assert_eq!(cache.insert(2, 2048), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.peek(&2), Some(&2048));
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#![allow(dead_code)]
+
+use maid_manager;
+use maid_manager::MaidManagerDatabase;
+use pmid_manager;
+use pmid_manager::PmidManagerDatabase;
+use routing::NameType;
+use routing::generic_sendable_type::GenericSendableType;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ManagerType {
+    MaidManager,
+    PmidManager,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PutError {
+    Maid(maid_manager::PutError),
+    Pmid(pmid_manager::PutError),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum TransferError {
+    Maid(maid_manager::DecodeError),
+    Pmid(pmid_manager::DecodeError),
+}
+
+// the accounts a vault keeps as both maid manager, for the clients storing data, and pmid manager,
+// for the nodes holding it
+pub struct VaultAccounting {
+    maid_manager: MaidManagerDatabase,
+    pmid_manager: PmidManagerDatabase,
+}
+
+impl VaultAccounting {
+    pub fn new() -> VaultAccounting {
+        VaultAccounting::with_databases(MaidManagerDatabase::new(), PmidManagerDatabase::new())
+    }
+
+    pub fn with_databases(maid_manager: MaidManagerDatabase, pmid_manager: PmidManagerDatabase) -> VaultAccounting {
+        VaultAccounting { maid_manager: maid_manager, pmid_manager: pmid_manager }
+    }
+
+    pub fn maid_manager(&self) -> &MaidManagerDatabase {
+        &self.maid_manager
+    }
+
+    pub fn maid_manager_mut(&mut self) -> &mut MaidManagerDatabase {
+        &mut self.maid_manager
+    }
+
+    pub fn pmid_manager(&self) -> &PmidManagerDatabase {
+        &self.pmid_manager
+    }
+
+    pub fn pmid_manager_mut(&mut self) -> &mut PmidManagerDatabase {
+        &mut self.pmid_manager
+    }
+
+    // size bytes put by client and held by pmid_node, charged to both accounts or to neither: a put
+    // the node has no room for is rejected before the client is charged, and one the client has no
+    // allowance for never reaches the node's account
+    pub fn handle_put(&mut self, client: &NameType, pmid_node: &NameType, size: u64) -> Result<(), PutError> {
+        if !self.pmid_manager.can_put(pmid_node, size) {
+            return self.pmid_manager.put_data(pmid_node, size).map_err(PutError::Pmid);
+        }
+        try!(self.maid_manager.put_data(client, size).map_err(PutError::Maid));
+        self.pmid_manager.put_data(pmid_node, size).map_err(PutError::Pmid)
+    }
+
+    // returns the bytes actually freed from the client's account and from the node's, as the
+    // databases' delete_data
+    pub fn handle_delete(&mut self, client: &NameType, pmid_node: &NameType, size: u64) -> (u64, u64) {
+        (self.maid_manager.delete_data(client, size), self.pmid_manager.delete_data(pmid_node, size))
+    }
+
+    // the accounts of close_group from both databases, the maid manager's first, each with the
+    // manager to pass it on to
+    pub fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<(ManagerType, GenericSendableType)> {
+        let mut transfers: Vec<(ManagerType, GenericSendableType)> =
+            self.maid_manager.retrieve_all_and_reset(close_group).into_iter()
+                             .map(|sendable| (ManagerType::MaidManager, sendable))
+                             .collect();
+        transfers.extend(self.pmid_manager.retrieve_all_and_reset(close_group).into_iter()
+                                          .map(|sendable| (ManagerType::PmidManager, sendable)));
+        transfers
+    }
+
+    // the receiving end of retrieve_all_and_reset
+    pub fn handle_account_transfer(&mut self, manager: ManagerType, name: NameType, serialised: &[u8])
+            -> Result<(), TransferError> {
+        match manager {
+            ManagerType::MaidManager =>
+                self.maid_manager.handle_account_transfer(name, serialised).map_err(TransferError::Maid),
+            ManagerType::PmidManager =>
+                self.pmid_manager.handle_account_transfer(name, serialised).map_err(TransferError::Pmid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use maid_manager;
+    use maid_manager::AccountCreation;
+    use pmid_manager;
+    use routing;
+    use routing::NameType;
+    use routing::sendable::Sendable;
+    use super::{ManagerType, PutError, TransferError, VaultAccounting};
+
+    #[test]
+    fn handle_put() {
+        let mut accounting = VaultAccounting::new();
+        let client: NameType = routing::test_utils::Random::generate_random();
+        let pmid_node: NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(accounting.handle_put(&client, &pmid_node, 1024),
+                   Err(PutError::Maid(maid_manager::PutError::AccountNotFound)));
+        assert_eq!(accounting.pmid_manager().usage(&pmid_node), None);
+
+        let allowance = accounting.maid_manager().default_allowance();
+        assert_eq!(accounting.maid_manager_mut().create_account(client.clone(), AccountCreation::new(allowance)), Ok(()));
+        assert_eq!(accounting.handle_put(&client, &pmid_node, 1024), Ok(()));
+        assert_eq!(accounting.maid_manager().data_stored(&client), Some(1024));
+        assert_eq!(accounting.pmid_manager().usage(&pmid_node), Some((1024, 0, 1073741824)));
+
+        // the node has no room, so the client isn't charged
+        assert_eq!(accounting.handle_put(&client, &pmid_node, 1073741824),
+                   Err(PutError::Pmid(pmid_manager::PutError::NotEnoughSpace { requested: 1073741824,
+                                                                               available: 1073740800 })));
+        assert_eq!(accounting.maid_manager().data_stored(&client), Some(1024));
+
+        assert_eq!(accounting.handle_delete(&client, &pmid_node, 512), (512, 512));
+        assert_eq!(accounting.maid_manager().data_stored(&client), Some(512));
+        assert_eq!(accounting.pmid_manager().usage(&pmid_node), Some((512, 0, 1073741824)));
+    }
+
+    #[test]
+    fn retrieve_all_and_reset() {
+        let mut sender = VaultAccounting::new();
+        let client: NameType = routing::test_utils::Random::generate_random();
+        let pmid_node: NameType = routing::test_utils::Random::generate_random();
+        let allowance = sender.maid_manager().default_allowance();
+        assert_eq!(sender.maid_manager_mut().create_account(client.clone(), AccountCreation::new(allowance)), Ok(()));
+        assert_eq!(sender.handle_put(&client, &pmid_node, 1024), Ok(()));
+
+        let transfers = sender.retrieve_all_and_reset(&vec![client.clone(), pmid_node.clone()]);
+        let managers: Vec<ManagerType> = transfers.iter().map(|&(manager, _)| manager).collect();
+        assert_eq!(managers, vec![ManagerType::MaidManager, ManagerType::PmidManager]);
+        assert_eq!(transfers[0].1.type_tag(), maid_manager::MAID_MANAGER_ACCOUNT_TAG);
+        assert_eq!(transfers[1].1.type_tag(), pmid_manager::PMID_MANAGER_ACCOUNT_TAG);
+        assert_eq!(sender.maid_manager().data_stored(&client), None);
+        assert_eq!(sender.pmid_manager().usage(&pmid_node), None);
+
+        let mut receiver = VaultAccounting::new();
+        for (manager, sendable) in transfers {
+            assert_eq!(receiver.handle_account_transfer(manager, sendable.name(), &sendable.serialised_contents()), Ok(()));
+        }
+        assert_eq!(receiver.maid_manager().data_stored(&client), Some(1024));
+        assert_eq!(receiver.pmid_manager().usage(&pmid_node), Some((1024, 0, 1073741824)));
+
+        assert_eq!(receiver.handle_account_transfer(ManagerType::MaidManager, client.clone(), &[]),
+                   Err(TransferError::Maid(maid_manager::DecodeError::Empty)));
+        assert_eq!(receiver.handle_account_transfer(ManagerType::PmidManager, pmid_node.clone(), &[]),
+                   Err(TransferError::Pmid(pmid_manager::DecodeError::Empty)));
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#![allow(dead_code)]
+
+use std::fmt;
+use std::str::FromStr;
+
+const UNITS: [&'static str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// A number of bytes, which can be parsed from a size such as "512MiB", "1.5 GiB" or "4096".
+///
+/// The units are B and the binary multiples KiB to EiB, matched without regard to case; a number
+/// without a unit is in bytes. A fractional size is rounded down to a whole number of bytes.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub struct ByteSize(u64);
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum ParseByteSizeError {
+    Empty,
+    InvalidNumber(String),
+    UnknownUnit(String),
+    Overflow,
+}
+
+impl fmt::Display for ParseByteSizeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseByteSizeError::Empty => write!(formatter, "no size given"),
+            ParseByteSizeError::InvalidNumber(ref number) => write!(formatter, "invalid number \"{}\" in size", number),
+            ParseByteSizeError::UnknownUnit(ref unit) => write!(formatter, "unknown unit \"{}\" in size", unit),
+            ParseByteSizeError::Overflow => write!(formatter, "size is more than 2^64 - 1 bytes"),
+        }
+    }
+}
+
+impl ByteSize {
+    pub fn new(bytes: u64) -> ByteSize {
+        ByteSize(bytes)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = ParseByteSizeError;
+
+    fn from_str(size: &str) -> Result<ByteSize, ParseByteSizeError> {
+        let size = size.trim();
+        if size.is_empty() {
+            return Err(ParseByteSizeError::Empty);
+        }
+        let split = size.find(|c: char| !(c.is_digit(10) || c == '.')).unwrap_or(size.len());
+        let (number, unit) = (&size[..split], size[split..].trim_start());
+        let multiplier = match unit_multiplier(unit) {
+            Some(multiplier) => multiplier,
+            None => return Err(ParseByteSizeError::UnknownUnit(unit.to_string())),
+        };
+        let invalid_number = || ParseByteSizeError::InvalidNumber(number.to_string());
+        let (whole, fraction) = match number.find('.') {
+            Some(point) => (&number[..point], &number[point + 1..]),
+            None => (number, ""),
+        };
+        if whole.is_empty() && fraction.is_empty() || fraction.contains('.') || fraction.len() > 18 {
+            return Err(invalid_number());
+        }
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            match whole.parse::<u64>() {
+                Ok(whole) => whole,
+                Err(_) if whole.chars().all(|c| c.is_digit(10)) => return Err(ParseByteSizeError::Overflow),
+                Err(_) => return Err(invalid_number()),
+            }
+        };
+        let bytes = match whole.checked_mul(multiplier) {
+            Some(bytes) => bytes,
+            None => return Err(ParseByteSizeError::Overflow),
+        };
+        let fraction_bytes = if fraction.is_empty() {
+            0
+        } else {
+            let numerator: u64 = match fraction.parse() {
+                Ok(numerator) => numerator,
+                Err(_) => return Err(invalid_number()),
+            };
+            let denominator = 10u64.pow(fraction.len() as u32);
+            // numerator < denominator, so this is below multiplier and can't overflow as a u128
+            ((numerator as u128 * multiplier as u128) / denominator as u128) as u64
+        };
+        match bytes.checked_add(fraction_bytes) {
+            Some(bytes) => Ok(ByteSize(bytes)),
+            None => Err(ParseByteSizeError::Overflow),
+        }
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", human_readable(self.0))
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> u64 {
+        size.0
+    }
+}
+
+fn unit_multiplier(unit: &str) -> Option<u64> {
+    if unit.is_empty() || unit.eq_ignore_ascii_case("B") {
+        return Some(1);
+    }
+    UNITS.iter().position(|known| known.eq_ignore_ascii_case(unit)).map(|index| 1u64 << (10 * (index + 1)))
+}
+
+/// `bytes` in the largest binary unit it reaches, to two decimal places, e.g. "1.50 GiB".
+/// Anything under a KiB is given exactly, e.g. "512 B".
+pub fn human_readable(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+/// `used` as a percentage of `capacity`, which is over 100 once over-committed; 100 if there is no
+/// capacity at all, as the accounts' `utilization` takes one with none to be full.
+pub fn utilisation(used: u64, capacity: u64) -> f64 {
+    if capacity == 0 {
+        100.0
+    } else {
+        used as f64 * 100.0 / capacity as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{human_readable, utilisation, ByteSize, ParseByteSizeError};
+
+    #[test]
+    fn human_readable_sizes() {
+        assert_eq!(human_readable(0), "0 B");
+        assert_eq!(human_readable(1023), "1023 B");
+        assert_eq!(human_readable(1024), "1.00 KiB");
+        assert_eq!(human_readable(1536), "1.50 KiB");
+        assert_eq!(human_readable(512 * 1024 * 1024), "512.00 MiB");
+        assert_eq!(human_readable(1073741824), "1.00 GiB");
+        assert_eq!(human_readable(::std::u64::MAX), "16.00 EiB");
+        assert_eq!(utilisation(512, 2048), 25.0);
+        assert_eq!(utilisation(4096, 2048), 200.0);
+        assert_eq!(utilisation(1, 0), 100.0);
+        assert_eq!(utilisation(0, 0), 100.0);
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = |size: &str| size.parse::<ByteSize>().map(|size| size.as_u64());
+        assert_eq!(parsed("4096"), Ok(4096));
+        assert_eq!(parsed("0 B"), Ok(0));
+        assert_eq!(parsed("1KiB"), Ok(1024));
+        assert_eq!(parsed("512MiB"), Ok(536870912));
+        assert_eq!(parsed("1GiB"), Ok(1073741824));
+        assert_eq!(parsed(" 1 gib "), Ok(1073741824));
+        assert_eq!(parsed("1.5GiB"), Ok(1610612736));
+        assert_eq!(parsed(".5KiB"), Ok(512));
+        assert_eq!(parsed("2TiB"), Ok(2199023255552));
+        assert_eq!(parsed("15EiB"), Ok(15 << 60));
+        assert_eq!(parsed("18446744073709551615"), Ok(::std::u64::MAX));
+
+        assert_eq!(parsed(""), Err(ParseByteSizeError::Empty));
+        assert_eq!(parsed("GiB"), Err(ParseByteSizeError::InvalidNumber("".to_string())));
+        assert_eq!(parsed("1.2.3MiB"), Err(ParseByteSizeError::InvalidNumber("1.2.3".to_string())));
+        assert_eq!(parsed("-1GiB"), Err(ParseByteSizeError::UnknownUnit("-1GiB".to_string())));
+        assert_eq!(parsed("1GB"), Err(ParseByteSizeError::UnknownUnit("GB".to_string())));
+        assert_eq!(parsed("1 GiB extra"), Err(ParseByteSizeError::UnknownUnit("GiB extra".to_string())));
+        assert_eq!(parsed("16EiB"), Err(ParseByteSizeError::Overflow));
+        assert_eq!(parsed("18446744073709551616"), Err(ParseByteSizeError::Overflow));
+        assert_eq!(ParseByteSizeError::UnknownUnit("GB".to_string()).to_string(), "unknown unit \"GB\" in size");
+
+        // formatting and parsing again gives back the same size for whole units
+        for &bytes in [1024u64, 536870912, 1073741824, 3 << 40].iter() {
+            assert_eq!(ByteSize::new(bytes).to_string().parse::<ByteSize>(), Ok(ByteSize::new(bytes)));
+        }
+        assert_eq!(u64::from(ByteSize::new(512)), 512);
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+// end-to-end checks of the account transfers made on churn, from retrieve_all_and_reset on the
+// managers losing the accounts to handle_account_transfer on those gaining them
+
+use maid_manager::{AccountCreation, MaidManagerDatabase, MAID_MANAGER_ACCOUNT_TAG};
+use pmid_manager::{PmidManagerDatabase, PMID_MANAGER_ACCOUNT_TAG};
+use routing::NameType;
+use routing::generic_sendable_type::GenericSendableType;
+use routing::sendable::Sendable;
+
+trait Manager {
+    fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<GenericSendableType>;
+    // the managers' DecodeErrors differ, so only the message is passed on
+    fn handle_account_transfer(&mut self, name: NameType, serialised: &[u8]) -> Result<(), String>;
+}
+
+impl Manager for MaidManagerDatabase {
+    fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<GenericSendableType> {
+        MaidManagerDatabase::retrieve_all_and_reset(self, close_group)
+    }
+
+    fn handle_account_transfer(&mut self, name: NameType, serialised: &[u8]) -> Result<(), String> {
+        MaidManagerDatabase::handle_account_transfer(self, name, serialised).map_err(|error| error.to_string())
+    }
+}
+
+impl Manager for PmidManagerDatabase {
+    fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<GenericSendableType> {
+        PmidManagerDatabase::retrieve_all_and_reset(self, close_group)
+    }
+
+    fn handle_account_transfer(&mut self, name: NameType, serialised: &[u8]) -> Result<(), String> {
+        PmidManagerDatabase::handle_account_transfer(self, name, serialised).map_err(|error| error.to_string())
+    }
+}
+
+// the close group of the accounts in moved has changed so that receiver now manages them: sender
+// hands over each of them it holds, as on churn. Returns the type tag of every account sent.
+fn churn_simulation<M: Manager>(sender: &mut M, receiver: &mut M, moved: &Vec<NameType>) -> Vec<u64> {
+    let mut tags = Vec::new();
+    for element in sender.retrieve_all_and_reset(moved) {
+        tags.push(element.type_tag());
+        assert_eq!(receiver.handle_account_transfer(element.name(), &element.serialised_contents()), Ok(()));
+    }
+    tags
+}
+
+fn names() -> Vec<NameType> {
+    (1..6u8).map(|i| NameType([i; 64])).collect()
+}
+
+fn create(db: &mut MaidManagerDatabase, name: &NameType, stored: u64) {
+    let allowance = db.default_allowance();
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(allowance)), Ok(()));
+    assert_eq!(db.put_data(name, stored), Ok(()));
+}
+
+#[test]
+fn maid_manager_churn() {
+    let names = names();
+    let mut sender = MaidManagerDatabase::new();
+    for (i, name) in names[..4].iter().enumerate() {
+        create(&mut sender, name, 1024 * (i as u64 + 1));
+    }
+    // the receiver already holds an older copy of one moved account, and one of its own
+    let mut receiver = MaidManagerDatabase::new();
+    create(&mut receiver, &names[1], 512);
+    create(&mut receiver, &names[4], 4096);
+
+    // a name the sender doesn't hold is simply not sent
+    let moved = vec![names[0].clone(), names[1].clone(), NameType([0xffu8; 64])];
+    assert_eq!(churn_simulation(&mut sender, &mut receiver, &moved), vec![MAID_MANAGER_ACCOUNT_TAG; 2]);
+
+    // the larger stored size of the two copies is kept
+    assert_eq!(receiver.data_stored(&names[0]), Some(1024));
+    assert_eq!(receiver.data_stored(&names[1]), Some(2048));
+    assert_eq!(receiver.data_stored(&names[4]), Some(4096));
+    assert_eq!(receiver.total_stored(), 1024 + 2048 + 4096);
+
+    assert_eq!(sender.exist(&names[0]), false);
+    assert_eq!(sender.exist(&names[1]), false);
+    assert_eq!(sender.data_stored(&names[2]), Some(3072));
+    assert_eq!(sender.data_stored(&names[3]), Some(4096));
+    assert_eq!(sender.total_stored(), 3072 + 4096);
+
+    // nothing is left to send for the same change
+    assert!(churn_simulation(&mut sender, &mut receiver, &moved).is_empty());
+    assert_eq!(receiver.total_stored(), 1024 + 2048 + 4096);
+}
+
+#[test]
+fn pmid_manager_churn() {
+    let names = names();
+    let mut sender = PmidManagerDatabase::new();
+    for (i, name) in names[..4].iter().enumerate() {
+        assert_eq!(sender.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+    }
+    sender.handle_lost_data(&names[1], 256);
+    let mut receiver = PmidManagerDatabase::new();
+    assert_eq!(receiver.put_data(&names[1], 1024), Ok(()));
+    receiver.handle_lost_data(&names[1], 512);
+    assert_eq!(receiver.put_data(&names[4], 4096), Ok(()));
+
+    let moved = vec![names[0].clone(), names[1].clone(), NameType([0xffu8; 64])];
+    assert_eq!(churn_simulation(&mut sender, &mut receiver, &moved), vec![PMID_MANAGER_ACCOUNT_TAG; 2]);
+
+    // the larger stored size is kept and the losses each manager recorded are summed
+    assert_eq!(receiver.usage(&names[0]), Some((1024, 0, 1073741824)));
+    assert_eq!(receiver.usage(&names[1]), Some((1792, 768, 1073741824)));
+    assert_eq!(receiver.usage(&names[4]), Some((4096, 0, 1073741824)));
+    assert_eq!(receiver.total_stored(), 1024 + 1792 + 4096);
+
+    assert_eq!(sender.exist(&names[0]), false);
+    assert_eq!(sender.exist(&names[1]), false);
+    assert_eq!(sender.usage(&names[2]), Some((3072, 0, 1073741824)));
+    assert_eq!(sender.usage(&names[3]), Some((4096, 0, 1073741824)));
+    assert_eq!(sender.total_stored(), 3072 + 4096);
+
+    assert!(churn_simulation(&mut sender, &mut receiver, &moved).is_empty());
+    assert_eq!(receiver.total_stored(), 1024 + 1792 + 4096);
+}
@@ -40,15 +40,15 @@ impl DataManagerDatabase {
   }
 
   pub fn exist(&mut self, name : &Identity) -> bool {
-    self.storage.get(name.clone()).is_some()
+    self.storage.get(name).is_some()
   }
 
   pub fn put_pmid_nodes(&mut self, name : &Identity, pmid_nodes: PmidNodes) {
-    self.storage.add(name.clone(), pmid_nodes.clone());
+    self.storage.insert(name.clone(), pmid_nodes.clone());
   }
 
   pub fn add_pmid_node(&mut self, name : &Identity, pmid_node: PmidNode) {
-    let entry = self.storage.remove(name.clone());
+    let entry = self.storage.remove(name);
       if entry.is_some() {
         let mut tmp = entry.unwrap();
         for i in 0..tmp.len() {
@@ -57,14 +57,14 @@ impl DataManagerDatabase {
             }
         }
         tmp.push(pmid_node);
-      self.storage.add(name.clone(), tmp);
+      self.storage.insert(name.clone(), tmp);
       } else {
-      self.storage.add(name.clone(), vec![pmid_node]);
+      self.storage.insert(name.clone(), vec![pmid_node]);
       }
   }
 
   pub fn remove_pmid_node(&mut self, name : &Identity, pmid_node: PmidNode) {
-    let entry = self.storage.remove(name.clone());
+    let entry = self.storage.remove(name);
       if entry.is_some() {
         let mut tmp = entry.unwrap();
         for i in 0..tmp.len() {
@@ -73,12 +73,12 @@ impl DataManagerDatabase {
           break;
             }
         }
-      self.storage.add(name.clone(), tmp);
+      self.storage.insert(name.clone(), tmp);
       }
   }
 
   pub fn get_pmid_nodes(&mut self, name : &Identity) -> PmidNodes {
-    let entry = self.storage.get(name.clone());
+    let entry = self.storage.get(name);
       if entry.is_some() {
         entry.unwrap().clone()
       } else {
@@ -91,7 +91,11 @@ impl DataManagerDatabase {
         let mut sendable_data = Vec::<generic_sendable_type::GenericSendableType>::with_capacity(data.len());
         for element in data {
             let mut e = cbor::Encoder::from_memory();
-            e.encode(&[&element.1]).unwrap();
+            // an entry which fails to encode is logged and dropped rather than taking the vault down
+            if let Err(error) = e.encode(&[&element.1]) {
+                error!("DataManager failed to encode the pmid nodes of {:?} for transfer: {}", element.0, error);
+                continue;
+            }
             let serialised_content = e.into_bytes();
             let sendable_type = generic_sendable_type::GenericSendableType::new(element.0.clone(), 1, serialised_content); //TODO Get type_tag correct
             sendable_data.push(sendable_type);
@@ -0,0 +1,73 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#![allow(dead_code)]
+
+use std::io;
+use std::path::Path;
+
+/// Bytes available to this process on the file system holding `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use libc;
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte")),
+    };
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// Bytes available to this process on the volume holding `path`.
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory_name: *const u16, free_bytes_available: *mut u64,
+                               total_number_of_bytes: *mut u64,
+                               total_number_of_free_bytes: *mut u64) -> i32;
+    }
+
+    let wide_path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut free_bytes_available = 0u64;
+    if unsafe { GetDiskFreeSpaceExW(wide_path.as_ptr(), &mut free_bytes_available, ptr::null_mut(),
+                                    ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(free_bytes_available)
+}
+
+#[cfg(test)]
+mod test {
+    use super::free_space;
+    use std::env;
+
+    #[test]
+    fn free_space_of_temp_dir() {
+        assert!(free_space(&env::temp_dir()).unwrap() > 0);
+        assert!(free_space(&env::temp_dir().join("free_space_no_such_dir")).is_err());
+    }
+}
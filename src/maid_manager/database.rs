@@ -17,60 +17,583 @@
 
 #![allow(dead_code)]
 
-use std::collections;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use routing;
 use routing::generic_sendable_type;
 use lru_time_cache::LruCache;
+use account_cache::AccountCache;
 use routing::NameType;
 use routing::sendable::Sendable;
 use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use rustc_serialize::json;
+#[cfg(feature = "use-bincode")]
+use bincode;
+#[cfg(feature = "serde")]
+use serde_cbor;
 use cbor;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use super::{AccountError, CreateError, DecodeError, PutError, MAID_MANAGER_ACCOUNT_TAG};
+use byte_size;
+use proto;
+use byte_size::ByteSize;
 
 type Identity = NameType; // maid node address
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug)]
+const DEFAULT_CAPACITY: usize = 10000;
+const DEFAULT_ALLOWANCE: u64 = 1073741824;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct ReservationId(u64);
+
+// serialised as the bare id so the JSON form of an account stays readable
+impl Encodable for ReservationId {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        self.0.encode(e)
+    }
+}
+
+impl Decodable for ReservationId {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ReservationId, D::Error> {
+        Ok(ReservationId(try!(u64::decode(d))))
+    }
+}
+
+// a token bucket holding up to ops tokens, refilled at ops tokens per period
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct RateLimit {
+  ops : u32,
+  per : Duration,
+  tokens : u32,
+  last_refill : Instant
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    duration.as_secs().saturating_mul(1000000000).saturating_add(duration.subsec_nanos() as u64)
+}
+
+impl RateLimit {
+    fn new(ops: u32, per: Duration) -> RateLimit {
+        RateLimit { ops: ops, per: per, tokens: ops, last_refill: Instant::now() }
+    }
+
+    // the tokens accrued since last_refill, or None if the bucket would be full
+    fn accrued(&self, now: Instant) -> Option<u32> {
+        let per = as_nanos(self.per);
+        let missing = (self.ops - self.tokens) as u64;
+        if per == 0 || missing == 0 {
+            return None;
+        }
+        let elapsed = as_nanos(now.duration_since(self.last_refill));
+        match elapsed.checked_mul(self.ops as u64) {
+            Some(scaled) if scaled / per < missing => Some((scaled / per) as u32),
+            _ => None,
+        }
+    }
+
+    fn available(&self, now: Instant) -> u32 {
+        match self.accrued(now) {
+            Some(accrued) => self.tokens + accrued,
+            None => self.ops,
+        }
+    }
+
+    // the bucket is refilled for the time elapsed, then a token is taken if there is one; only the
+    // time actually converted into tokens is consumed so partial progress towards the next is kept
+    fn acquire(&mut self) -> bool {
+        let now = Instant::now();
+        match self.accrued(now) {
+            Some(accrued) => {
+                let nanos = accrued as u64 * as_nanos(self.per) / self.ops as u64;
+                self.tokens += accrued;
+                self.last_refill += Duration::new(nanos / 1000000000, (nanos % 1000000000) as u32);
+            }
+            None => {
+                self.tokens = self.ops;
+                self.last_refill = now;
+            }
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+// above records whether utilization was at or over fraction when last checked
+#[derive(PartialEq, Debug, Clone)]
+struct Watermark {
+  fraction : f64,
+  above : bool
+}
+
+// set_watermark never lets fraction be NaN
+impl Eq for Watermark {}
+
+// the rate limit and watermark are local policy, so they are neither serialised nor carried over by merge
+#[derive(PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MaidManagerAccount {
   data_stored : u64,
-  space_available : u64
+  space_available : u64,
+  reservations : Vec<(ReservationId, u64)>,
+  next_reservation : u64,
+  #[cfg_attr(feature = "serde", serde(with = "epoch_time"))]
+  last_modified : SystemTime,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  rate_limit : Option<RateLimit>,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  watermark : Option<Watermark>
 }
 
 impl Clone for MaidManagerAccount {
     fn clone(&self) -> Self {
         MaidManagerAccount {
           data_stored: self.data_stored,
-          space_available: self.space_available
+          space_available: self.space_available,
+          reservations: self.reservations.clone(),
+          next_reservation: self.next_reservation,
+          last_modified: self.last_modified,
+          rate_limit: self.rate_limit.clone(),
+          watermark: self.watermark.clone()
+        }
+    }
+}
+
+// last_modified is serialised as (seconds, nanoseconds) since the unix epoch
+impl Encodable for MaidManagerAccount {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        let last_modified = match self.last_modified.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => (since_epoch.as_secs(), since_epoch.subsec_nanos()),
+            Err(_) => (0, 0),
+        };
+        e.emit_struct("MaidManagerAccount", 5, |e| {
+            try!(e.emit_struct_field("data_stored", 0, |e| self.data_stored.encode(e)));
+            try!(e.emit_struct_field("space_available", 1, |e| self.space_available.encode(e)));
+            try!(e.emit_struct_field("reservations", 2, |e| self.reservations.encode(e)));
+            try!(e.emit_struct_field("next_reservation", 3, |e| self.next_reservation.encode(e)));
+            e.emit_struct_field("last_modified", 4, |e| last_modified.encode(e))
+        })
+    }
+}
+
+// the serde counterpart of the (seconds, nanoseconds) pair above
+#[cfg(feature = "serde")]
+mod epoch_time {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        (since_epoch.as_secs(), since_epoch.subsec_nanos()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        let (seconds, nanoseconds): (u64, u32) = try!(Deserialize::deserialize(d));
+        Ok(UNIX_EPOCH + Duration::new(seconds, nanoseconds))
+    }
+}
+
+impl Decodable for MaidManagerAccount {
+    fn decode<D: Decoder>(d: &mut D) -> Result<MaidManagerAccount, D::Error> {
+        d.read_struct("MaidManagerAccount", 5, |d| {
+            let data_stored = try!(d.read_struct_field("data_stored", 0, Decodable::decode));
+            let space_available = try!(d.read_struct_field("space_available", 1, Decodable::decode));
+            let reservations = try!(d.read_struct_field("reservations", 2, Decodable::decode));
+            let next_reservation = try!(d.read_struct_field("next_reservation", 3, Decodable::decode));
+            let (seconds, nanoseconds): (u64, u32) = try!(d.read_struct_field("last_modified", 4, Decodable::decode));
+            Ok(MaidManagerAccount {
+                data_stored: data_stored,
+                space_available: space_available,
+                reservations: reservations,
+                next_reservation: next_reservation,
+                last_modified: UNIX_EPOCH + Duration::new(seconds, nanoseconds),
+                rate_limit: None,
+                watermark: None,
+            })
+        })
+    }
+}
+
+// the layout of MAID_MANAGER_ACCOUNT_VERSION 1, before last_modified was added
+#[derive(RustcDecodable)]
+pub struct MaidManagerAccountV1 {
+  data_stored : u64,
+  space_available : u64,
+  reservations : Vec<(ReservationId, u64)>,
+  next_reservation : u64
+}
+
+// with no record of when it was last modified, the account is taken as modified now
+impl From<MaidManagerAccountV1> for MaidManagerAccount {
+    fn from(account: MaidManagerAccountV1) -> MaidManagerAccount {
+        MaidManagerAccount {
+          data_stored: account.data_stored,
+          space_available: account.space_available,
+          reservations: account.reservations,
+          next_reservation: account.next_reservation,
+          last_modified: SystemTime::now(),
+          rate_limit: None,
+          watermark: None
         }
     }
 }
 
+// e.g. "1.00 GiB stored of 4.00 GiB (25.0%), 3.00 GiB available", with the space reserved also
+// given when there is any; Debug remains the derived field by field listing
+impl fmt::Display for MaidManagerAccount {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    try!(write!(formatter, "{} stored of {} ({:.1}%), {} available", byte_size::human_readable(self.data_stored),
+                byte_size::human_readable(self.total_capacity()),
+                self.utilization() * 100.0,
+                byte_size::human_readable(self.space_available)));
+    match self.get_reserved_space() {
+      0 => Ok(()),
+      reserved => write!(formatter, ", {} reserved", byte_size::human_readable(reserved)),
+    }
+  }
+}
+
 impl MaidManagerAccount {
     pub fn new() -> MaidManagerAccount {
         // FIXME : to bypass the AccountCreation process for simple network allownance is granted automatically
-        MaidManagerAccount { data_stored: 0, space_available: 1073741824 }
+        MaidManagerAccount::with_allowance(DEFAULT_ALLOWANCE)
     }
 
-    pub fn put_data(&mut self, size : u64) -> bool {
-        if size > self.space_available {
-            return false;
+    // as with_allowance, for an allowance given as e.g. "10GiB"
+    pub fn with_allowance_size(allowance : ByteSize) -> MaidManagerAccount {
+        MaidManagerAccount::with_allowance(allowance.as_u64())
+    }
+
+    // an account with no allowance, for read-only clients; a zero sized put still succeeds, since it
+    // uses none of the allowance, and so does deleting, which has nothing to free
+    pub fn empty() -> MaidManagerAccount {
+        MaidManagerAccount::with_allowance(0)
+    }
+
+    // an account part way through its life, without reservations; rejected if data_stored and
+    // space_available don't fit in a u64 when summed
+    pub fn from_parts(data_stored : u64, space_available : u64) -> Result<MaidManagerAccount, AccountError> {
+        let mut account = MaidManagerAccount::with_allowance(space_available);
+        account.data_stored = data_stored;
+        try!(account.validate());
+        Ok(account)
+    }
+
+    pub fn with_allowance(allowance : u64) -> MaidManagerAccount {
+        MaidManagerAccount {
+          data_stored: 0,
+          space_available: allowance,
+          reservations: Vec::new(),
+          next_reservation: 0,
+          last_modified: SystemTime::now(),
+          rate_limit: None,
+          watermark: None
         }
-        self.data_stored += size;
-        self.space_available -= size;
-        true
     }
 
-    pub fn delete_data(&mut self, size : u64) {
-        if self.data_stored < size {
-            self.space_available += self.data_stored;
-            self.data_stored = 0;
-        } else {
-            self.data_stored -= size;
-            self.space_available += size;
+    // data_stored + space_available is conserved by both put_data and delete_data: a put which
+    // can't be fully accounted for is rejected, a delete larger than data_stored is clamped.
+    // a put is rejected as RateLimited only if it would otherwise have fit, and only an accepted
+    // put takes a token from the rate limit
+    pub fn put_data(&mut self, size : u64) -> Result<(), PutError> {
+        self.put_data_watermarked(size).map(|_| ())
+    }
+
+    // as put_data, returning true when this put took utilization from below the watermark to at or
+    // above it; false while it stays above, until a delete takes it back below
+    pub fn put_data_watermarked(&mut self, size : u64) -> Result<bool, PutError> {
+        // the limit is inclusive: a put of exactly space_available is accepted and leaves none, and
+        // only a put of more than that is rejected
+        match (self.data_stored.checked_add(size), self.space_available.checked_sub(size)) {
+            (Some(data_stored), Some(space_available)) => {
+                if let Some(ref mut rate_limit) = self.rate_limit {
+                    if !rate_limit.acquire() {
+                        return Err(PutError::RateLimited);
+                    }
+                }
+                self.data_stored = data_stored;
+                self.space_available = space_available;
+                self.last_modified = SystemTime::now();
+                self.debug_assert_invariant();
+                Ok(self.update_watermark())
+            }
+            _ => Err(PutError::NotEnoughSpace)
+        }
+    }
+
+    // allows at most ops puts in any period of length per, with the allowance refilling steadily
+    // over time; the bucket starts full
+    pub fn set_rate_limit(&mut self, ops : u32, per : Duration) {
+        self.rate_limit = Some(RateLimit::new(ops, per));
+    }
+
+    pub fn clear_rate_limit(&mut self) {
+        self.rate_limit = None;
+    }
+
+    // fraction of utilization at which put_data_watermarked reports a crossing, an account already
+    // at or over it won't report one until it has dropped back below
+    pub fn set_watermark(&mut self, fraction : f64) {
+        assert!(fraction >= 0.0 && fraction <= 1.0, "watermark {} is not a fraction", fraction);
+        let above = self.utilization() >= fraction;
+        self.watermark = Some(Watermark { fraction: fraction, above: above });
+    }
+
+    pub fn clear_watermark(&mut self) {
+        self.watermark = None;
+    }
+
+    // true if utilization has just gone from below the watermark to at or above it
+    fn update_watermark(&mut self) -> bool {
+        let utilization = self.utilization();
+        match self.watermark {
+            Some(ref mut watermark) => {
+                let was_above = watermark.above;
+                watermark.above = utilization >= watermark.fraction;
+                watermark.above && !was_above
+            }
+            None => false,
+        }
+    }
+
+    // whether put_data(size) would succeed, without modifying the account
+    pub fn can_put(&self, size : u64) -> bool {
+        self.data_stored.checked_add(size).is_some() && self.space_available.checked_sub(size).is_some() &&
+            self.rate_limit.as_ref().map_or(true, |rate_limit| rate_limit.available(Instant::now()) > 0)
+    }
+
+    // returns the bytes actually freed, which is less than size if less than that was stored
+    pub fn delete_data(&mut self, size : u64) -> u64 {
+        let freed = cmp::min(size, self.data_stored);
+        self.data_stored -= freed;
+        self.space_available = self.space_available.saturating_add(freed);
+        self.last_modified = SystemTime::now();
+        self.debug_assert_invariant();
+        let _ = self.update_watermark();
+        freed
+    }
+
+    // time since the last put or delete, zero if the clock has gone backwards since
+    pub fn idle_since(&self) -> Duration {
+        SystemTime::now().duration_since(self.last_modified).unwrap_or(Duration::new(0, 0))
+    }
+
+    // space is taken from space_available when reserved, but only counted as data_stored once the
+    // data actually arrives and the reservation is committed
+    pub fn reserve(&mut self, size : u64) -> Option<ReservationId> {
+        match self.space_available.checked_sub(size) {
+            Some(space_available) => {
+                self.space_available = space_available;
+                let id = ReservationId(self.next_reservation);
+                self.next_reservation = self.next_reservation.wrapping_add(1);
+                self.reservations.push((id, size));
+                self.debug_assert_invariant();
+                Some(id)
+            }
+            None => None
+        }
+    }
+
+    // false if id is unknown, e.g. already committed or cancelled
+    pub fn commit(&mut self, id : ReservationId) -> bool {
+        match self.take_reservation(id) {
+            Some(size) => {
+                self.data_stored = self.data_stored.saturating_add(size);
+                self.last_modified = SystemTime::now();
+                self.debug_assert_invariant();
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn cancel(&mut self, id : ReservationId) -> bool {
+        match self.take_reservation(id) {
+            Some(size) => {
+                self.space_available = self.space_available.saturating_add(size);
+                self.debug_assert_invariant();
+                true
+            }
+            None => false
+        }
+    }
+
+    // the conserved total must be representable, which a corrupted or malicious record may break
+    pub fn validate(&self) -> Result<(), AccountError> {
+        let reserved = self.reservations.iter().fold(Some(0u64), |total, &(_, size)| {
+            total.and_then(|total| total.checked_add(size))
+        });
+        match reserved.and_then(|reserved| reserved.checked_add(self.data_stored))
+                      .and_then(|total| total.checked_add(self.space_available)) {
+            Some(_) => Ok(()),
+            None => Err(AccountError::Overflow)
+        }
+    }
+
+    // clamps an invalid account to a valid one, keeping data_stored as recorded: reservations are
+    // dropped if they alone overflow, then space_available is cut to whatever is left
+    pub fn repair(&mut self) {
+        if self.validate().is_ok() {
+            return;
+        }
+        if self.get_reserved_space().checked_add(self.data_stored).is_none() {
+            self.reservations.clear();
+        }
+        let used = self.data_stored.saturating_add(self.get_reserved_space());
+        self.space_available = cmp::min(self.space_available, ::std::u64::MAX - used);
+        self.debug_assert_invariant();
+    }
+
+    // bytes held on the heap for the reservations, beyond size_of::<MaidManagerAccount>()
+    fn heap_bytes(&self) -> usize {
+        self.reservations.capacity() * ::std::mem::size_of::<(ReservationId, u64)>()
+    }
+    pub fn get_reserved_space(&self) -> u64 {
+        self.reservations.iter().fold(0u64, |total, &(_, size)| total.saturating_add(size))
+    }
+
+    fn take_reservation(&mut self, id : ReservationId) -> Option<u64> {
+        match self.reservations.iter().position(|&(reserved, _)| reserved == id) {
+            Some(index) => Some(self.reservations.remove(index).1),
+            None => None
+        }
+    }
+
+    // fraction of the total allowance (data_stored + space_available + reserved space) in use; an
+    // account with no allowance at all is treated as full
+    pub fn utilization(&self) -> f64 {
+        let total = self.total_capacity();
+        if total == 0 {
+            return 1.0;
+        }
+        self.data_stored as f64 / total as f64
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.space_available == 0
+    }
+
+    // a JSON object with the keys "data_stored", "space_available", "reservations" (an array of
+    // [id, size] pairs), "next_reservation" and "last_modified" ([seconds, nanoseconds] since the
+    // unix epoch), all sizes in bytes
+    pub fn to_json(&self) -> String {
+        json::encode(self).unwrap()
+    }
+
+    pub fn from_json(serialised : &str) -> Result<MaidManagerAccount, json::DecoderError> {
+        json::decode(serialised)
+    }
+
+    // equal sizes, reservations and next reservation id; when it was last modified and the local rate
+    // limit aren't compared
+    pub fn semantically_eq(&self, other: &MaidManagerAccount) -> bool {
+        self.data_stored == other.data_stored && self.space_available == other.space_available &&
+            self.reservations == other.reservations && self.next_reservation == other.next_reservation
+    }
+
+    // a maid manager must never under-report a client's usage, so the larger data_stored is kept and
+    // space_available recomputed from the larger of the two totals to preserve the invariant. The
+    // reservations of both are kept, combined by id with the larger size for one held by both and
+    // ordered by id, so merging in either order gives the same account. Taken in that order, any
+    // which no longer fits in the total beside the larger data_stored is dropped, as if cancelled
+    // without the space being returned; committing one then fails as for an unknown id.
+    pub fn merge(&mut self, other: &MaidManagerAccount) {
+        let total = cmp::max(self.total_capacity(), other.total_capacity());
+        for &(id, size) in other.reservations.iter() {
+            match self.reservations.iter().position(|&(held, _)| held == id) {
+                Some(index) => self.reservations[index].1 = cmp::max(self.reservations[index].1, size),
+                None => self.reservations.push((id, size)),
+            }
+        }
+        self.reservations.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        self.next_reservation = cmp::max(self.next_reservation, other.next_reservation);
+        self.data_stored = cmp::max(self.data_stored, other.data_stored);
+        let mut room = total.saturating_sub(self.data_stored);
+        self.reservations.retain(|&(_, size)| {
+            match room.checked_sub(size) {
+                Some(left) => { room = left; true }
+                None => false,
+            }
+        });
+        self.space_available = room;
+        self.last_modified = cmp::max(self.last_modified, other.last_modified);
+        self.debug_assert_invariant();
+    }
+
+    // data_stored + space_available + the reserved space, which puts, deletes and reservations
+    // all conserve; saturates at u64::MAX for an account which fails validate
+    pub fn total_capacity(&self) -> u64 {
+        self.data_stored.saturating_add(self.space_available).saturating_add(self.get_reserved_space())
+    }
+
+    // every mutating method leaves the account valid; checked in debug builds only
+    fn debug_assert_invariant(&self) {
+        debug_assert!(self.validate().is_ok(), "MaidManagerAccount total overflows u64: {:?}", self);
+    }
+
+    #[cfg(feature = "use-bincode")]
+    pub fn to_bincode(&self) -> Vec<u8> {
+        bincode::rustc_serialize::encode(self, bincode::SizeLimit::Infinite).unwrap()
+    }
+
+    #[cfg(feature = "use-bincode")]
+    pub fn from_bincode(serialised : &[u8]) -> Result<MaidManagerAccount, bincode::rustc_serialize::DecodingError> {
+        bincode::rustc_serialize::decode(serialised)
+    }
+
+    // the same bytes as the rustc-serialize cbor encoding, so either can decode the other's output
+    #[cfg(feature = "serde")]
+    pub fn to_serde_cbor(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).unwrap()
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_serde_cbor(serialised : &[u8]) -> Result<MaidManagerAccount, serde_cbor::Error> {
+        serde_cbor::from_slice(serialised)
+    }
+
+    // a protobuf encoding for services not written in rust, of
+    //   message MaidManagerAccount {
+    //     uint64 data_stored = 1;
+    //     uint64 space_available = 2;
+    //   }
+    // written length-delimited, see proto. Reservations aren't carried, and the account decoded is
+    // modified now, as one built by from_parts.
+    pub fn to_proto_bytes(&self) -> Vec<u8> {
+        proto::encode_delimited(&[(1, self.data_stored), (2, self.space_available)])
+    }
+
+    pub fn from_proto_bytes(serialised : &[u8]) -> Result<MaidManagerAccount, DecodeError> {
+        if serialised.is_empty() {
+            return Err(DecodeError::Empty);
         }
+        let mut fields = [0u64; 2];
+        try!(proto::decode_delimited(serialised, &mut fields).map_err(DecodeError::InvalidAccount));
+        MaidManagerAccount::from_parts(fields[0], fields[1])
+            .map_err(|_| DecodeError::InvalidAccount("data_stored and space_available overflow".to_string()))
     }
 
     pub fn get_available_space(&self) -> u64 {
       self.space_available.clone()
     }
 
+    // the same as get_available_space, named to match PmidManagerAccount::remaining_space
+    pub fn remaining_space(&self) -> u64 {
+      self.space_available
+    }
+
 
     pub fn get_data_stored(&self) -> u64 {
         self.data_stored.clone()
@@ -78,93 +601,2468 @@ impl MaidManagerAccount {
 
 }
 
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MaidManagerStats {
+  pub account_count : usize,
+  pub total_data_stored : u64,
+  pub total_space_available : u64
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MaidManagerMetrics {
+  pub puts_ok : u64,
+  pub puts_rejected : u64,
+  pub deletes : u64,
+  pub accounts_created : u64,
+  // accounts dropped to make room for a new one once the database holds its capacity
+  pub evictions : u64,
+  // of the latest transfer by any of the retrieve_all variants: how long it took to remove and
+  // encode the accounts, None until there has been one, and how many accounts and serialised bytes
+  // it produced. For retrieve_all_and_reset_chunked these are the totals of the chunks taken so far,
+  // and for retrieve_all_compressed the bytes are those of the compressed blob.
+  pub last_transfer_duration : Option<Duration>,
+  pub last_transfer_accounts : usize,
+  pub last_transfer_bytes : u64
+}
+
+// a client's request to open an account with the given allowance, in bytes
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AccountCreation {
+  pub allowance : u64
+}
+
+impl AccountCreation {
+  pub fn new(allowance : u64) -> AccountCreation {
+      AccountCreation { allowance: allowance }
+  }
+}
+
 pub struct MaidManagerDatabase {
-  storage: collections::HashMap<Identity, MaidManagerAccount>,
+  storage: AccountCache<Identity, MaidManagerAccount>,
+  capacity: usize,
+  default_allowance: u64,
+  max_allowance: u64,
+  metrics: MaidManagerMetrics,
+  on_remove: Option<Box<dyn Fn(&Identity, &MaidManagerAccount) + Send>>,
+  on_watermark: Option<Box<dyn Fn(&Identity, &MaidManagerAccount) + Send>>,
+  name_validation: bool,
+  reserved_names: Vec<Identity>,
+  // kept up to date by every mutation made through the database, see recompute
+  total_stored: u64,
+  // the outcome of each put recently made by put_data_with_id, see enable_put_dedup
+  put_ids: Option<LruCache<[u8; 32], bool>>,
+  max_accounts: Option<usize>,
+  // when each name was soft deleted, see purge_tombstones
+  tombstones: HashMap<Identity, SystemTime>,
+}
+
+pub struct MaidManagerDatabaseBuilder {
+  capacity: usize,
+  ttl: Option<Duration>,
+  default_allowance: u64,
+  name_validation: bool,
+  max_accounts: Option<usize>,
+}
+
+impl MaidManagerDatabaseBuilder {
+  pub fn new() -> MaidManagerDatabaseBuilder {
+      MaidManagerDatabaseBuilder {
+          capacity: DEFAULT_CAPACITY,
+          ttl: None,
+          default_allowance: DEFAULT_ALLOWANCE,
+          name_validation: false,
+          max_accounts: None,
+      }
+  }
+
+  pub fn capacity(mut self, capacity: usize) -> MaidManagerDatabaseBuilder {
+      self.capacity = capacity;
+      self
+  }
+
+  pub fn ttl(mut self, ttl: Duration) -> MaidManagerDatabaseBuilder {
+      self.ttl = Some(ttl);
+      self
+  }
+
+  pub fn default_allowance(mut self, allowance: u64) -> MaidManagerDatabaseBuilder {
+      self.default_allowance = allowance;
+      self
+  }
+
+  // when enabled, names reserved by MaidManagerDatabase::reserve_name are refused accounts
+  pub fn name_validation(mut self, enabled: bool) -> MaidManagerDatabaseBuilder {
+      self.name_validation = enabled;
+      self
+  }
+
+  // see MaidManagerDatabase::with_max_accounts
+  pub fn max_accounts(mut self, limit: usize) -> MaidManagerDatabaseBuilder {
+      self.max_accounts = Some(limit);
+      self
+  }
+
+  pub fn build(self) -> MaidManagerDatabase {
+      let storage = match self.ttl {
+          Some(ttl) => AccountCache::with_expiry_duration_and_capacity(ttl, self.capacity),
+          None => AccountCache::with_capacity(self.capacity),
+      };
+      MaidManagerDatabase {
+          storage: storage,
+          capacity: self.capacity,
+          default_allowance: self.default_allowance,
+          max_allowance: self.default_allowance,
+          metrics: MaidManagerMetrics { puts_ok: 0, puts_rejected: 0, deletes: 0, accounts_created: 0, evictions: 0,
+                                        last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 },
+          on_remove: None,
+          on_watermark: None,
+          name_validation: self.name_validation,
+          reserved_names: vec![NameType([0u8; 64])],
+          total_stored: 0,
+          put_ids: None,
+          max_accounts: self.max_accounts,
+          tombstones: HashMap::new(),
+      }
+  }
+}
+
+impl Default for MaidManagerDatabase {
+  fn default() -> MaidManagerDatabase {
+      MaidManagerDatabase::new()
+  }
 }
 
 impl MaidManagerDatabase {
   pub fn new () -> MaidManagerDatabase {
-      MaidManagerDatabase { storage: collections::HashMap::with_capacity(10000), }
+      MaidManagerDatabaseBuilder::new().build()
+  }
+
+  // accounts beyond count, or untouched for longer than ttl, are evicted least recently used first
+  pub fn with_capacity(count: usize, ttl: Duration) -> MaidManagerDatabase {
+      MaidManagerDatabaseBuilder::new().capacity(count).ttl(ttl).build()
+  }
+
+  // allowance expected of a newly created account, which is also the most a creation may request
+  pub fn with_default_allowance(allowance: u64) -> MaidManagerDatabase {
+      MaidManagerDatabaseBuilder::new().default_allowance(allowance).build()
+  }
+
+  // with validation on, create_account and put_data reject the zero name and any others reserved
+  pub fn with_name_validation(enabled: bool) -> MaidManagerDatabase {
+      MaidManagerDatabaseBuilder::new().name_validation(enabled).build()
+  }
+
+  // once limit accounts are held, create_account and insert_new refuse new names with AtCapacity,
+  // where the capacity of with_capacity would evict to make room; the accounts already held may
+  // still be put to and deleted from, and accounts handed over on churn are still accepted
+  pub fn with_max_accounts(limit: usize) -> MaidManagerDatabase {
+      MaidManagerDatabaseBuilder::new().max_accounts(limit).build()
+  }
+
+  pub fn reserve_name(&mut self, name: Identity) {
+      if !self.reserved_names.contains(&name) {
+          self.reserved_names.push(name);
+      }
+  }
+
+  fn is_reserved(&self, name: &Identity) -> bool {
+      self.name_validation && self.reserved_names.contains(name)
+  }
+
+  pub fn load_from_file(path: &Path) -> io::Result<MaidManagerDatabase> {
+      let mut database = MaidManagerDatabase::new();
+      try!(database.restore_from_file(path));
+      Ok(database)
+  }
+
+  // as load_from_file, but into this database so that its configuration is kept; an account in the
+  // file replaces one held under the same name
+  pub fn restore_from_file(&mut self, path: &Path) -> io::Result<()> {
+      let mut file = match fs::File::open(path) {
+          Ok(file) => file,
+          Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+          Err(error) => return Err(error),
+      };
+      let mut serialised = Vec::new();
+      try!(file.read_to_end(&mut serialised));
+      let mut d = cbor::Decoder::from_bytes(serialised);
+      let accounts: Vec<(Identity, MaidManagerAccount)> = match d.decode().next() {
+          Some(Ok(accounts)) => accounts,
+          Some(Err(error)) => return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+          None => Vec::new(),
+      };
+      for (name, account) in accounts {
+          let _ = self.storage.insert(name, account);
+      }
+      self.recompute();
+      Ok(())
+  }
+
+  // written to a temporary file in the same directory which is then renamed over path, so a crash
+  // part way through never leaves a truncated database behind
+  pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+      let accounts: Vec<(&Identity, &MaidManagerAccount)> = self.storage.peek_iter().collect();
+      let mut e = cbor::Encoder::from_memory();
+      match e.encode(&[&accounts]) {
+          Ok(_) => (),
+          Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+      }
+      let mut temp_name = path.file_name().unwrap_or(path.as_os_str()).to_os_string();
+      temp_name.push(".tmp");
+      let temp_path = path.with_file_name(temp_name);
+      {
+          let mut file = try!(fs::File::create(&temp_path));
+          try!(file.write_all(e.as_bytes()));
+          try!(file.sync_all());
+      }
+      fs::rename(&temp_path, path)
   }
 
   pub fn exist(&mut self, name : &Identity) -> bool {
       self.storage.contains_key(name)
   }
 
-  pub fn put_data(&mut self, name: &Identity, size: u64) -> bool {
-      let entry = self.storage.entry(name.clone()).or_insert(MaidManagerAccount::new());
-      entry.put_data(size)
+  pub fn get_account(&self, name : &Identity) -> Option<MaidManagerAccount> {
+      self.storage.peek(name).map(|account| account.clone())
+  }
+
+  pub fn data_stored(&self, name : &Identity) -> Option<u64> {
+      self.storage.peek(name).map(|account| account.get_data_stored())
+  }
+
+  pub fn space_available(&self, name : &Identity) -> Option<u64> {
+      self.storage.peek(name).map(|account| account.get_available_space())
   }
 
-  pub fn retrieve_all_and_reset(&mut self) -> Vec<generic_sendable_type::GenericSendableType> {
-      let data: Vec<_> = self.storage.drain().collect();
-      let mut sendable_data = Vec::with_capacity(data.len());
-      for element in data {
-          let mut e = cbor::Encoder::from_memory();
-          e.encode(&[&element.1]).unwrap();
-          let serialised_content = e.into_bytes();
-          sendable_data.push(generic_sendable_type::GenericSendableType::new(element.0, 0, serialised_content)); //TODO Get type_tag correct
+  // a header row then one row per account, with the name as lower case hex
+  pub fn export_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+      try!(writeln!(w, "name,data_stored,space_available"));
+      for (name, account) in self.storage.peek_iter() {
+          try!(writeln!(w, "{},{},{}", name_as_hex(name), account.data_stored, account.space_available));
       }
-      sendable_data
+      Ok(())
   }
 
-  pub fn delete_data(&mut self, name : &Identity, size: u64) {
-      match self.storage.get_mut(name) {
-          Some(value) => value.delete_data(size),
-          None => (),
+  pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Identity, &'a MaidManagerAccount)> {
+      self.storage.peek_iter()
+  }
+
+  pub fn identities(&self) -> Vec<Identity> {
+      self.iter().map(|(name, _)| name.clone()).collect()
+  }
+
+  // whether both hold the same accounts, compared with MaidManagerAccount::semantically_eq, whatever
+  // order they were inserted in or recently used; metrics and configuration aren't compared
+  pub fn semantically_eq(&self, other: &MaidManagerDatabase) -> bool {
+      self.iter().count() == other.iter().count() &&
+          self.iter().all(|(name, account)| {
+              other.storage.peek(name).map_or(false, |theirs| account.semantically_eq(theirs))
+          })
+  }
+
+  // the accounts with at least min_free space available, most first and ties in name order
+  pub fn candidates_with_space(&self, min_free: u64) -> Vec<Identity> {
+      let mut candidates: Vec<(&Identity, u64)> = self.iter()
+          .filter(|&(_, account)| account.space_available >= min_free)
+          .map(|(name, account)| (name, account.space_available))
+          .collect();
+      candidates.sort_by(|lhs, rhs| rhs.1.cmp(&lhs.1).then(lhs.0.cmp(rhs.0)));
+      candidates.into_iter().map(|(name, _)| name.clone()).collect()
+  }
+
+  pub fn stats(&self) -> MaidManagerStats {
+      let mut stats = MaidManagerStats { account_count: 0, total_data_stored: 0, total_space_available: 0 };
+      for (_, account) in self.storage.peek_iter() {
+          stats.account_count += 1;
+          stats.total_data_stored = stats.total_data_stored.saturating_add(account.data_stored);
+          stats.total_space_available = stats.total_space_available.saturating_add(account.space_available);
       }
+      stats
   }
-}
 
+  // a rough guide to the memory taken by the database: the cache keeps each name in its map and in
+  // its lru order, alongside the account, its last access time and the stamp of that access, which
+  // also keys the order, and each map is taken to add a further word per entry
+  pub fn estimated_memory_bytes(&self) -> usize {
+      let entry = 2 * ::std::mem::size_of::<Identity>() + ::std::mem::size_of::<MaidManagerAccount>() +
+                  ::std::mem::size_of::<Instant>() + 2 * ::std::mem::size_of::<u64>() +
+                  2 * ::std::mem::size_of::<usize>();
+      self.storage.peek_iter().fold(::std::mem::size_of::<MaidManagerDatabase>(), |total, (_, account)| {
+          total + entry + account.heap_bytes()
+      })
+  }
 
-#[cfg(test)]
-mod test {
-  use super::*;
-  use routing;
-  use cbor;
+  // the count accounts closest to target by XOR distance, nearest first, as a close group is chosen,
+  // each with its data_stored
+  pub fn accounts_within(&self, target: &NameType, count: usize) -> Vec<(Identity, u64)> {
+      let mut accounts: Vec<(Identity, u64)> =
+          self.storage.peek_iter().map(|(name, account)| (name.clone(), account.data_stored)).collect();
+      // the distances compared byte by byte, most significant first, which is the order
+      // closer_to_target gives but total, equal names comparing equal
+      let distance = |name: &Identity| {
+          let mut distance = [0u8; 64];
+          for (byte, (lhs, rhs)) in distance.iter_mut().zip(name.0.iter().zip(target.0.iter())) {
+              *byte = lhs ^ rhs;
+          }
+          distance
+      };
+      accounts.sort_by(|lhs, rhs| distance(&lhs.0)[..].cmp(&distance(&rhs.0)[..]));
+      accounts.truncate(count);
+      accounts
+  }
 
-  #[test]
-  fn exist() {
-    let mut db = MaidManagerDatabase::new();
-    let name = routing::test_utils::Random::generate_random();
-    assert_eq!(db.exist(&name), false);
-    db.put_data(&name, 1024);
-    assert_eq!(db.exist(&name), true);
+  // replaces the cache with a copy of itself, which drops the space it kept for accounts since
+  // removed and that each account kept for reservations since committed or cancelled; the order of
+  // use and the time each account was last used are kept. Returns the fall in estimated_memory_bytes.
+  pub fn compact(&mut self) -> usize {
+      let before = self.estimated_memory_bytes();
+      self.storage = self.storage.clone();
+      before.saturating_sub(self.estimated_memory_bytes())
   }
 
-  #[test]
-  fn put_data() {
-    let mut db = MaidManagerDatabase::new();
-    let name = routing::test_utils::Random::generate_random();
-    assert_eq!(db.put_data(&name, 0), true);
-    assert_eq!(db.put_data(&name, 1), true);
-    assert_eq!(db.put_data(&name, 1073741823), true);
-    assert_eq!(db.put_data(&name, 1), false);
-    assert_eq!(db.put_data(&name, 1), false);
-    assert_eq!(db.put_data(&name, 0), true);
-    assert_eq!(db.put_data(&name, 1), false);
-    assert_eq!(db.exist(&name), true);
+  pub fn default_allowance(&self) -> u64 {
+      self.default_allowance
   }
 
-  #[test]
-  fn delete_data() {
-    let mut db = MaidManagerDatabase::new();
-    let name = routing::test_utils::Random::generate_random();
-    db.delete_data(&name, 0);
-    assert_eq!(db.exist(&name), false);
-    assert_eq!(db.put_data(&name, 0), true);
-    assert_eq!(db.exist(&name), true);
-    db.delete_data(&name, 1);
-    assert_eq!(db.exist(&name), true);
-    assert_eq!(db.put_data(&name, 1073741824), true);
-    assert_eq!(db.put_data(&name, 1), false);
-    db.delete_data(&name, 1);
-    assert_eq!(db.put_data(&name, 1), true);
-    assert_eq!(db.put_data(&name, 1), false);
-    db.delete_data(&name, 1073741825);
-    assert_eq!(db.exist(&name), true);
-    assert_eq!(db.put_data(&name, 1073741825), false);
-    assert_eq!(db.put_data(&name, 1073741824), true);
+  pub fn set_max_allowance(&mut self, max_allowance: u64) {
+      self.max_allowance = max_allowance;
+  }
+
+  pub fn create_account(&mut self, name: Identity, request: AccountCreation) -> Result<(), CreateError> {
+      if self.is_reserved(&name) {
+          return Err(CreateError::InvalidName);
+      }
+      if self.tombstones.contains_key(&name) {
+          return Err(CreateError::Deleted);
+      }
+      if self.storage.contains_key(&name) {
+          return Err(CreateError::AlreadyExists);
+      }
+      if request.allowance > self.max_allowance {
+          return Err(CreateError::AllowanceTooLarge { requested: request.allowance, max: self.max_allowance });
+      }
+      if self.at_max_accounts() {
+          return Err(CreateError::AtCapacity);
+      }
+      self.insert_account(name, MaidManagerAccount::with_allowance(request.allowance));
+      self.metrics.accounts_created = self.metrics.accounts_created.saturating_add(1);
+      Ok(())
+  }
+
+  // adds account as it is, failing with AlreadyExists rather than replacing it if name is already
+  // held, or AtCapacity or Deleted as create_account; unlike create_account the allowance isn't
+  // checked against the maximum, nor the name against those reserved
+  pub fn insert_new(&mut self, name: Identity, account: MaidManagerAccount) -> Result<(), CreateError> {
+      if self.tombstones.contains_key(&name) {
+          return Err(CreateError::Deleted);
+      }
+      if self.storage.contains_key(&name) {
+          return Err(CreateError::AlreadyExists);
+      }
+      if self.at_max_accounts() {
+          return Err(CreateError::AtCapacity);
+      }
+      self.insert_account(name, account);
+      Ok(())
+  }
+
+  fn at_max_accounts(&self) -> bool {
+      self.max_accounts.map_or(false, |limit| self.storage.len() >= limit)
+  }
+
+  pub fn put_data(&mut self, name: &Identity, size: u64) -> Result<(), PutError> {
+      if let Err(error) = self.check_put_name(name) {
+          self.record_put(false);
+          return Err(error);
+      }
+      let result = match self.storage.get_mut(name) {
+          Some(account) => match account.put_data_watermarked(size) {
+              Ok(crossed) => {
+                  if let (true, Some(on_watermark)) = (crossed, self.on_watermark.as_ref()) {
+                      on_watermark(name, account);
+                  }
+                  Ok(())
+              }
+              Err(error) => Err(error),
+          },
+          None => Err(PutError::AccountNotFound),
+      };
+      if result.is_ok() {
+          self.total_stored = self.total_stored.saturating_add(size);
+      }
+      self.record_put(result.is_ok());
+      result
+  }
+
+  // remembers the outcome of the last capacity puts made by put_data_with_id, replacing any already
+  // remembered
+  pub fn enable_put_dedup(&mut self, capacity: usize) {
+      assert!(capacity > 0, "capacity must be positive");
+      self.put_ids = Some(LruCache::with_capacity(capacity));
+  }
+
+  pub fn disable_put_dedup(&mut self) {
+      self.put_ids = None;
+  }
+
+  // as put_data, returning whether the put was accepted, for a put which the network may deliver
+  // more than once: with enable_put_dedup, a repeated op_id still remembered isn't applied again and
+  // gets the answer given the first time. Without it every delivery is applied.
+  pub fn put_data_with_id(&mut self, name: &Identity, size: u64, op_id: [u8; 32]) -> bool {
+      if let Some(accepted) = self.put_ids.as_mut().and_then(|put_ids| put_ids.get(&op_id).cloned()) {
+          return accepted;
+      }
+      let accepted = self.put_data(name, size).is_ok();
+      if let Some(ref mut put_ids) = self.put_ids {
+          let _ = put_ids.insert(op_id, accepted);
+      }
+      accepted
+  }
+
+  // whether put_data would succeed, an unknown name is checked against the default allowance it
+  // would be created with by MaidManager::handle_put; nothing is inserted or modified
+  pub fn can_put(&self, name: &Identity, size: u64) -> bool {
+      if self.is_reserved(name) || self.tombstones.contains_key(name) {
+          return false;
+      }
+      match self.storage.peek(name) {
+          Some(account) => account.can_put(size),
+          None => MaidManagerAccount::with_allowance(self.default_allowance).can_put(size),
+      }
+  }
+
+  // a reserved or soft deleted name takes no puts, whether or not it has an account
+  fn check_put_name(&self, name: &Identity) -> Result<(), PutError> {
+      if self.is_reserved(name) {
+          return Err(PutError::InvalidName);
+      }
+      if self.tombstones.contains_key(name) {
+          return Err(PutError::Deleted);
+      }
+      Ok(())
+  }
+
+  pub fn metrics(&self) -> MaidManagerMetrics {
+      self.metrics.clone()
+  }
+
+  fn record_put(&mut self, succeeded: bool) {
+      if succeeded {
+          self.metrics.puts_ok = self.metrics.puts_ok.saturating_add(1);
+      } else {
+          self.metrics.puts_rejected = self.metrics.puts_rejected.saturating_add(1);
+      }
+  }
+
+  // applies each put in turn as put_data, returning whether each succeeded; a run of puts to the
+  // same identity shares a single lookup, and fails entirely if that account hasn't been created or
+  // put_data would reject its name
+  pub fn put_data_batch(&mut self, items: &[(Identity, u64)]) -> Vec<bool> {
+      let mut results = Vec::with_capacity(items.len());
+      let mut start = 0;
+      while start < items.len() {
+          let name = &items[start].0;
+          let end = items[start..].iter().position(|item| item.0 != *name).map_or(items.len(), |count| start + count);
+          let account = match self.check_put_name(name) {
+              Ok(()) => self.storage.get_mut(name),
+              Err(_) => None,
+          };
+          match account {
+              Some(account) => {
+                  for &(_, size) in items[start..end].iter() {
+                      match account.put_data_watermarked(size) {
+                          Ok(crossed) => {
+                              if let (true, Some(on_watermark)) = (crossed, self.on_watermark.as_ref()) {
+                                  on_watermark(name, account);
+                              }
+                              self.total_stored = self.total_stored.saturating_add(size);
+                              results.push(true);
+                          }
+                          Err(_) => results.push(false),
+                      }
+                  }
+              }
+              None => results.extend((start..end).map(|_| false)),
+          }
+          for index in start..end {
+              let succeeded = results[index];
+              self.record_put(succeeded);
+          }
+          start = end;
+      }
+      results
+  }
+
+  // a transferred account replaces ours only if it records more data stored, a maid manager must
+  // never under-report a client's usage; whole records are kept so the conserved total is intact
+  pub fn handle_account_transfer(&mut self, name: Identity, serialised: &[u8]) -> Result<(), DecodeError> {
+      let account = try!(decode_transfer(serialised));
+      self.accept_transfer(name, account)
+  }
+
+  // as retrieve_all_and_reset, but with each account bincode encoded, which the receiving manager
+  // must pass to handle_account_transfer_bincode
+  #[cfg(feature = "use-bincode")]
+  pub fn retrieve_all_and_reset_bincode(&mut self, close_group: &Vec<NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      let started = Instant::now();
+      let sendable_data: Vec<generic_sendable_type::GenericSendableType> =
+          self.take_accounts(close_group).into_iter().map(|(name, account)| {
+              generic_sendable_type::GenericSendableType::new(name, MAID_MANAGER_ACCOUNT_TAG, account.to_bincode())
+          }).collect();
+      self.record_transfer(started.elapsed(), sendable_data.len(), serialised_bytes(&sendable_data));
+      sendable_data
+  }
+
+  #[cfg(feature = "use-bincode")]
+  pub fn handle_account_transfer_bincode(&mut self, name: Identity, serialised: &[u8]) -> Result<(), DecodeError> {
+      let account = try!(MaidManagerAccount::from_bincode(serialised)
+                             .map_err(|error| DecodeError::InvalidAccount(error.to_string())));
+      self.accept_transfer(name, account)
+  }
+
+  // applies each transfer as handle_account_transfer, so by the same policy, returning how many
+  // were applied; one which fails to decode or validate is logged and skipped
+  pub fn load_transfers<I: IntoIterator<Item = (Identity, Vec<u8>)>>(&mut self, transfers: I) -> usize {
+      let mut applied = 0;
+      for (name, serialised) in transfers {
+          match self.handle_account_transfer(name.clone(), &serialised) {
+              Ok(()) => applied += 1,
+              Err(error) => warn!("MaidManager skipped the transfer of account {}: {}", name_as_hex(&name), error),
+          }
+      }
+      applied
+  }
+
+  fn accept_transfer(&mut self, name: Identity, account: MaidManagerAccount) -> Result<(), DecodeError> {
+      if account.validate().is_err() {
+          return Err(DecodeError::InvalidAccount("data_stored, space_available and the reservations overflow".to_string()));
+      }
+      if let Some(value) = self.storage.get_mut(&name) {
+          if account.data_stored > value.data_stored {
+              self.total_stored = self.total_stored.saturating_sub(value.data_stored).saturating_add(account.data_stored);
+              *value = account;
+          }
+      } else {
+          self.insert_account(name, account);
+      }
+      Ok(())
+  }
+
+  pub fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      let started = Instant::now();
+      let sendable_data = encode_accounts(self.take_accounts(close_group));
+      self.record_transfer(started.elapsed(), sendable_data.len(), serialised_bytes(&sendable_data));
+      sendable_data
+  }
+
+  fn record_transfer(&mut self, duration: Duration, accounts: usize, bytes: u64) {
+      self.metrics.last_transfer_duration = Some(duration);
+      self.metrics.last_transfer_accounts = accounts;
+      self.metrics.last_transfer_bytes = bytes;
+  }
+
+  // as retrieve_all_and_reset, but yielding at most chunk_size accounts at a time; each batch is
+  // only removed and encoded when the iterator reaches it, so any batches not consumed are retained
+  pub fn retrieve_all_and_reset_chunked<'a>(&'a mut self, close_group: &Vec<NameType>, chunk_size: usize)
+          -> impl Iterator<Item = Vec<generic_sendable_type::GenericSendableType>> + 'a {
+      assert!(chunk_size > 0, "chunk_size must be positive");
+      let mut names = self.names_in_group(close_group).into_iter();
+      let (mut duration, mut accounts, mut bytes) = (Duration::new(0, 0), 0, 0);
+      ::std::iter::from_fn(move || {
+          let started = Instant::now();
+          let chunk: Vec<Identity> = names.by_ref().take(chunk_size).collect();
+          if chunk.is_empty() {
+              self.record_transfer(duration, accounts, bytes);
+              return None;
+          }
+          let taken = chunk.into_iter()
+                           .filter_map(|name| self.remove_account(&name).map(|account| (name, account)))
+                           .collect();
+          let sendable_data = encode_accounts(taken);
+          duration = duration + started.elapsed();
+          accounts += sendable_data.len();
+          bytes += serialised_bytes(&sendable_data);
+          self.record_transfer(duration, accounts, bytes);
+          Some(sendable_data)
+      })
+  }
+
+  // the in-group accounts as a single compressed blob, for ingest_compressed on the receiving manager;
+  // if they can't be encoded the error is returned and the accounts are left in place
+  pub fn retrieve_all_compressed(&mut self, close_group: &Vec<NameType>) -> io::Result<Vec<u8>> {
+      let started = Instant::now();
+      let accounts: Vec<(Identity, MaidManagerAccount)> =
+          self.storage.peek_iter()
+                      .filter(|&(name, _)| close_group.iter().find(|a| *a == name).is_some())
+                      .map(|(name, account)| (name.clone(), account.clone()))
+                      .collect();
+      let compressed = try!(compress_accounts(&accounts));
+      let _ = self.take_accounts(close_group);
+      self.record_transfer(started.elapsed(), accounts.len(), compressed.len() as u64);
+      Ok(compressed)
+  }
+
+  // each account is merged as if received by handle_account_transfer, returning how many there were
+  pub fn ingest_compressed(&mut self, compressed: &[u8]) -> io::Result<usize> {
+      let accounts = try!(decompress_accounts(compressed));
+      let count = accounts.len();
+      for (name, account) in accounts {
+          if let Err(error) = self.accept_transfer(name.clone(), account) {
+              error!("MaidManager dropped compressed account {}: {}", name_as_hex(&name), error);
+          }
+      }
+      Ok(count)
+  }
+
+  // only accounts in close_group are handed over, the others stay with us. The cache iterates in
+  // name order, so every retrieve_all_and_reset variant hands accounts over sorted by name, whatever
+  // order they were added or last used in.
+  fn names_in_group(&self, close_group: &Vec<NameType>) -> Vec<Identity> {
+      self.storage.peek_iter()
+                  .map(|(name, _)| name)
+                  .filter(|name| close_group.iter().find(|a| a == name).is_some())
+                  .cloned()
+                  .collect()
+  }
+
+  fn take_accounts(&mut self, close_group: &Vec<NameType>) -> Vec<(Identity, MaidManagerAccount)> {
+      let names = self.names_in_group(close_group);
+      let mut accounts = Vec::with_capacity(names.len());
+      for name in names {
+          match self.remove_account(&name) {
+              Some(account) => accounts.push((name, account)),
+              None => continue,
+          }
+      }
+      accounts
+  }
+
+  // returns the bytes actually freed, zero for an unknown name
+  pub fn delete_data(&mut self, name : &Identity, size: u64) -> u64 {
+      let freed = match self.storage.get_mut(name) {
+          Some(value) => value.delete_data(size),
+          None => return 0,
+      };
+      self.metrics.deletes = self.metrics.deletes.saturating_add(1);
+      self.total_stored = self.total_stored.saturating_sub(freed);
+      freed
+  }
+
+  pub fn remove_account(&mut self, name : &Identity) -> Option<MaidManagerAccount> {
+      let account = self.storage.remove(name);
+      if let Some(ref account) = account {
+          self.total_stored = self.total_stored.saturating_sub(account.data_stored);
+      }
+      if let (Some(on_remove), Some(account)) = (self.on_remove.as_ref(), account.as_ref()) {
+          on_remove(name, account);
+      }
+      account
+  }
+
+  // removes the account as remove_account does, but leaves a tombstone so that puts for name which
+  // arrive late are rejected with PutError::Deleted, and a new account for it with
+  // CreateError::Deleted, until purge_tombstones drops it; a name not held is tombstoned all the
+  // same. Transfers on churn aren't checked against the tombstones.
+  pub fn soft_delete(&mut self, name: &Identity) {
+      let _ = self.remove_account(name);
+      let _ = self.tombstones.insert(name.clone(), SystemTime::now());
+  }
+
+  pub fn is_deleted(&self, name: &Identity) -> bool {
+      self.tombstones.contains_key(name)
+  }
+
+  // drops the tombstones left by soft_delete at least older_than ago, returning how many were
+  // dropped; one dated after now by a clock change is kept
+  pub fn purge_tombstones(&mut self, older_than: Duration) -> usize {
+      let before = self.tombstones.len();
+      self.tombstones.retain(|_, deleted| deleted.elapsed().map(|age| age < older_than).unwrap_or(true));
+      before - self.tombstones.len()
+  }
+
+  // moves the account held under from to to, every field kept as it is; false, with nothing changed,
+  // if from isn't held, to already is or to is reserved. The account isn't passed to on_remove.
+  pub fn rename_account(&mut self, from: &Identity, to: Identity) -> bool {
+      if self.is_reserved(&to) || self.storage.contains_key(&to) {
+          return false;
+      }
+      match self.storage.remove(from) {
+          Some(account) => {
+              let _ = self.storage.insert(to, account);
+              true
+          }
+          None => false,
+      }
+  }
+
+  // the sum of data_stored over every account, maintained as the accounts change rather than by
+  // iterating; accounts evicted to make room are subtracted, but those outliving the ttl aren't, so
+  // with a ttl set recompute should be called after anything which may have dropped accounts
+  pub fn total_stored(&self) -> u64 {
+      self.total_stored
+  }
+
+  // rebuilds total_stored from the accounts held
+  pub fn recompute(&mut self) {
+      self.total_stored = self.storage.peek_iter()
+                                      .fold(0u64, |total, (_, account)| total.saturating_add(account.data_stored));
+  }
+
+  // called with each account dropped from the database, whether removed or evicted to make room for
+  // another; accounts which outlive the ttl are dropped silently
+  pub fn set_on_remove(&mut self, on_remove: Box<dyn Fn(&Identity, &MaidManagerAccount) + Send>) {
+      self.on_remove = Some(on_remove);
+  }
+
+  // called by put_data and put_data_batch with an account whose put has just taken it across the
+  // watermark set by MaidManagerAccount::set_watermark
+  pub fn set_on_watermark(&mut self, on_watermark: Box<dyn Fn(&Identity, &MaidManagerAccount) + Send>) {
+      self.on_watermark = Some(on_watermark);
+  }
+
+  fn insert_account(&mut self, name: Identity, account: MaidManagerAccount) {
+      // made room for here rather than by the insert, so that the account evicted is known
+      if let Some((evicted, evicted_account)) = self.storage.evict_for(&name) {
+          self.total_stored = self.total_stored.saturating_sub(evicted_account.data_stored);
+          self.metrics.evictions = self.metrics.evictions.saturating_add(1);
+          if let Some(ref on_remove) = self.on_remove {
+              on_remove(&evicted, &evicted_account);
+          }
+      }
+      self.total_stored = self.total_stored.saturating_add(account.data_stored);
+      if let Some(replaced) = self.storage.insert(name, account) {
+          self.total_stored = self.total_stored.saturating_sub(replaced.data_stored);
+      }
+  }
+
+  // unknown names are skipped
+  pub fn delete_data_batch(&mut self, items: &[(Identity, u64)]) {
+      for &(ref name, size) in items.iter() {
+          let _ = self.delete_data(name, size);
+      }
+  }
+
+  // removes accounts holding no data and still on the default allowance, returning how many went
+  pub fn prune_empty(&mut self) -> usize {
+      let default_allowance = self.default_allowance;
+      let names: Vec<Identity> = self.storage.peek_iter()
+                                     .filter(|&(_, account)| {
+                                         account.data_stored == 0 && account.space_available == default_allowance
+                                     })
+                                     .map(|(name, _)| name.clone())
+                                     .collect();
+      for name in names.iter() {
+          let _ = self.remove_account(name);
+      }
+      names.len()
+  }
+}
+
+// an 8 byte big endian account count, then the deflated cbor encoding of the accounts
+fn compress_accounts(accounts: &Vec<(Identity, MaidManagerAccount)>) -> io::Result<Vec<u8>> {
+    let count = accounts.len() as u64;
+    let mut compressed: Vec<u8> = (0..8).map(|i| (count >> (56 - 8 * i)) as u8).collect();
+    let mut e = cbor::Encoder::from_memory();
+    if let Err(error) = e.encode(&[accounts]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string()));
+    }
+    {
+        let mut encoder = DeflateEncoder::new(&mut compressed, Compression::default());
+        try!(encoder.write_all(e.as_bytes()));
+        let _ = try!(encoder.finish());
+    }
+    Ok(compressed)
+}
+
+fn decompress_accounts(compressed: &[u8]) -> io::Result<Vec<(Identity, MaidManagerAccount)>> {
+    if compressed.len() < 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing account count"));
+    }
+    let count = compressed[..8].iter().fold(0u64, |count, &byte| (count << 8) | byte as u64);
+    let mut serialised = Vec::new();
+    try!(DeflateDecoder::new(&compressed[8..]).read_to_end(&mut serialised));
+    let mut d = cbor::Decoder::from_bytes(serialised);
+    let accounts: Vec<(Identity, MaidManagerAccount)> = match d.decode().next() {
+        Some(Ok(accounts)) => accounts,
+        Some(Err(error)) => return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+        None => Vec::new(),
+    };
+    if accounts.len() as u64 != count {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("expected {} accounts, found {}", count, accounts.len())));
+    }
+    Ok(accounts)
+}
+
+// the cbor encoding of an account, as handed over by retrieve_all_and_reset
+fn decode_transfer(serialised: &[u8]) -> Result<MaidManagerAccount, DecodeError> {
+    let mut d = cbor::Decoder::from_bytes(serialised);
+    match d.decode().next() {
+        Some(Ok(account)) => Ok(account),
+        Some(Err(error)) => Err(DecodeError::InvalidAccount(error.to_string())),
+        None => Err(DecodeError::Empty),
+    }
+}
+
+fn name_as_hex(name: &Identity) -> String {
+    name.get_id().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn serialised_bytes(sent: &[generic_sendable_type::GenericSendableType]) -> u64 {
+    sent.iter().map(|sendable| sendable.serialised_contents().len() as u64).sum()
+}
+
+fn encode_accounts(accounts: Vec<(Identity, MaidManagerAccount)>) -> Vec<generic_sendable_type::GenericSendableType> {
+    encode_all(accounts, MAID_MANAGER_ACCOUNT_TAG)
+}
+
+// an element which fails to encode is logged and left out rather than failing the whole transfer
+fn encode_all<T: Encodable>(elements: Vec<(Identity, T)>, type_tag: u64) -> Vec<generic_sendable_type::GenericSendableType> {
+    let mut sendable_data = Vec::with_capacity(elements.len());
+    for (name, element) in elements {
+        let mut e = cbor::Encoder::from_memory();
+        match e.encode(&[&element]) {
+            Ok(()) => sendable_data.push(generic_sendable_type::GenericSendableType::new(name, type_tag, e.into_bytes())),
+            Err(error) => error!("MaidManager failed to encode account {} for transfer: {}", name_as_hex(&name), error),
+        }
+    }
+    sendable_data
+}
+
+// a MaidManagerDatabase which can be cloned and shared between threads, each call holds the lock
+// only for the duration of the underlying database operation
+#[derive(Clone)]
+pub struct SharedMaidManagerDatabase {
+  database: Arc<Mutex<MaidManagerDatabase>>,
+}
+
+impl SharedMaidManagerDatabase {
+  pub fn new(database: MaidManagerDatabase) -> SharedMaidManagerDatabase {
+      SharedMaidManagerDatabase { database: Arc::new(Mutex::new(database)) }
+  }
+
+  pub fn create_account(&self, name: Identity, request: AccountCreation) -> Result<(), CreateError> {
+      self.database.lock().unwrap().create_account(name, request)
+  }
+
+  pub fn put_data(&self, name: &Identity, size: u64) -> Result<(), PutError> {
+      self.database.lock().unwrap().put_data(name, size)
+  }
+
+  pub fn delete_data(&self, name: &Identity, size: u64) -> u64 {
+      self.database.lock().unwrap().delete_data(name, size)
+  }
+
+  pub fn exist(&self, name: &Identity) -> bool {
+      self.database.lock().unwrap().exist(name)
+  }
+
+  pub fn get_account(&self, name: &Identity) -> Option<MaidManagerAccount> {
+      self.database.lock().unwrap().get_account(name)
+  }
+
+  pub fn stats(&self) -> MaidManagerStats {
+      self.database.lock().unwrap().stats()
+  }
+
+  // the accounts are removed under the lock, but serialised after it has been released; the lock is
+  // taken again to record the transfer in the metrics
+  pub fn retrieve_all_and_reset(&self, close_group: &Vec<NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      let started = Instant::now();
+      let accounts = self.database.lock().unwrap().take_accounts(close_group);
+      let sendable_data = encode_accounts(accounts);
+      self.database.lock().unwrap().record_transfer(started.elapsed(), sendable_data.len(),
+                                                    serialised_bytes(&sendable_data));
+      sendable_data
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use routing;
+  use routing::NameType;
+  use cbor;
+  use rand;
+
+  fn create(db: &mut MaidManagerDatabase, name: &NameType) {
+    let allowance = db.default_allowance();
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(allowance)), Ok(()));
+  }
+
+  #[test]
+  fn exist() {
+    let mut db = MaidManagerDatabase::new();
+    let name = routing::test_utils::Random::generate_random();
+    assert_eq!(db.exist(&name), false);
+    create(&mut db, &name);
+    assert_eq!(db.exist(&name), true);
+  }
+
+  #[test]
+  fn create_account() {
+    let mut db = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::AccountNotFound));
+    assert_eq!(db.exist(&name), false);
+
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(1073741825)),
+               Err(CreateError::AllowanceTooLarge { requested: 1073741825, max: 1073741824 }));
+    assert_eq!(db.exist(&name), false);
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(4096)), Ok(()));
+    assert_eq!(db.space_available(&name), Some(4096));
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+    assert_eq!(db.put_data(&name, 4096), Err(PutError::NotEnoughSpace));
+
+    // a duplicate creation leaves the existing account untouched
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(2048)), Err(CreateError::AlreadyExists));
+    assert_eq!(db.data_stored(&name), Some(1024));
+    assert_eq!(db.space_available(&name), Some(3072));
+
+    db.set_max_allowance(2 * 1073741824);
+    let name: NameType = routing::test_utils::Random::generate_random();
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(2 * 1073741824)), Ok(()));
+    assert_eq!(db.metrics().accounts_created, 2);
+  }
+
+  #[test]
+  fn insert_new() {
+    let mut db = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    let account = MaidManagerAccount::from_parts(1024, 3072).unwrap();
+    assert_eq!(db.insert_new(name.clone(), account.clone()), Ok(()));
+    assert_eq!(db.get_account(&name), Some(account));
+    assert_eq!(db.total_stored(), 1024);
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+
+    // a second registration of the same name is refused and the account held is left as it was
+    assert_eq!(db.insert_new(name.clone(), MaidManagerAccount::new()), Err(CreateError::AlreadyExists));
+    assert_eq!(db.data_stored(&name), Some(2048));
+    assert_eq!(db.space_available(&name), Some(2048));
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(4096)), Err(CreateError::AlreadyExists));
+    assert_eq!(db.total_stored(), 2048);
+
+    let created: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &created);
+    assert_eq!(db.insert_new(created.clone(), MaidManagerAccount::empty()), Err(CreateError::AlreadyExists));
+    assert_eq!(db.space_available(&created), Some(1073741824));
+  }
+
+  #[test]
+  fn put_data() {
+    let mut db = MaidManagerDatabase::new();
+    let name = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 0), Ok(()));
+    assert_eq!(db.put_data(&name, 1), Ok(()));
+    assert_eq!(db.put_data(&name, 1073741823), Ok(()));
+    assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data(&name, 0), Ok(()));
+    assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.exist(&name), true);
+    let account = db.get_account(&name).unwrap();
+    assert_eq!(account.remaining_space(), 0);
+    assert_eq!(account.remaining_space(), account.get_available_space());
+  }
+
+  #[test]
+  fn max_accounts() {
+    let mut db = MaidManagerDatabase::with_max_accounts(2);
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    create(&mut db, &names[0]);
+    assert_eq!(db.insert_new(names[1].clone(), MaidManagerAccount::new()), Ok(()));
+    assert_eq!(db.create_account(names[2].clone(), AccountCreation::new(4096)), Err(CreateError::AtCapacity));
+    assert_eq!(db.insert_new(names[2].clone(), MaidManagerAccount::new()), Err(CreateError::AtCapacity));
+    assert_eq!(db.insert_new(names[0].clone(), MaidManagerAccount::new()), Err(CreateError::AlreadyExists));
+    assert_eq!(db.exist(&names[2]), false);
+    assert_eq!(db.metrics().accounts_created, 1);
+
+    // the accounts held are still updated
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    assert_eq!(db.put_data(&names[1], 2048), Ok(()));
+    assert_eq!(db.delete_data(&names[0], 512), 512);
+    assert_eq!(db.data_stored(&names[0]), Some(512));
+    assert_eq!(db.data_stored(&names[1]), Some(2048));
+    assert_eq!(db.put_data(&names[2], 1024), Err(PutError::AccountNotFound));
+
+    // and a new name fits once one is removed
+    assert!(db.remove_account(&names[1]).is_some());
+    create(&mut db, &names[2]);
+    assert_eq!(db.create_account(names[3].clone(), AccountCreation::new(4096)), Err(CreateError::AtCapacity));
+  }
+
+  #[test]
+  fn retrieve_all_and_reset_order() {
+    let names: Vec<NameType> = (1..6u8).map(|i| NameType([i * 37; 64])).collect();
+    let mut sorted = names.clone();
+    sorted.sort();
+    let retrieved = |order: &[usize]| {
+      let mut db = MaidManagerDatabase::new();
+      for &i in order.iter() {
+        create(&mut db, &names[i]);
+        assert_eq!(db.put_data(&names[i], 1024 * i as u64), Ok(()));
+      }
+      // touching an account changes its place in the cache's lru order but not in the output
+      let _ = db.put_data(&names[order[0]], 0);
+      let close_group: Vec<NameType> = order.iter().rev().map(|&i| names[i].clone()).collect();
+      db.retrieve_all_and_reset(&close_group).iter().map(|element| element.name()).collect::<Vec<NameType>>()
+    };
+    assert_eq!(retrieved(&[0, 1, 2, 3, 4]), sorted);
+    assert_eq!(retrieved(&[4, 3, 2, 1, 0]), sorted);
+    assert_eq!(retrieved(&[2, 0, 4, 1, 3]), sorted);
+  }
+
+  #[test]
+  fn soft_delete() {
+    let mut db = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+    db.soft_delete(&name);
+    assert_eq!(db.exist(&name), false);
+    assert!(db.is_deleted(&name));
+    assert_eq!(db.total_stored(), 0);
+
+    // a late put is rejected, and the name can't be given a new account, until the tombstone goes
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::Deleted));
+    assert_eq!(db.can_put(&name, 1024), false);
+    let allowance = db.default_allowance();
+    assert_eq!(db.create_account(name.clone(), AccountCreation::new(allowance)), Err(CreateError::Deleted));
+    assert_eq!(db.insert_new(name.clone(), MaidManagerAccount::new()), Err(CreateError::Deleted));
+    assert_eq!(db.purge_tombstones(::std::time::Duration::new(3600, 0)), 0);
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::Deleted));
+
+    assert_eq!(db.purge_tombstones(::std::time::Duration::new(0, 0)), 1);
+    assert!(!db.is_deleted(&name));
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::AccountNotFound));
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 512), Ok(()));
+    assert_eq!(db.data_stored(&name), Some(512));
+    assert_eq!(db.purge_tombstones(::std::time::Duration::new(0, 0)), 0);
+
+    // a name never held can be tombstoned too
+    let unknown: NameType = routing::test_utils::Random::generate_random();
+    db.soft_delete(&unknown);
+    assert_eq!(db.put_data(&unknown, 1024), Err(PutError::Deleted));
+  }
+
+  #[test]
+  fn put_data_with_id() {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    let name: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    db.enable_put_dedup(2);
+    assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+    assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+    assert_eq!(db.data_stored(&name), Some(1024));
+    assert_eq!(db.total_stored(), 1024);
+    assert_eq!(db.metrics().puts_ok, 1);
+
+    // a rejected put is answered the same when repeated, even once there is room for it
+    assert_eq!(db.put_data_with_id(&name, 4096, [2u8; 32]), false);
+    assert_eq!(db.delete_data(&name, 1024), 1024);
+    assert_eq!(db.put_data_with_id(&name, 4096, [2u8; 32]), false);
+    assert_eq!(db.data_stored(&name), Some(0));
+
+    // only the last two ids are remembered
+    assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+    assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+    assert_eq!(db.data_stored(&name), Some(2048));
+
+    db.disable_put_dedup();
+    assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+    assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+    assert_eq!(db.data_stored(&name), Some(4096));
+  }
+
+  #[test]
+  fn put_at_boundary() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.put_data(1024), Ok(()));
+    assert_eq!(account.put_data(3072), Ok(()));
+    assert_eq!(account.get_data_stored(), 4096);
+    assert_eq!(account.get_available_space(), 0);
+    assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace));
+
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.put_data(4097), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.put_data(4096), Ok(()));
+    let mut account = MaidManagerAccount::with_allowance(::std::u64::MAX);
+    assert_eq!(account.put_data(::std::u64::MAX), Ok(()));
+    assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace));
+  }
+
+  #[test]
+  fn semantically_eq() {
+    let names: Vec<NameType> = (1..5u8).map(|i| NameType([i; 64])).collect();
+    let mut forwards = MaidManagerDatabase::new();
+    for (i, name) in names.iter().enumerate() {
+      create(&mut forwards, name);
+      assert_eq!(forwards.put_data(name, 1024 * i as u64), Ok(()));
+    }
+    let mut backwards = MaidManagerDatabase::with_capacity(100, ::std::time::Duration::from_secs(3600));
+    for (i, name) in names.iter().enumerate().rev() {
+      create(&mut backwards, name);
+      assert_eq!(backwards.put_data(name, 512 * i as u64), Ok(()));
+      assert_eq!(backwards.put_data(name, 512 * i as u64), Ok(()));
+    }
+    assert!(forwards.semantically_eq(&backwards) && backwards.semantically_eq(&forwards));
+
+    backwards.storage.get_mut(&names[0]).unwrap().set_rate_limit(1, ::std::time::Duration::from_secs(60));
+    assert!(forwards.semantically_eq(&backwards));
+    let _ = backwards.storage.get_mut(&names[1]).unwrap().reserve(1).unwrap();
+    assert!(!forwards.semantically_eq(&backwards));
+    let _ = forwards.storage.get_mut(&names[1]).unwrap().reserve(1).unwrap();
+    assert!(forwards.semantically_eq(&backwards));
+    create(&mut forwards, &NameType([9u8; 64]));
+    assert!(!forwards.semantically_eq(&backwards) && !backwards.semantically_eq(&forwards));
+  }
+
+  #[test]
+  fn name_validation() {
+    let zero = NameType([0u8; 64]);
+    let mut db = MaidManagerDatabase::new();
+    create(&mut db, &zero);
+    assert_eq!(db.put_data(&zero, 1024), Ok(()));
+
+    let mut db = MaidManagerDatabase::with_name_validation(true);
+    let allowance = db.default_allowance();
+    assert_eq!(db.create_account(zero.clone(), AccountCreation::new(allowance)), Err(CreateError::InvalidName));
+    assert_eq!(db.put_data(&zero, 1024), Err(PutError::InvalidName));
+    assert_eq!(db.can_put(&zero, 1024), false);
+    assert_eq!(db.exist(&zero), false);
+
+    let reserved = NameType([7u8; 64]);
+    create(&mut db, &reserved);
+    db.reserve_name(reserved.clone());
+    assert_eq!(db.put_data(&reserved, 1024), Err(PutError::InvalidName));
+    assert_eq!(db.metrics().puts_rejected, 2);
+    let name = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+  }
+
+  #[test]
+  fn candidates_with_space() {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    let names: Vec<NameType> = (1..5u8).map(|i| NameType([i; 64])).collect();
+    for (name, &stored) in names.iter().zip([1024, 3072, 0, 1024].iter()) {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, stored), Ok(()));
+    }
+    // free space is 3072, 1024, 4096 and 3072
+    assert_eq!(db.candidates_with_space(0), vec![names[2].clone(), names[0].clone(), names[3].clone(),
+                                                  names[1].clone()]);
+    assert_eq!(db.candidates_with_space(1025), vec![names[2].clone(), names[0].clone(), names[3].clone()]);
+    assert_eq!(db.candidates_with_space(3071), vec![names[2].clone(), names[0].clone(), names[3].clone()]);
+    assert_eq!(db.candidates_with_space(3072), vec![names[2].clone(), names[0].clone(), names[3].clone()]);
+    assert_eq!(db.candidates_with_space(3073), vec![names[2].clone()]);
+    assert_eq!(db.candidates_with_space(4096), vec![names[2].clone()]);
+    assert_eq!(db.candidates_with_space(4097), Vec::<NameType>::new());
+  }
+
+  #[test]
+  fn encode_all_skips_failures() {
+    // cbor can't encode a map with non-string keys
+    let mut unencodable = ::std::collections::BTreeMap::new();
+    let _ = unencodable.insert(1u64, 1u64);
+    let good = NameType([1u8; 64]);
+    let elements = vec![(good.clone(), ::std::collections::BTreeMap::new()), (NameType([2u8; 64]), unencodable)];
+    let sendable_data = super::encode_all(elements, MAID_MANAGER_ACCOUNT_TAG);
+    assert_eq!(sendable_data.len(), 1);
+    assert_eq!(sendable_data[0].name(), good);
+  }
+
+  #[test]
+  fn can_put() {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    let name = routing::test_utils::Random::generate_random();
+    assert_eq!(db.can_put(&name, 4096), true);
+    assert_eq!(db.can_put(&name, 4097), false);
+    assert_eq!(db.exist(&name), false);
+    create(&mut db, &name);
+    for &size in [1024, 4096, ::std::u64::MAX, 2048, 1024, 1, 0].iter() {
+      let predicted = db.can_put(&name, size);
+      assert_eq!(db.put_data(&name, size).is_ok(), predicted);
+    }
+    assert_eq!(db.data_stored(&name), Some(4096));
+  }
+
+  #[test]
+  fn delete_data() {
+    let mut db = MaidManagerDatabase::new();
+    let name = routing::test_utils::Random::generate_random();
+    assert_eq!(db.delete_data(&name, 0), 0);
+    assert_eq!(db.exist(&name), false);
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 0), Ok(()));
+    assert_eq!(db.exist(&name), true);
+    assert_eq!(db.delete_data(&name, 1), 0);
+    assert_eq!(db.exist(&name), true);
+    assert_eq!(db.put_data(&name, 1073741824), Ok(()));
+    assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.delete_data(&name, 1), 1);
+    assert_eq!(db.put_data(&name, 1), Ok(()));
+    assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.delete_data(&name, 1073741825), 1073741824);
+    assert_eq!(db.exist(&name), true);
+    assert_eq!(db.put_data(&name, 1073741825), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data(&name, 1073741824), Ok(()));
+  }
+
+  #[test]
+  fn default_allowance() {
+    let mut db = MaidManagerDatabase::with_default_allowance(2 * 1073741824);
+    let name = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1073741824 + 536870912), Ok(()));
+    assert_eq!(db.space_available(&name), Some(536870912));
+    assert_eq!(db.put_data(&name, 536870913), Err(PutError::NotEnoughSpace));
+
+    let mut db = MaidManagerDatabase::new();
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1073741824 + 536870912), Err(PutError::NotEnoughSpace));
+
+    let mut account = MaidManagerAccount::with_allowance(1024);
+    assert_eq!(account.get_available_space(), 1024);
+    assert_eq!(account.put_data(1025), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.put_data(1024), Ok(()));
+  }
+
+  #[test]
+  fn utilization() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.utilization(), 0.0);
+    assert_eq!(account.is_full(), false);
+    assert_eq!(account.put_data(2048), Ok(()));
+    assert_eq!(account.utilization(), 0.5);
+    assert_eq!(account.is_full(), false);
+    assert_eq!(account.put_data(2048), Ok(()));
+    assert_eq!(account.utilization(), 1.0);
+    assert_eq!(account.is_full(), true);
+    account.delete_data(4096);
+    assert_eq!(account.utilization(), 0.0);
+
+    let account = MaidManagerAccount::with_allowance(0);
+    assert_eq!(account.utilization(), 1.0);
+    assert_eq!(account.is_full(), true);
+  }
+
+  #[test]
+  fn prune_empty() {
+    let mut db = MaidManagerDatabase::new();
+    assert_eq!(db.prune_empty(), 0);
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter().take(3) {
+      create(&mut db, name);
+    }
+    assert_eq!(db.put_data(&names[0], 0), Ok(()));
+    assert_eq!(db.put_data(&names[1], 1024), Ok(()));
+    assert_eq!(db.put_data(&names[2], 1024), Ok(()));
+    db.delete_data(&names[2], 1024);
+    // an account whose allowance differs from the default is kept even when empty
+    db.storage.insert(names[3].clone(), MaidManagerAccount::with_allowance(2048));
+
+    assert_eq!(db.prune_empty(), 2);
+    assert_eq!(db.exist(&names[0]), false);
+    assert_eq!(db.exist(&names[1]), true);
+    assert_eq!(db.exist(&names[2]), false);
+    assert_eq!(db.exist(&names[3]), true);
+    assert_eq!(db.prune_empty(), 0);
+  }
+
+  #[test]
+  fn reserve_and_commit() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    let id = account.reserve(1024).unwrap();
+    assert_eq!(account.get_available_space(), 3072);
+    assert_eq!(account.get_data_stored(), 0);
+    assert_eq!(account.get_reserved_space(), 1024);
+    assert_eq!(account.commit(id), true);
+    assert_eq!(account.get_available_space(), 3072);
+    assert_eq!(account.get_data_stored(), 1024);
+    assert_eq!(account.get_reserved_space(), 0);
+    // a second commit, or a cancel after commit, changes nothing
+    assert_eq!(account.commit(id), false);
+    assert_eq!(account.cancel(id), false);
+    assert_eq!(account.get_available_space(), 3072);
+    assert_eq!(account.get_data_stored(), 1024);
+  }
+
+  #[test]
+  fn reserve_and_cancel() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    let first = account.reserve(1024).unwrap();
+    let second = account.reserve(2048).unwrap();
+    assert!(first != second);
+    assert_eq!(account.get_available_space(), 1024);
+    assert_eq!(account.cancel(first), true);
+    assert_eq!(account.get_available_space(), 2048);
+    assert_eq!(account.get_data_stored(), 0);
+    assert_eq!(account.cancel(first), false);
+    assert_eq!(account.commit(first), false);
+    assert_eq!(account.commit(second), true);
+    assert_eq!(account.get_available_space(), 2048);
+    assert_eq!(account.get_data_stored(), 2048);
+    assert_eq!(account.commit(ReservationId(100)), false);
+  }
+
+  #[test]
+  fn reserve_too_much() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.reserve(4097), None);
+    assert_eq!(account.get_available_space(), 4096);
+    let id = account.reserve(4096).unwrap();
+    assert_eq!(account.reserve(1), None);
+    assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.is_full(), true);
+    assert_eq!(account.cancel(id), true);
+    assert_eq!(account.get_available_space(), 4096);
+  }
+
+  #[test]
+  fn shared_database() {
+    let shared = SharedMaidManagerDatabase::new(MaidManagerDatabase::new());
+    let names: Vec<NameType> = (0..8).map(|_| routing::test_utils::Random::generate_random()).collect();
+    let threads: Vec<_> = names.iter().cloned().map(|name| {
+        let shared = shared.clone();
+        ::std::thread::spawn(move || {
+            assert_eq!(shared.create_account(name.clone(), AccountCreation::new(1073741824)), Ok(()));
+            for _ in 0..100 {
+                assert_eq!(shared.put_data(&name, 1024), Ok(()));
+            }
+            shared.delete_data(&name, 1024 * 50);
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let stats = shared.stats();
+    assert_eq!(stats.account_count, names.len());
+    assert_eq!(stats.total_data_stored, names.len() as u64 * 1024 * 50);
+    assert_eq!(stats.total_space_available, names.len() as u64 * (1073741824 - 1024 * 50));
+    for name in names.iter() {
+        assert_eq!(shared.exist(name), true);
+        assert_eq!(shared.get_account(name).unwrap().get_data_stored(), 1024 * 50);
+    }
+
+    let sendable = shared.retrieve_all_and_reset(&names);
+    assert_eq!(sendable.len(), names.len());
+    assert_eq!(shared.stats().account_count, 0);
+  }
+
+  #[test]
+  fn put_data_batch() {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    assert!(db.put_data_batch(&[]).is_empty());
+    let name_1: NameType = routing::test_utils::Random::generate_random();
+    let name_2: NameType = routing::test_utils::Random::generate_random();
+    let name_3: NameType = routing::test_utils::Random::generate_random();
+    let unknown: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    create(&mut db, &name_3);
+    assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+
+    let results = db.put_data_batch(&[(name_1.clone(), 2048),
+                                      (name_2.clone(), 3072),
+                                      (name_2.clone(), 2048),
+                                      (name_2.clone(), 1024),
+                                      (name_1.clone(), 1024),
+                                      (unknown.clone(), 0),
+                                      (unknown.clone(), 0),
+                                      (name_3.clone(), 0)]);
+    assert_eq!(results, vec![true, true, false, true, true, false, false, true]);
+    assert_eq!(db.exist(&unknown), false);
+    assert_eq!(db.data_stored(&name_1), Some(4096));
+    assert_eq!(db.data_stored(&name_2), Some(4096));
+    assert_eq!(db.space_available(&name_2), Some(0));
+    assert_eq!(db.data_stored(&name_3), Some(0));
+    assert_eq!(db.put_data_batch(&[(name_1.clone(), 1)]), vec![false]);
+    assert_eq!(db.metrics().puts_ok, 6);
+    assert_eq!(db.metrics().puts_rejected, 4);
+
+    // names put_data would reject are rejected by the batch too, even with an account
+    let mut db = MaidManagerDatabase::with_name_validation(true);
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    create(&mut db, &name_3);
+    db.reserve_name(name_1.clone());
+    db.soft_delete(&name_2);
+    assert_eq!(db.put_data_batch(&[(name_1.clone(), 1024), (name_2.clone(), 1024), (name_3.clone(), 1024)]),
+               vec![false, false, true]);
+    assert_eq!(db.data_stored(&name_1), Some(0));
+    assert_eq!(db.total_stored(), 1024);
+    assert_eq!(db.metrics().puts_ok, 1);
+    assert_eq!(db.metrics().puts_rejected, 2);
+  }
+
+  #[test]
+  fn delete_data_batch() {
+    let mut db = MaidManagerDatabase::new();
+    let name_1: NameType = routing::test_utils::Random::generate_random();
+    let name_2: NameType = routing::test_utils::Random::generate_random();
+    let unknown: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    assert_eq!(db.put_data(&name_1, 8192), Ok(()));
+    assert_eq!(db.put_data(&name_2, 1024), Ok(()));
+
+    db.delete_data_batch(&[(name_1.clone(), 1024),
+                           (unknown.clone(), 1024),
+                           (name_1.clone(), 2048),
+                           (name_2.clone(), 4096),
+                           (name_1.clone(), 1024)]);
+    assert_eq!(db.data_stored(&name_1), Some(8192 - 4096));
+    assert_eq!(db.space_available(&name_1), Some(1073741824 - 4096));
+    assert_eq!(db.data_stored(&name_2), Some(0));
+    assert_eq!(db.space_available(&name_2), Some(1073741824));
+    assert_eq!(db.exist(&unknown), false);
+  }
+
+  #[test]
+  fn metrics() {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    assert_eq!(db.metrics(), MaidManagerMetrics { puts_ok: 0, puts_rejected: 0, deletes: 0, accounts_created: 0, evictions: 0,
+                                                  last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 });
+    let name_1: NameType = routing::test_utils::Random::generate_random();
+    let name_2: NameType = routing::test_utils::Random::generate_random();
+    assert_eq!(db.put_data(&name_2, 1024), Err(PutError::AccountNotFound));
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+    assert_eq!(db.put_data(&name_1, 3072), Ok(()));
+    assert_eq!(db.put_data(&name_1, 1), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data(&name_2, 4097), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data_batch(&[(name_2.clone(), 2048), (name_2.clone(), 4096)]), vec![true, false]);
+    db.delete_data(&name_1, 1024);
+    db.delete_data_batch(&[(name_2.clone(), 1024), (routing::test_utils::Random::generate_random(), 1024)]);
+
+    assert_eq!(db.metrics(), MaidManagerMetrics { puts_ok: 3, puts_rejected: 4, deletes: 2, accounts_created: 2,
+                                               evictions: 0,
+                                               last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 });
+  }
+
+  #[test]
+  fn transfer_metrics() {
+    let mut db = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (0..100).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for (i, name) in names.iter().enumerate() {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+    }
+    assert_eq!(db.metrics().last_transfer_duration, None);
+
+    let sendable = db.retrieve_all_and_reset(&names[..60].to_vec());
+    let metrics = db.metrics();
+    assert!(metrics.last_transfer_duration.is_some());
+    assert_eq!(metrics.last_transfer_accounts, 60);
+    assert_eq!(metrics.last_transfer_bytes,
+               sendable.iter().map(|element| element.serialised_contents().len() as u64).sum::<u64>());
+    assert!(metrics.last_transfer_bytes > 0);
+
+    // a transfer of nothing replaces the figures of the last
+    assert!(db.retrieve_all_and_reset(&names[..60].to_vec()).is_empty());
+    assert!(db.metrics().last_transfer_duration.is_some());
+    assert_eq!(db.metrics().last_transfer_accounts, 0);
+    assert_eq!(db.metrics().last_transfer_bytes, 0);
+    // the chunks taken so far are totalled
+    let mut chunks = db.retrieve_all_and_reset_chunked(&names[60..80].to_vec(), 8);
+    let first = chunks.next().unwrap();
+    let second = chunks.next().unwrap();
+    drop(chunks);
+    assert_eq!(db.metrics().last_transfer_accounts, 16);
+    assert_eq!(db.metrics().last_transfer_bytes,
+               first.iter().chain(second.iter()).map(|element| element.serialised_contents().len() as u64).sum::<u64>());
+    assert_eq!(db.retrieve_all_and_reset_chunked(&names[60..80].to_vec(), 8).count(), 1);
+    assert_eq!(db.metrics().last_transfer_accounts, 4);
+
+    let compressed = db.retrieve_all_compressed(&names[80..90].to_vec()).unwrap();
+    assert_eq!(db.metrics().last_transfer_accounts, 10);
+    assert_eq!(db.metrics().last_transfer_bytes, compressed.len() as u64);
+
+    // as for the shared handle
+    let shared = SharedMaidManagerDatabase::new(db);
+    let sendable = shared.retrieve_all_and_reset(&names[90..].to_vec());
+    let metrics = shared.database.lock().unwrap().metrics();
+    assert_eq!(metrics.last_transfer_accounts, 10);
+    assert_eq!(metrics.last_transfer_bytes,
+               sendable.iter().map(|element| element.serialised_contents().len() as u64).sum::<u64>());
+  }
+
+  #[test]
+  fn validate_and_repair() {
+    let mut account = MaidManagerAccount::new();
+    assert_eq!(account.validate(), Ok(()));
+    assert_eq!(account.put_data(1024), Ok(()));
+    let _ = account.reserve(1024).unwrap();
+    assert_eq!(account.validate(), Ok(()));
+
+    let mut invalid = MaidManagerAccount::with_allowance(::std::u64::MAX);
+    invalid.data_stored = ::std::u64::MAX;
+    assert_eq!(invalid.validate(), Err(AccountError::Overflow));
+    invalid.repair();
+    assert_eq!(invalid.validate(), Ok(()));
+    assert_eq!(invalid.get_data_stored(), ::std::u64::MAX);
+    assert_eq!(invalid.get_available_space(), 0);
+
+    let mut invalid = MaidManagerAccount::with_allowance(::std::u64::MAX);
+    let _ = invalid.reserve(2048).unwrap();
+    invalid.data_stored = 1024;
+    assert_eq!(invalid.validate(), Err(AccountError::Overflow));
+    invalid.repair();
+    assert_eq!(invalid.validate(), Ok(()));
+    assert_eq!(invalid.get_reserved_space(), 2048);
+    assert_eq!(invalid.get_available_space(), ::std::u64::MAX - 2048 - 1024);
+
+    let mut invalid = MaidManagerAccount::with_allowance(::std::u64::MAX);
+    let _ = invalid.reserve(::std::u64::MAX).unwrap();
+    invalid.data_stored = 1024;
+    assert_eq!(invalid.validate(), Err(AccountError::Overflow));
+    invalid.repair();
+    assert_eq!(invalid.validate(), Ok(()));
+    assert_eq!(invalid.get_reserved_space(), 0);
+
+    // an invalid account received during churn is discarded
+    let mut db = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    let mut invalid = MaidManagerAccount::with_allowance(::std::u64::MAX);
+    invalid.data_stored = ::std::u64::MAX;
+    let mut e = cbor::Encoder::from_memory();
+    e.encode(&[&invalid]).unwrap();
+    match db.handle_account_transfer(name.clone(), e.as_bytes()) {
+      Err(DecodeError::InvalidAccount(_)) => (),
+      result => panic!("Unexpected {:?}", result),
+    }
+    assert_eq!(db.exist(&name), false);
+  }
+
+  #[test]
+  fn remove_account() {
+    let mut db = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    assert_eq!(db.remove_account(&name), None);
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+    let account = db.get_account(&name).unwrap();
+    assert_eq!(db.remove_account(&name), Some(account));
+    assert_eq!(db.exist(&name), false);
+    assert_eq!(db.remove_account(&name), None);
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::AccountNotFound));
+  }
+
+  #[test]
+  fn rename_account() {
+    let mut db = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (1..4u8).map(|i| NameType([i; 64])).collect();
+    create(&mut db, &names[0]);
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    let _ = db.storage.get_mut(&names[0]).unwrap().reserve(512).unwrap();
+    let account = db.get_account(&names[0]).unwrap();
+
+    assert_eq!(db.rename_account(&names[0], names[1].clone()), true);
+    assert_eq!(db.exist(&names[0]), false);
+    assert_eq!(db.get_account(&names[1]), Some(account.clone()));
+    assert_eq!(db.total_stored(), 1024);
+
+    // a missing source or an existing destination leaves both accounts as they were
+    assert_eq!(db.rename_account(&names[0], names[2].clone()), false);
+    assert_eq!(db.exist(&names[2]), false);
+    create(&mut db, &names[2]);
+    assert_eq!(db.rename_account(&names[1], names[2].clone()), false);
+    assert_eq!(db.get_account(&names[1]), Some(account));
+    assert_eq!(db.data_stored(&names[2]), Some(0));
+
+    let mut validated = MaidManagerDatabase::with_name_validation(true);
+    create(&mut validated, &names[0]);
+    assert_eq!(validated.rename_account(&names[0], NameType([0u8; 64])), false);
+    assert_eq!(validated.exist(&names[0]), true);
+  }
+
+  #[test]
+  fn on_remove() {
+    use std::sync::{Arc, Mutex};
+    let mut db = MaidManagerDatabase::with_capacity(2, ::std::time::Duration::from_secs(3600));
+    let removed: Arc<Mutex<Vec<(NameType, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+    {
+        let removed = removed.clone();
+        db.set_on_remove(Box::new(move |name: &NameType, account: &MaidManagerAccount| {
+            removed.lock().unwrap().push((name.clone(), account.get_data_stored()));
+        }));
+    }
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    assert_eq!(db.remove_account(&names[0]), None);
+    create(&mut db, &names[0]);
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    assert!(db.remove_account(&names[0]).is_some());
+    assert_eq!(*removed.lock().unwrap(), vec![(names[0].clone(), 1024)]);
+
+    // evicted to make room
+    create(&mut db, &names[1]);
+    create(&mut db, &names[2]);
+    assert_eq!(db.put_data(&names[1], 2048), Ok(()));
+    create(&mut db, &names[3]);
+    assert_eq!(db.exist(&names[2]), false);
+    assert_eq!(removed.lock().unwrap()[1], (names[2].clone(), 0));
+
+    let _ = db.retrieve_all_and_reset(&vec![names[1].clone()]);
+    assert_eq!(removed.lock().unwrap()[2], (names[1].clone(), 2048));
+    assert_eq!(db.prune_empty(), 1);
+    assert_eq!(removed.lock().unwrap()[3], (names[3].clone(), 0));
+    assert_eq!(removed.lock().unwrap().len(), 4);
+  }
+
+  #[test]
+  fn account_merge() {
+    let mut first = MaidManagerAccount::new();
+    assert_eq!(first.put_data(4096), Ok(()));
+    let mut second = MaidManagerAccount::new();
+    assert_eq!(second.put_data(1024), Ok(()));
+
+    let mut merged = first.clone();
+    merged.merge(&second);
+    assert_eq!(merged.get_data_stored(), 4096);
+    assert_eq!(merged.get_available_space(), 1073741824 - 4096);
+    let mut other_order = second.clone();
+    other_order.merge(&first);
+    assert_eq!(other_order, merged);
+
+    // merging is idempotent
+    let before = merged.clone();
+    merged.merge(&before);
+    assert_eq!(merged, before);
+    merged.merge(&second);
+    assert_eq!(merged, before);
+
+    let larger = MaidManagerAccount::with_allowance(2 * 1073741824);
+    merged.merge(&larger);
+    assert_eq!(merged.get_data_stored(), 4096);
+    assert_eq!(merged.get_available_space(), 2 * 1073741824 - 4096);
+    let mut other_order = larger.clone();
+    other_order.merge(&before);
+    assert_eq!(other_order, merged);
+
+    // reservations from both are kept, the larger of one held by both
+    let mut first = MaidManagerAccount::new();
+    assert_eq!(first.put_data(1024), Ok(()));
+    assert_eq!(first.reserve(512), Some(ReservationId(0)));
+    let mut second = MaidManagerAccount::new();
+    assert_eq!(second.reserve(1024), Some(ReservationId(0)));
+    assert_eq!(second.reserve(256), Some(ReservationId(1)));
+    let mut merged = first.clone();
+    merged.merge(&second);
+    let mut other_order = second.clone();
+    other_order.merge(&first);
+    assert_eq!(other_order, merged);
+    assert_eq!(merged.reservations, vec![(ReservationId(0), 1024), (ReservationId(1), 256)]);
+    assert_eq!(merged.next_reservation, 2);
+    assert_eq!(merged.get_data_stored(), 1024);
+    assert_eq!(merged.get_reserved_space(), 1280);
+    assert_eq!(merged.total_capacity(), 1073741824);
+    let before = merged.clone();
+    merged.merge(&first);
+    assert_eq!(merged, before);
+    assert_eq!(merged.reserve(1), Some(ReservationId(2)));
+
+    // reservations which don't fit beside the larger data_stored are dropped rather than adding to
+    // the total
+    let mut full = MaidManagerAccount::with_allowance(100);
+    assert_eq!(full.put_data(100), Ok(()));
+    let mut reserved = MaidManagerAccount::with_allowance(100);
+    assert_eq!(reserved.reserve(50), Some(ReservationId(0)));
+    let mut merged = full.clone();
+    merged.merge(&reserved);
+    let mut other_order = reserved.clone();
+    other_order.merge(&full);
+    assert_eq!(other_order, merged);
+    assert_eq!(merged.get_data_stored(), 100);
+    assert_eq!(merged.get_reserved_space(), 0);
+    assert_eq!(merged.get_available_space(), 0);
+    assert_eq!(merged.total_capacity(), 100);
+    assert_eq!(merged.commit(ReservationId(0)), false);
+
+    let mut partly = MaidManagerAccount::with_allowance(100);
+    assert_eq!(partly.put_data(60), Ok(()));
+    let mut reserved = MaidManagerAccount::with_allowance(100);
+    assert_eq!(reserved.reserve(30), Some(ReservationId(0)));
+    assert_eq!(reserved.reserve(30), Some(ReservationId(1)));
+    let mut merged = partly.clone();
+    merged.merge(&reserved);
+    let mut other_order = reserved.clone();
+    other_order.merge(&partly);
+    assert_eq!(other_order, merged);
+    assert_eq!(merged.reservations, vec![(ReservationId(0), 30)]);
+    assert_eq!(merged.get_available_space(), 10);
+    assert_eq!(merged.total_capacity(), 100);
+    assert_eq!(merged.reserve(1), Some(ReservationId(2)));
+  }
+
+  #[test]
+  fn builder() {
+    let mut db = MaidManagerDatabaseBuilder::new().capacity(2)
+                                                  .ttl(::std::time::Duration::from_secs(3600))
+                                                  .default_allowance(4096)
+                                                  .build();
+    let names: Vec<NameType> = (0..3).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter() {
+      create(&mut db, name);
+    }
+    assert_eq!(db.exist(&names[0]), false);
+    assert_eq!(db.space_available(&names[2]), Some(4096));
+    assert_eq!(db.put_data(&names[2], 4097), Err(PutError::NotEnoughSpace));
+    assert_eq!(db.put_data(&names[2], 4096), Ok(()));
+
+    let mut db = MaidManagerDatabase::default();
+    assert_eq!(db.default_allowance(), 1073741824);
+    create(&mut db, &names[0]);
+    assert_eq!(db.space_available(&names[0]), Some(1073741824));
+    assert_eq!(MaidManagerDatabaseBuilder::new().build().default_allowance(), 1073741824);
+  }
+
+  #[test]
+  fn rate_limit() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    account.set_rate_limit(3, ::std::time::Duration::from_millis(100));
+    for _ in 0..3 {
+      assert_eq!(account.can_put(1), true);
+      assert_eq!(account.put_data(1), Ok(()));
+    }
+    assert_eq!(account.can_put(1), false);
+    assert_eq!(account.put_data(1), Err(PutError::RateLimited));
+    assert_eq!(account.put_data(1), Err(PutError::RateLimited));
+    // over capacity is still reported as such
+    assert_eq!(account.put_data(8192), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.get_data_stored(), 3);
+
+    ::std::thread::sleep(::std::time::Duration::from_millis(110));
+    for _ in 0..3 {
+      assert_eq!(account.put_data(1), Ok(()));
+    }
+    assert_eq!(account.put_data(1), Err(PutError::RateLimited));
+    assert_eq!(account.get_data_stored(), 6);
+
+    account.clear_rate_limit();
+    assert_eq!(account.put_data(1), Ok(()));
+  }
+
+  #[test]
+  fn rate_limit_through_database() {
+    let mut db = MaidManagerDatabase::new();
+    let name = routing::test_utils::Random::generate_random();
+    create(&mut db, &name);
+    db.storage.get_mut(&name).unwrap().set_rate_limit(1, ::std::time::Duration::from_secs(60));
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+    assert_eq!(db.put_data(&name, 1024), Err(PutError::RateLimited));
+    assert_eq!(db.metrics().puts_rejected, 1);
+  }
+
+  #[test]
+  fn total_capacity() {
+    let mut account = MaidManagerAccount::with_allowance(8192);
+    assert_eq!(account.total_capacity(), 8192);
+    assert_eq!(account.put_data(1024), Ok(()));
+    assert_eq!(account.put_data(4096), Ok(()));
+    assert_eq!(account.put_data(8192), Err(PutError::NotEnoughSpace));
+    account.delete_data(2048);
+    let id = account.reserve(1024).unwrap();
+    assert_eq!(account.total_capacity(), 8192);
+    account.delete_data(::std::u64::MAX);
+    assert_eq!(account.commit(id), true);
+    assert_eq!(account.put_data(7168), Ok(()));
+    assert_eq!(account.get_data_stored(), 8192);
+    assert_eq!(account.total_capacity(), 8192);
+  }
+
+  #[test]
+  fn watermark() {
+    let mut account = MaidManagerAccount::with_allowance(1000);
+    assert_eq!(account.put_data(800), Ok(()));
+    account.set_watermark(0.9);
+    assert_eq!(account.put_data_watermarked(99), Ok(false));
+    assert_eq!(account.put_data_watermarked(1), Ok(true));
+    assert_eq!(account.put_data_watermarked(50), Ok(false));
+    assert_eq!(account.put_data_watermarked(100), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.put_data_watermarked(50), Ok(false));
+
+    // dropping back below re-arms it
+    let _ = account.delete_data(150);
+    assert_eq!(account.put_data_watermarked(10), Ok(false));
+    assert_eq!(account.put_data_watermarked(100), Ok(true));
+
+    // starting above the watermark doesn't count as a crossing
+    account.set_watermark(0.5);
+    assert_eq!(account.put_data_watermarked(1), Ok(false));
+    account.clear_watermark();
+    let _ = account.delete_data(1000);
+    assert_eq!(account.put_data_watermarked(1000), Ok(false));
+  }
+
+  #[test]
+  fn on_watermark() {
+    let crossings = Arc::new(Mutex::new(Vec::new()));
+    let mut db = MaidManagerDatabase::with_default_allowance(10000);
+    let recorded = crossings.clone();
+    db.set_on_watermark(Box::new(move |name, account| {
+      recorded.lock().unwrap().push((name.clone(), account.get_data_stored()));
+    }));
+    let name = NameType([1u8; 64]);
+    create(&mut db, &name);
+    db.storage.get_mut(&name).unwrap().set_watermark(0.9);
+    for _ in 0..10 {
+      assert_eq!(db.put_data(&name, 950), Ok(()));
+    }
+    assert_eq!(*crossings.lock().unwrap(), vec![(name.clone(), 9500)]);
+    let _ = db.delete_data(&name, 2000);
+    assert_eq!(db.put_data_batch(&[(name.clone(), 1000), (name.clone(), 1000)]), vec![true, true]);
+    assert_eq!(*crossings.lock().unwrap(), vec![(name.clone(), 9500), (name.clone(), 9500)]);
+  }
+
+  #[test]
+  fn idle_since() {
+    let mut account = MaidManagerAccount::new();
+    assert_eq!(account.put_data(1024), Ok(()));
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    let idle = account.idle_since();
+    assert!(idle >= ::std::time::Duration::from_millis(20));
+
+    assert_eq!(account.put_data(1024), Ok(()));
+    assert!(account.idle_since() < idle);
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    account.delete_data(1024);
+    assert!(account.idle_since() < ::std::time::Duration::from_millis(20));
+
+    // a rejected put leaves it alone
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::NotEnoughSpace));
+    assert!(account.idle_since() >= ::std::time::Duration::from_millis(20));
+  }
+
+  #[test]
+  fn get_account() {
+    let mut db = MaidManagerDatabase::new();
+    let name = routing::test_utils::Random::generate_random();
+    assert_eq!(db.get_account(&name), None);
+    assert_eq!(db.data_stored(&name), None);
+    assert_eq!(db.space_available(&name), None);
+    assert_eq!(db.exist(&name), false);
+
+    create(&mut db, &name);
+    assert_eq!(db.put_data(&name, 1024), Ok(()));
+    let account = db.get_account(&name).unwrap();
+    assert_eq!(account.get_data_stored(), 1024);
+    assert_eq!(db.data_stored(&name), Some(1024));
+    assert_eq!(db.space_available(&name), Some(1073741824 - 1024));
+  }
+
+  #[test]
+  fn account_overflow() {
+    let mut account = MaidManagerAccount::new();
+    let total = account.get_data_stored() + account.get_available_space();
+    assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.get_data_stored(), 0);
+    assert_eq!(account.get_available_space(), total);
+
+    assert_eq!(account.put_data(1024), Ok(()));
+    assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::NotEnoughSpace));
+    assert_eq!(account.get_data_stored(), 1024);
+    assert_eq!(account.get_data_stored() + account.get_available_space(), total);
+
+    account.delete_data(::std::u64::MAX);
+    assert_eq!(account.get_data_stored(), 0);
+    assert_eq!(account.get_available_space(), total);
+
+    assert_eq!(account.put_data(total), Ok(()));
+    assert_eq!(account.get_available_space(), 0);
+    account.delete_data(total + 1);
+    assert_eq!(account.get_data_stored(), 0);
+    assert_eq!(account.get_available_space(), total);
+  }
+
+  #[test]
+  fn retrieve_all_and_reset() {
+    use routing::sendable::Sendable;
+    let mut db = MaidManagerDatabase::new();
+    let name_1: NameType = routing::test_utils::Random::generate_random();
+    let name_2: NameType = routing::test_utils::Random::generate_random();
+    let name_3: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    create(&mut db, &name_3);
+    assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+    assert_eq!(db.put_data(&name_2, 2048), Ok(()));
+    assert_eq!(db.put_data(&name_3, 4096), Ok(()));
+
+    let sendable = db.retrieve_all_and_reset(&vec![name_1.clone(), name_3.clone()]);
+    assert_eq!(sendable.len(), 2);
+    assert!(sendable.iter().all(|a| a.name() == name_1 || a.name() == name_3));
+    assert_eq!(db.exist(&name_1), false);
+    assert_eq!(db.exist(&name_2), true);
+    assert_eq!(db.exist(&name_3), false);
+    assert_eq!(db.data_stored(&name_2), Some(2048));
+
+    assert!(db.retrieve_all_and_reset(&vec![name_1.clone()]).is_empty());
+    let sendable = db.retrieve_all_and_reset(&vec![name_2.clone()]);
+    assert_eq!(sendable.len(), 1);
+    assert_eq!(sendable[0].type_tag(), ::maid_manager::MAID_MANAGER_ACCOUNT_TAG);
+    assert_eq!(db.exist(&name_2), false);
+  }
+
+  #[test]
+  fn retrieve_all_and_reset_chunked() {
+    let mut db = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (0..2500).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter() {
+      create(&mut db, name);
+    }
+    let out_of_group: Vec<NameType> = (0..10).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in out_of_group.iter() {
+      create(&mut db, name);
+    }
+
+    let sizes: Vec<usize> = db.retrieve_all_and_reset_chunked(&names, 1000).map(|batch| batch.len()).collect();
+    assert_eq!(sizes, vec![1000, 1000, 500]);
+    assert_eq!(db.stats().account_count, out_of_group.len());
+    assert!(out_of_group.iter().all(|name| db.exist(name)));
+
+    // batches which aren't consumed stay in the database
+    let group: Vec<NameType> = out_of_group[..5].to_vec();
+    assert_eq!(db.retrieve_all_and_reset_chunked(&group, 2).next().map(|batch| batch.len()), Some(2));
+    assert_eq!(db.stats().account_count, out_of_group.len() - 2);
+  }
+
+  #[test]
+  fn load_transfers() {
+    use routing::sendable::Sendable;
+    let mut sender = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (1..5u8).map(|i| NameType([i; 64])).collect();
+    for name in names.iter() {
+      create(&mut sender, name);
+      assert_eq!(sender.put_data(name, 2048), Ok(()));
+    }
+    let mut transfers: Vec<(NameType, Vec<u8>)> = sender.retrieve_all_and_reset(&names).iter()
+        .map(|element| (element.name(), element.serialised_contents()))
+        .collect();
+    transfers.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+    // one truncated, one not cbor at all
+    let length = transfers[1].1.len();
+    transfers[1].1.truncate(length / 2);
+    transfers[2].1 = vec![0xff; 8];
+
+    let mut receiver = MaidManagerDatabase::new();
+    create(&mut receiver, &names[3]);
+    assert_eq!(receiver.put_data(&names[3], 1024), Ok(()));
+    let replayed = transfers[3].clone();
+    assert_eq!(receiver.load_transfers(transfers), 2);
+    assert_eq!(receiver.data_stored(&names[0]), Some(2048));
+    assert_eq!(receiver.exist(&names[1]), false);
+    assert_eq!(receiver.exist(&names[2]), false);
+    // replaces the account already held, which records less data stored, as handle_account_transfer
+    assert_eq!(receiver.data_stored(&names[3]), Some(2048));
+    assert_eq!(receiver.get_account(&names[3]).unwrap().total_capacity(), 1073741824);
+    // a transfer applied twice has no further effect
+    assert_eq!(receiver.load_transfers(vec![replayed]), 1);
+    assert_eq!(receiver.data_stored(&names[3]), Some(2048));
+    assert_eq!(receiver.total_stored(), 4096);
+    assert_eq!(receiver.load_transfers(Vec::new()), 0);
+  }
+
+  #[test]
+  fn compressed_transfer() {
+    let mut db = MaidManagerDatabase::new();
+    let mut copy = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (0..1000).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for (i, name) in names.iter().enumerate() {
+      create(&mut db, name);
+      create(&mut copy, name);
+      assert_eq!(db.put_data(name, 1024 * (i as u64 % 8)), Ok(()));
+      assert_eq!(copy.put_data(name, 1024 * (i as u64 % 8)), Ok(()));
+    }
+    let out_of_group: NameType = routing::test_utils::Random::generate_random();
+    create(&mut db, &out_of_group);
+
+    // each element also carries a 64 byte name and an 8 byte type tag
+    let blobs_size = copy.retrieve_all_and_reset(&names).iter()
+                                              .fold(0, |total, element| total + 64 + 8 + element.serialised_contents().len());
+    let compressed = db.retrieve_all_compressed(&names).unwrap();
+    assert!(compressed.len() < blobs_size);
+    assert_eq!(db.stats().account_count, 1);
+    assert_eq!(db.exist(&out_of_group), true);
+
+    let mut receiver = MaidManagerDatabase::new();
+    assert_eq!(receiver.ingest_compressed(&compressed).unwrap(), names.len());
+    assert_eq!(receiver.stats().account_count, names.len());
+    for (i, name) in names.iter().enumerate() {
+      assert_eq!(receiver.data_stored(name), Some(1024 * (i as u64 % 8)));
+    }
+
+    // a count which doesn't match the accounts, or a truncated blob, is rejected
+    let mut corrupted = compressed.clone();
+    corrupted[7] ^= 1;
+    assert_eq!(receiver.ingest_compressed(&corrupted).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+    assert!(receiver.ingest_compressed(&compressed[..compressed.len() / 2]).is_err());
+    assert!(receiver.ingest_compressed(&compressed[..4]).is_err());
+  }
+
+  #[test]
+  fn handle_account_transfer() {
+    use routing::sendable::Sendable;
+    let mut sender = MaidManagerDatabase::new();
+    let mut receiver = MaidManagerDatabase::new();
+    let name_1: NameType = routing::test_utils::Random::generate_random();
+    let name_2: NameType = routing::test_utils::Random::generate_random();
+    let name_3: NameType = routing::test_utils::Random::generate_random();
+    for name in vec![&name_1, &name_2, &name_3] {
+      create(&mut sender, name);
+    }
+    create(&mut receiver, &name_2);
+    create(&mut receiver, &name_3);
+    assert_eq!(sender.put_data(&name_1, 1024), Ok(()));
+    assert_eq!(sender.put_data(&name_2, 4096), Ok(()));
+    assert_eq!(sender.put_data(&name_3, 1024), Ok(()));
+    assert_eq!(receiver.put_data(&name_2, 2048), Ok(()));
+    assert_eq!(receiver.put_data(&name_3, 8192), Ok(()));
+
+    let close_group = vec![name_1.clone(), name_2.clone(), name_3.clone()];
+    for element in sender.retrieve_all_and_reset(&close_group) {
+        assert_eq!(receiver.handle_account_transfer(element.name(), &element.serialised_contents()), Ok(()));
+    }
+    // new account inserted
+    assert_eq!(receiver.data_stored(&name_1), Some(1024));
+    assert_eq!(receiver.space_available(&name_1), Some(1073741824 - 1024));
+    // incoming account has more data stored
+    assert_eq!(receiver.data_stored(&name_2), Some(4096));
+    assert_eq!(receiver.space_available(&name_2), Some(1073741824 - 4096));
+    // held account has more data stored
+    assert_eq!(receiver.data_stored(&name_3), Some(8192));
+    assert_eq!(receiver.space_available(&name_3), Some(1073741824 - 8192));
+
+    let name_4: NameType = routing::test_utils::Random::generate_random();
+    match receiver.handle_account_transfer(name_4.clone(), &[0u8, 1, 2, 3]) {
+      Err(DecodeError::InvalidAccount(_)) => (),
+      result => panic!("Unexpected {:?}", result),
+    }
+    assert_eq!(receiver.handle_account_transfer(name_4.clone(), &[]), Err(DecodeError::Empty));
+    assert_eq!(receiver.exist(&name_4), false);
+  }
+
+  #[test]
+  fn eviction() {
+    let mut db = MaidManagerDatabase::with_capacity(3, ::std::time::Duration::from_secs(3600));
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter().take(3) {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, 1024), Ok(()));
+    }
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    create(&mut db, &names[3]);
+    assert_eq!(db.put_data(&names[3], 1024), Ok(()));
+    // names[1] is now the least recently used
+    assert_eq!(db.exist(&names[0]), true);
+    assert_eq!(db.exist(&names[1]), false);
+    assert_eq!(db.exist(&names[2]), true);
+    assert_eq!(db.exist(&names[3]), true);
+    assert_eq!(db.data_stored(&names[0]), Some(2048));
+    db.delete_data(&names[0], 1024);
+    assert_eq!(db.data_stored(&names[0]), Some(1024));
+
+    let mut db = MaidManagerDatabase::with_capacity(10, ::std::time::Duration::from_millis(10));
+    create(&mut db, &names[0]);
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    assert_eq!(db.exist(&names[0]), false);
+  }
+
+  #[test]
+  fn eviction_accounting() {
+    use std::sync::{Arc, Mutex};
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    let removed: Arc<Mutex<Vec<(NameType, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut db = MaidManagerDatabase::with_capacity(3, ::std::time::Duration::from_secs(3600));
+    {
+        let removed = removed.clone();
+        db.set_on_remove(Box::new(move |name: &NameType, account: &MaidManagerAccount| {
+            removed.lock().unwrap().push((name.clone(), account.get_data_stored()));
+        }));
+    }
+    for (i, name) in names.iter().take(3).enumerate() {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+    }
+    assert_eq!(db.total_stored(), 6144);
+    assert_eq!(db.metrics().evictions, 0);
+
+    // names[0] is the least recently used, and goes to make room
+    create(&mut db, &names[3]);
+    assert_eq!(db.put_data(&names[3], 4096), Ok(()));
+    assert_eq!(db.exist(&names[0]), false);
+    assert_eq!(*removed.lock().unwrap(), vec![(names[0].clone(), 1024)]);
+    assert_eq!(db.metrics().evictions, 1);
+    assert_eq!(db.total_stored(), 2048 + 3072 + 4096);
+    let total = db.total_stored();
+    db.recompute();
+    assert_eq!(db.total_stored(), total);
+
+    // the totals are kept without a callback too
+    let mut db = MaidManagerDatabase::with_capacity(1, ::std::time::Duration::from_secs(3600));
+    create(&mut db, &names[0]);
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    create(&mut db, &names[1]);
+    assert_eq!(db.total_stored(), 0);
+    assert_eq!(db.metrics().evictions, 1);
+  }
+
+  #[test]
+  fn iter() {
+    let mut db = MaidManagerDatabase::new();
+    assert_eq!(db.iter().count(), 0);
+    let names: Vec<NameType> = (0..5).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for (i, name) in names.iter().enumerate() {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, 1024 * i as u64), Ok(()));
+    }
+    assert_eq!(db.put_data(&names[0], 1), Ok(()));
+
+    let mut seen: Vec<NameType> = Vec::new();
+    for (name, account) in db.iter() {
+      let i = names.iter().position(|a| a == name).unwrap();
+      let expected = if i == 0 { 1 } else { 1024 * i as u64 };
+      assert_eq!(account.get_data_stored(), expected);
+      assert!(!seen.contains(name));
+      seen.push(name.clone());
+    }
+    assert_eq!(seen.len(), names.len());
+
+    let mut identities = db.identities();
+    identities.sort();
+    seen.sort();
+    assert_eq!(identities, seen);
+  }
+
+  #[test]
+  fn accounts_within() {
+    let mut db = MaidManagerDatabase::new();
+    for &byte in [0x01u8, 0x02, 0x04, 0x08, 0x10].iter() {
+      let name = NameType([byte; 64]);
+      create(&mut db, &name);
+      assert_eq!(db.put_data(&name, byte as u64 * 1024), Ok(()));
+    }
+    // XOR distances from the target are 0x02, 0x01, 0x07, 0x0b and 0x13 in each byte
+    let target = NameType([0x03u8; 64]);
+    assert_eq!(db.accounts_within(&target, 3),
+               vec![(NameType([0x02u8; 64]), 2048), (NameType([0x01u8; 64]), 1024), (NameType([0x04u8; 64]), 4096)]);
+    let all: Vec<NameType> = db.accounts_within(&target, 10).into_iter().map(|(name, _)| name).collect();
+    assert_eq!(all, [0x02u8, 0x01, 0x04, 0x08, 0x10].iter().map(|&byte| NameType([byte; 64])).collect::<Vec<_>>());
+    // an account at the target itself is the closest
+    assert_eq!(db.accounts_within(&NameType([0x10u8; 64]), 1), vec![(NameType([0x10u8; 64]), 16384)]);
+    assert!(db.accounts_within(&target, 0).is_empty());
+    assert!(MaidManagerDatabase::new().accounts_within(&target, 3).is_empty());
+
+    // over many names the order agrees with closer_to_target throughout
+    for _ in 0..200 {
+      let name: NameType = routing::test_utils::Random::generate_random();
+      create(&mut db, &name);
+    }
+    let target: NameType = routing::test_utils::Random::generate_random();
+    let nearest: Vec<NameType> = db.accounts_within(&target, 100).into_iter().map(|(name, _)| name).collect();
+    assert_eq!(nearest.len(), 100);
+    assert!(nearest.windows(2).all(|pair| routing::closer_to_target(&pair[0], &pair[1], &target)));
+  }
+
+  #[test]
+  fn compact() {
+    let mut db = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (0..10).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter() {
+      create(&mut db, name);
+      let account = db.storage.get_mut(name).unwrap();
+      let ids: Vec<ReservationId> = (0..8).map(|_| account.reserve(1024).unwrap()).collect();
+      for id in ids[1..].iter() {
+        assert!(account.cancel(*id));
+      }
+    }
+    for name in names[5..].iter() {
+      let _ = db.remove_account(name);
+    }
+    let before = db.estimated_memory_bytes();
+    let reclaimed = db.compact();
+    assert!(reclaimed > 0);
+    assert_eq!(db.estimated_memory_bytes(), before - reclaimed);
+    assert_eq!(db.compact(), 0);
+
+    // nothing held is changed
+    assert_eq!(db.storage.len(), 5);
+    for name in names[..5].iter() {
+      assert_eq!(db.get_account(name).unwrap().get_reserved_space(), 1024);
+    }
+  }
+
+  #[test]
+  fn estimated_memory_bytes() {
+    let mut db = MaidManagerDatabase::new();
+    let empty = db.estimated_memory_bytes();
+    assert!(empty > 0);
+    let names: Vec<NameType> = (0..20).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names[..10].iter() {
+      create(&mut db, name);
+    }
+    let ten = db.estimated_memory_bytes();
+    for name in names[10..].iter() {
+      create(&mut db, name);
+    }
+    let twenty = db.estimated_memory_bytes();
+    assert!(ten > empty);
+    assert_eq!(twenty - ten, ten - empty);
+
+    // reservations add to the account's share
+    let _ = db.storage.get_mut(&names[0]).unwrap().reserve(1024).unwrap();
+    assert!(db.estimated_memory_bytes() > twenty);
+  }
+
+  #[test]
+  fn stats() {
+    let mut db = MaidManagerDatabase::new();
+    assert_eq!(db.stats(), MaidManagerStats { account_count: 0, total_data_stored: 0, total_space_available: 0 });
+    let names: Vec<NameType> = (0..3).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter() {
+      create(&mut db, name);
+    }
+    assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+    assert_eq!(db.put_data(&names[1], 2048), Ok(()));
+    assert_eq!(db.put_data(&names[2], 4096), Ok(()));
+    db.delete_data(&names[1], 1024);
+    db.delete_data(&names[2], 8192);
+
+    let stats = db.stats();
+    assert_eq!(stats.account_count, 3);
+    assert_eq!(stats.total_data_stored, 1024 + 1024);
+    assert_eq!(stats.total_space_available, 3 * 1073741824 - 2048);
+    assert_eq!(db.stats(), stats);
+  }
+
+  #[test]
+  fn save_and_load() {
+    let path = ::std::env::temp_dir().join(format!("maid_manager_{}.db", rand::random::<u64>()));
+    let loaded = MaidManagerDatabase::load_from_file(&path).unwrap();
+    assert!(loaded.storage.is_empty());
+
+    let mut db = MaidManagerDatabase::new();
+    let mut names: Vec<NameType> = Vec::new();
+    for i in 0..10 {
+      let name: NameType = routing::test_utils::Random::generate_random();
+      create(&mut db, &name);
+      assert_eq!(db.put_data(&name, 1024 * i), Ok(()));
+      names.push(name);
+    }
+    db.save_to_file(&path).unwrap();
+
+    let loaded = MaidManagerDatabase::load_from_file(&path).unwrap();
+    assert_eq!(loaded.storage.len(), names.len());
+    for (i, name) in names.iter().enumerate() {
+      assert_eq!(loaded.data_stored(name), Some(1024 * i as u64));
+      assert_eq!(loaded.space_available(name), Some(1073741824 - 1024 * i as u64));
+    }
+    ::std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn export_csv() {
+    let mut db = MaidManagerDatabase::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    db.export_csv(&mut buffer).unwrap();
+    assert_eq!(String::from_utf8(buffer).unwrap(), "name,data_stored,space_available\n");
+
+    let name_1 = NameType([1u8; 64]);
+    let name_2 = NameType([0xabu8; 64]);
+    create(&mut db, &name_1);
+    create(&mut db, &name_2);
+    assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+    assert_eq!(db.put_data(&name_2, 4096), Ok(()));
+
+    let mut buffer: Vec<u8> = Vec::new();
+    db.export_csv(&mut buffer).unwrap();
+    let exported = String::from_utf8(buffer).unwrap();
+    let mut lines: Vec<&str> = exported.lines().collect();
+    assert_eq!(lines.remove(0), "name,data_stored,space_available");
+    lines.sort();
+    let hex_1: String = (0..64).map(|_| "01").collect();
+    let hex_2: String = (0..64).map(|_| "ab").collect();
+    assert_eq!(lines, vec![format!("{},1024,{}", hex_1, 1073741824 - 1024),
+                           format!("{},4096,{}", hex_2, 1073741824 - 4096)]);
+    assert_eq!(db.stats().account_count, 2);
+  }
+
+  #[test]
+  fn json() {
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.put_data(1024), Ok(()));
+    let _ = account.reserve(512).unwrap();
+    account.last_modified = ::std::time::UNIX_EPOCH + ::std::time::Duration::new(1437000000, 5);
+    let serialised = account.to_json();
+    assert_eq!(serialised,
+               "{\"data_stored\":1024,\"space_available\":2560,\"reservations\":[[0,512]],\"next_reservation\":1,\
+                \"last_modified\":[1437000000,5]}");
+    assert_eq!(MaidManagerAccount::from_json(&serialised).unwrap(), account);
+    assert!(MaidManagerAccount::from_json("{\"data_stored\":1024}").is_err());
+    assert!(MaidManagerAccount::from_json("not json").is_err());
+  }
+
+  #[cfg(feature = "use-bincode")]
+  #[test]
+  fn bincode() {
+    let mut account = MaidManagerAccount::new();
+    assert_eq!(account.put_data(1024), Ok(()));
+    let _ = account.reserve(512).unwrap();
+    let serialised = account.to_bincode();
+    assert_eq!(MaidManagerAccount::from_bincode(&serialised).unwrap(), account);
+    assert!(MaidManagerAccount::from_bincode(&serialised[1..]).is_err());
+
+    // fixed width integers and a length prefixed list of reservations, while cbor also carries the
+    // field names
+    let default_account = MaidManagerAccount::new();
+    let mut e = cbor::Encoder::from_memory();
+    e.encode(&[&default_account]).unwrap();
+    assert_eq!(default_account.to_bincode().len(), 44);
+    assert!(default_account.to_bincode().len() < e.as_bytes().len());
+
+    let mut sender = MaidManagerDatabase::new();
+    let mut receiver = MaidManagerDatabase::new();
+    let name: NameType = routing::test_utils::Random::generate_random();
+    create(&mut sender, &name);
+    assert_eq!(sender.put_data(&name, 1024), Ok(()));
+    for element in sender.retrieve_all_and_reset_bincode(&vec![name.clone()]) {
+      assert_eq!(element.type_tag(), ::maid_manager::MAID_MANAGER_ACCOUNT_TAG);
+      assert_eq!(receiver.handle_account_transfer_bincode(element.name(), &element.serialised_contents()), Ok(()));
+    }
+    assert!(receiver.handle_account_transfer_bincode(name.clone(), &[0u8]).is_err());
+    assert_eq!(sender.exist(&name), false);
+    assert_eq!(sender.metrics().last_transfer_accounts, 1);
+    assert_eq!(sender.metrics().last_transfer_bytes, 44);
+    assert_eq!(receiver.data_stored(&name), Some(1024));
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn serde() {
+    let mut account = MaidManagerAccount::new();
+    assert_eq!(account.put_data(1024), Ok(()));
+    let _ = account.reserve(512).unwrap();
+    account.last_modified = ::std::time::UNIX_EPOCH + ::std::time::Duration::new(1437000000, 5);
+    let serialised = account.to_serde_cbor();
+    assert_eq!(MaidManagerAccount::from_serde_cbor(&serialised).unwrap(), account);
+    assert!(MaidManagerAccount::from_serde_cbor(&serialised[1..]).is_err());
+
+    let mut e = cbor::Encoder::from_memory();
+    e.encode(&[&account]).unwrap();
+    assert_eq!(serialised, e.as_bytes());
+    let mut d = cbor::Decoder::from_bytes(&serialised[..]);
+    assert_eq!(d.decode::<MaidManagerAccount>().next().unwrap().unwrap(), account);
+  }
+
+  #[test]
+  fn proto_bytes() {
+    let fields = |account: &MaidManagerAccount| (account.get_data_stored(), account.get_available_space());
+    let account = MaidManagerAccount::from_parts(1024, 3072).unwrap();
+    // 1024 and 3072 are the varints 0x80 0x08 and 0x80 0x18
+    assert_eq!(account.to_proto_bytes(), vec![0x06, 0x08, 0x80, 0x08, 0x10, 0x80, 0x18]);
+    assert_eq!(MaidManagerAccount::from_proto_bytes(&account.to_proto_bytes()).map(|decoded| fields(&decoded)),
+               Ok((1024, 3072)));
+    assert_eq!(MaidManagerAccount::empty().to_proto_bytes(), vec![0x00]);
+
+    for account in vec![MaidManagerAccount::new(), MaidManagerAccount::empty(),
+                        MaidManagerAccount::from_parts(::std::u64::MAX, 0).unwrap()] {
+      let decoded = MaidManagerAccount::from_proto_bytes(&account.to_proto_bytes()).unwrap();
+      assert_eq!(fields(&decoded), fields(&account));
+    }
+
+    let serialised = account.to_proto_bytes();
+    assert_eq!(MaidManagerAccount::from_proto_bytes(&[]), Err(DecodeError::Empty));
+    assert!(MaidManagerAccount::from_proto_bytes(&serialised[..serialised.len() - 1]).is_err());
+    assert!(MaidManagerAccount::from_proto_bytes(&serialised[1..]).is_err());
+    // both fields at u64::MAX can't be summed
+    let overflowing = [0x14, 0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+                       0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+    match MaidManagerAccount::from_proto_bytes(&overflowing) {
+      Err(DecodeError::InvalidAccount(_)) => (),
+      _ => panic!("an overflowing account was decoded"),
+    }
+  }
+
+  #[test]
+  fn recompute() {
+    let mut db = MaidManagerDatabase::new();
+    let names: Vec<NameType> = (0..3).map(|_| routing::test_utils::Random::generate_random()).collect();
+    for name in names.iter() {
+      create(&mut db, name);
+      assert_eq!(db.put_data(name, 1024), Ok(()));
+    }
+    assert_eq!(db.delete_data(&names[0], 512), 512);
+    assert_eq!(db.put_data_batch(&[(names[1].clone(), 256), (names[1].clone(), 256)]), vec![true, true]);
+    let _ = db.remove_account(&names[2]);
+    assert_eq!(db.total_stored(), 512 + 1536);
+
+    db.total_stored = 1;
+    db.recompute();
+    assert_eq!(db.total_stored(), 512 + 1536);
+    assert_eq!(db.total_stored(), db.data_stored(&names[0]).unwrap() + db.data_stored(&names[1]).unwrap());
+  }
+
+  #[test]
+  fn display() {
+    let account = MaidManagerAccount::from_parts(1073741824, 3221225472).unwrap();
+    assert_eq!(account.to_string(), "1.00 GiB stored of 4.00 GiB (25.0%), 3.00 GiB available");
+    let mut account = MaidManagerAccount::with_allowance(4096);
+    assert_eq!(account.put_data(1536), Ok(()));
+    let _ = account.reserve(512).unwrap();
+    assert_eq!(account.to_string(), "1.50 KiB stored of 4.00 KiB (37.5%), 2.00 KiB available, 512 B reserved");
+    // an account with no allowance is full, as utilization and the pmid account's Display have it
+    assert_eq!(MaidManagerAccount::empty().to_string(), "0 B stored of 0 B (100.0%), 0 B available");
+    assert_eq!(MaidManagerAccount::with_allowance(0).utilization(), 1.0);
+    assert_eq!(MaidManagerAccount::with_allowance(0).to_string(), "0 B stored of 0 B (100.0%), 0 B available");
+  }
+
+  #[test]
+  fn with_allowance_size() {
+    let account = MaidManagerAccount::with_allowance_size("512MiB".parse().unwrap());
+    assert_eq!(account.get_available_space(), 536870912);
+    assert!(account.semantically_eq(&MaidManagerAccount::with_allowance(536870912)));
+  }
+
+  #[test]
+  fn from_parts() {
+    let account = MaidManagerAccount::from_parts(1024, 3072).unwrap();
+    assert_eq!(account.get_data_stored(), 1024);
+    assert_eq!(account.get_available_space(), 3072);
+    assert_eq!(account.total_capacity(), 4096);
+    assert_eq!(account.get_reserved_space(), 0);
+    assert_eq!(account.can_put(3072), true);
+    assert_eq!(account.can_put(3073), false);
+
+    let full = MaidManagerAccount::from_parts(::std::u64::MAX, 0).unwrap();
+    assert_eq!(full.get_data_stored(), ::std::u64::MAX);
+    assert_eq!(MaidManagerAccount::from_parts(::std::u64::MAX, 1), Err(AccountError::Overflow));
+    assert_eq!(MaidManagerAccount::from_parts(0, 0).unwrap().semantically_eq(&MaidManagerAccount::empty()), true);
+  }
+
+  #[test]
+  fn empty_account() {
+    let mut account = MaidManagerAccount::empty();
+    assert_eq!(account.get_available_space(), 0);
+    assert_eq!(account.can_put(1), false);
+    for &size in [1, 1024, ::std::u64::MAX].iter() {
+      assert_eq!(account.put_data(size), Err(PutError::NotEnoughSpace));
+    }
+    assert_eq!(account.put_data(0), Ok(()));
+    assert_eq!(account.delete_data(1024), 0);
+    assert_eq!(account.get_data_stored(), 0);
+
+    let mut e = cbor::Encoder::from_memory();
+    e.encode(&[&account]).unwrap();
+    let mut d = cbor::Decoder::from_bytes(e.into_bytes());
+    let decoded: MaidManagerAccount = d.decode().next().unwrap().unwrap();
+    assert_eq!(decoded, account);
+    assert_eq!(decoded.get_available_space(), 0);
   }
 
   #[test]
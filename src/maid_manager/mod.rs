@@ -18,17 +18,139 @@
 #![allow(dead_code)]
 
 mod database;
+mod op_log;
 
 use routing::generic_sendable_type;
+use cbor;
 use cbor::{ Decoder };
 use routing;
 use routing::NameType;
 use maidsafe_types;
 use routing::sendable::Sendable;
-pub use self::database::MaidManagerAccount;
+pub use self::database::{AccountCreation, MaidManagerAccount, MaidManagerDatabase, ReservationId};
+pub use self::op_log::{OpLog, OpLogEntry};
+use flate2::Crc;
+use std::fmt;
 
 type Address = NameType;
 
+/// Type tag of the serialised MaidManagerAccount sent on during churn.
+pub const MAID_MANAGER_ACCOUNT_TAG: u64 = 1000;
+
+/// Version byte prepended to a serialised MaidManagerAccount by encode_versioned.
+pub const MAID_MANAGER_ACCOUNT_VERSION: u8 = 2;
+
+// version 1 accounts predate last_modified and are still accepted by decode_versioned
+const MAID_MANAGER_ACCOUNT_VERSION_V1: u8 = 1;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum CreateError {
+  AlreadyExists,
+  AllowanceTooLarge { requested: u64, max: u64 },
+  // the name is reserved, see MaidManagerDatabase::with_name_validation
+  InvalidName,
+  // the database already holds as many accounts as it may, see MaidManagerDatabase::with_max_accounts
+  AtCapacity,
+  // the name was removed by MaidManagerDatabase::soft_delete and its tombstone not yet purged
+  Deleted,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PutError {
+  AccountNotFound,
+  NotEnoughSpace,
+  // the account's rate limit has no puts left for now, see MaidManagerAccount::set_rate_limit
+  RateLimited,
+  // as CreateError::InvalidName
+  InvalidName,
+  // as CreateError::Deleted
+  Deleted,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum AccountError {
+  // data_stored, space_available and the reserved space don't fit in a u64 when summed
+  Overflow,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DecodeError {
+  Empty,
+  UnknownVersion(u8),
+  InvalidAccount(String),
+  // the trailing checksum added by encode_with_checksum doesn't match the bytes before it
+  ChecksumMismatch,
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DecodeError::Empty => write!(formatter, "no version byte in serialised MaidManagerAccount"),
+      DecodeError::UnknownVersion(version) =>
+          write!(formatter, "unknown MaidManagerAccount version {} (expected {})", version, MAID_MANAGER_ACCOUNT_VERSION),
+      DecodeError::InvalidAccount(ref error) => write!(formatter, "invalid MaidManagerAccount: {}", error),
+      DecodeError::ChecksumMismatch => write!(formatter, "checksum mismatch in serialised MaidManagerAccount"),
+    }
+  }
+}
+
+// the cbor encoding of account, preceded by MAID_MANAGER_ACCOUNT_VERSION
+pub fn encode_versioned(account: &MaidManagerAccount) -> Vec<u8> {
+  let mut e = cbor::Encoder::from_memory();
+  e.encode(&[account]).unwrap();
+  let mut serialised = vec![MAID_MANAGER_ACCOUNT_VERSION];
+  serialised.extend(e.as_bytes().iter().cloned());
+  serialised
+}
+
+pub fn decode_versioned(serialised: &[u8]) -> Result<MaidManagerAccount, DecodeError> {
+  match serialised.first() {
+    None => Err(DecodeError::Empty),
+    Some(&MAID_MANAGER_ACCOUNT_VERSION) => decode_account(&serialised[1..]),
+    Some(&MAID_MANAGER_ACCOUNT_VERSION_V1) =>
+        decode_account::<database::MaidManagerAccountV1>(&serialised[1..]).map(MaidManagerAccount::from),
+    Some(&version) => Err(DecodeError::UnknownVersion(version)),
+  }
+}
+
+fn decode_account<T: ::rustc_serialize::Decodable>(serialised: &[u8]) -> Result<T, DecodeError> {
+  let mut d = cbor::Decoder::from_bytes(serialised);
+  match d.decode().next() {
+    Some(Ok(account)) => Ok(account),
+    Some(Err(error)) => Err(DecodeError::InvalidAccount(error.to_string())),
+    None => Err(DecodeError::InvalidAccount("no account after version byte".to_string())),
+  }
+}
+
+// encode_versioned followed by the big endian CRC32 of everything before it
+pub fn encode_with_checksum(account: &MaidManagerAccount) -> Vec<u8> {
+  let mut serialised = encode_versioned(account);
+  let checksum = crc32(&serialised);
+  serialised.extend((0..4).map(|i| (checksum >> (24 - 8 * i)) as u8));
+  serialised
+}
+
+pub fn decode_with_checksum(serialised: &[u8]) -> Result<MaidManagerAccount, DecodeError> {
+  if serialised.is_empty() {
+    return Err(DecodeError::Empty);
+  }
+  if serialised.len() < 4 {
+    return Err(DecodeError::ChecksumMismatch);
+  }
+  let (payload, trailer) = serialised.split_at(serialised.len() - 4);
+  let checksum = trailer.iter().fold(0u32, |checksum, &byte| (checksum << 8) | byte as u32);
+  if crc32(payload) != checksum {
+    return Err(DecodeError::ChecksumMismatch);
+  }
+  decode_versioned(payload)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = Crc::new();
+  crc.update(bytes);
+  crc.sum()
+}
+
 pub struct MaidManager {
   db_ : database::MaidManagerDatabase
 }
@@ -46,7 +168,16 @@ impl MaidManager {
       maidsafe_types::PayloadTypeTag::ImmutableData => {
         let immutable_data : maidsafe_types::ImmutableData = payload.get_data();
         let data_name = routing::types::array_as_vector(&immutable_data.name().get_id());
-        if !self.db_.put_data(from, immutable_data.get_value().len() as u64) {
+        // routing has no message by which a client asks for an account, so a client's first put is
+        // the only request which can open one; it goes through create_account with the default
+        // allowance, so a name the policy refuses (reserved or deleted) still gets no account
+        if !self.db_.exist(from) {
+          let allowance = self.db_.default_allowance();
+          if self.db_.create_account(from.clone(), AccountCreation::new(allowance)).is_err() {
+            return Err(routing::RoutingError::InvalidRequest);
+          }
+        }
+        if self.db_.put_data(from, immutable_data.get_value().len() as u64).is_err() {
           return Err(routing::RoutingError::InvalidRequest);
         }
         destinations.push(NameType::new(immutable_data.name().get_id()));
@@ -64,8 +195,8 @@ impl MaidManager {
     Ok(routing::Action::SendOn(destinations))
   }
 
-  pub fn retrieve_all_and_reset(&mut self) -> Vec<generic_sendable_type::GenericSendableType> {
-    self.db_.retrieve_all_and_reset()
+  pub fn retrieve_all_and_reset(&mut self, close_group: &Vec<NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+    self.db_.retrieve_all_and_reset(close_group)
   }
 
 }
@@ -101,5 +232,73 @@ mod test {
             }
             routing::Action::Reply(x) => panic!("Unexpected"),
         }
+        assert_eq!(maid_manager.db_.data_stored(&from), Some(1024));
+
+        // the account is opened through create_account, so a client whose account was deleted isn't
+        // given another
+        let deleted: NameType = routing::test_utils::Random::generate_random();
+        maid_manager.db_.soft_delete(&deleted);
+        assert!(maid_manager.handle_put(&deleted, &array_as_vector(encoder.as_bytes())).is_err());
+        assert_eq!(maid_manager.db_.exist(&deleted), false);
+    }
+
+    #[test]
+    fn versioned_serialisation() {
+        let mut account = MaidManagerAccount::new();
+        assert_eq!(account.put_data(1024), Ok(()));
+        let serialised = encode_versioned(&account);
+        assert_eq!(serialised[0], MAID_MANAGER_ACCOUNT_VERSION);
+        assert_eq!(decode_versioned(&serialised), Ok(account));
+
+        let mut bumped = serialised.clone();
+        bumped[0] = MAID_MANAGER_ACCOUNT_VERSION + 1;
+        let error = decode_versioned(&bumped).unwrap_err();
+        assert_eq!(error, DecodeError::UnknownVersion(MAID_MANAGER_ACCOUNT_VERSION + 1));
+        assert_eq!(error.to_string(), "unknown MaidManagerAccount version 3 (expected 2)");
+
+        assert_eq!(decode_versioned(&[]), Err(DecodeError::Empty));
+        match decode_versioned(&serialised[..serialised.len() - 1]) {
+            Err(DecodeError::InvalidAccount(_)) => (),
+            result => panic!("Unexpected {:?}", result),
+        }
+    }
+
+    #[derive(RustcEncodable)]
+    struct AccountV1 {
+        data_stored: u64,
+        space_available: u64,
+        reservations: Vec<(u64, u64)>,
+        next_reservation: u64,
+    }
+
+    #[test]
+    fn versioned_serialisation_v1() {
+        let legacy = AccountV1 { data_stored: 1024, space_available: 2048, reservations: vec![], next_reservation: 0 };
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&legacy]).unwrap();
+        let mut serialised = vec![1u8];
+        serialised.extend(e.as_bytes().iter().cloned());
+
+        let account = decode_versioned(&serialised).unwrap();
+        assert_eq!(account.get_data_stored(), 1024);
+        assert_eq!(account.get_available_space(), 2048);
+        assert!(account.idle_since() < ::std::time::Duration::new(60, 0));
+    }
+
+    #[test]
+    fn checksummed_serialisation() {
+        let mut account = MaidManagerAccount::new();
+        assert_eq!(account.put_data(1024), Ok(()));
+        let serialised = encode_with_checksum(&account);
+        assert_eq!(decode_with_checksum(&serialised), Ok(account));
+
+        let mut corrupted = serialised.clone();
+        corrupted[serialised.len() / 2] ^= 0x01;
+        assert_eq!(decode_with_checksum(&corrupted), Err(DecodeError::ChecksumMismatch));
+        let mut corrupted = serialised.clone();
+        let last = serialised.len() - 1;
+        corrupted[last] ^= 0x80;
+        assert_eq!(decode_with_checksum(&corrupted), Err(DecodeError::ChecksumMismatch));
+        assert_eq!(decode_with_checksum(&serialised[..2]), Err(DecodeError::ChecksumMismatch));
     }
 }
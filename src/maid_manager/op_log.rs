@@ -0,0 +1,325 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#![allow(dead_code)]
+
+use cbor;
+use routing::NameType;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use super::database::{AccountCreation, MaidManagerDatabase};
+
+#[derive(PartialEq, Eq, Debug, Clone, RustcEncodable, RustcDecodable)]
+pub enum OpLogEntry {
+  Create { name: NameType, allowance: u64 },
+  Put { name: NameType, size: u64 },
+  Delete { name: NameType, size: u64 },
+}
+
+// an append-only record of the operations applied to a MaidManagerDatabase since its last snapshot,
+// each entry cbor encoded one after another. Replaying applies the same operations in the same
+// order, so entries may be appended whether or not the operation succeeded.
+pub struct OpLog {
+  path: PathBuf,
+  file: fs::File,
+}
+
+impl OpLog {
+  // entries already in the log at path are kept and appended to; a partly written final entry, as
+  // left by a crash during append, is cut off first, since replay would stop at it and never reach
+  // the entries appended after. A log with an unreadable entry before others is corrupt rather than
+  // torn, and is refused with an InvalidData error, the file left as it is.
+  pub fn open(path: &Path) -> io::Result<OpLog> {
+    let mut file = try!(fs::OpenOptions::new().create(true).read(true).append(true).open(path));
+    let mut serialised = Vec::new();
+    let _ = try!(file.read_to_end(&mut serialised));
+    let (_, complete) = try!(decode_entries(&serialised));
+    if complete < serialised.len() {
+      try!(file.set_len(complete as u64));
+      try!(file.sync_all());
+    }
+    Ok(OpLog { path: path.to_path_buf(), file: file })
+  }
+
+  // the entry is synced to disk before returning
+  pub fn append(&mut self, entry: &OpLogEntry) -> io::Result<()> {
+    try!(self.file.write_all(&try!(encode(entry))));
+    self.file.sync_data()
+  }
+
+  // applies every entry in the log at path to database, which should be empty and configured as
+  // the one the log was written from, its allowances deciding which creations succeed. Returns the
+  // entries database rejected, as those rejected when first logged are again. A log which doesn't
+  // exist is empty, a partly written final entry, as left by a crash during append, is ignored, and
+  // a corrupt log is an InvalidData error with nothing applied.
+  pub fn replay(database: &mut MaidManagerDatabase, path: &Path) -> io::Result<Vec<OpLogEntry>> {
+    OpLog::apply_all(database, path)
+  }
+
+  // as replay, but starting from the snapshot last written by compact, which is restored into
+  // database first
+  pub fn recover(database: &mut MaidManagerDatabase, snapshot_path: &Path, path: &Path)
+          -> io::Result<Vec<OpLogEntry>> {
+    try!(database.restore_from_file(snapshot_path));
+    OpLog::apply_all(database, path)
+  }
+
+  // saves database, which must be the result of every entry logged so far, as the snapshot for
+  // recover and then empties the log. A crash after the snapshot but before the truncation only
+  // leaves entries which recover would apply a second time, so the log should be compacted soon
+  // after the snapshot is taken.
+  pub fn compact(&mut self, database: &MaidManagerDatabase, snapshot_path: &Path) -> io::Result<()> {
+    try!(database.save_to_file(snapshot_path));
+    try!(self.file.set_len(0));
+    self.file.sync_all()
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn apply_all(database: &mut MaidManagerDatabase, path: &Path) -> io::Result<Vec<OpLogEntry>> {
+    let mut serialised = Vec::new();
+    match fs::File::open(path) {
+      Ok(mut file) => { let _ = try!(file.read_to_end(&mut serialised)); }
+      Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error),
+    }
+    let (entries, _) = try!(decode_entries(&serialised));
+    Ok(entries.into_iter().filter(|entry| !apply(database, entry.clone())).collect())
+  }
+}
+
+fn encode(entry: &OpLogEntry) -> io::Result<Vec<u8>> {
+  let mut e = cbor::Encoder::from_memory();
+  match e.encode(&[entry]) {
+    Ok(()) => Ok(e.into_bytes()),
+    Err(error) => Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+  }
+}
+
+// the entries up to the first which can't be decoded, and the length in bytes of those entries;
+// the cbor decoder doesn't report how far it has read, but an entry encodes to the same bytes it
+// was decoded from. Whatever follows must be a torn final entry: shorter than the longest entry,
+// with no entry starting anywhere within it. Otherwise an entry was corrupted where it was written,
+// and stopping there would lose every entry after it, so the log is refused instead.
+fn decode_entries(serialised: &[u8]) -> io::Result<(Vec<OpLogEntry>, usize)> {
+  let mut entries = Vec::new();
+  let mut length = 0;
+  {
+    let mut d = cbor::Decoder::from_bytes(serialised);
+    for entry in d.decode::<OpLogEntry>() {
+      match entry {
+        Ok(entry) => {
+          length += try!(encode(&entry)).len();
+          entries.push(entry);
+        }
+        Err(_) => break,
+      }
+    }
+  }
+  if length < serialised.len() {
+    let torn = serialised.len() - length < try!(longest_entry()) &&
+               (length + 1..serialised.len()).all(|start| {
+                 match cbor::Decoder::from_bytes(&serialised[start..]).decode::<OpLogEntry>().next() {
+                   Some(Ok(_)) => false,
+                   _ => true,
+                 }
+               });
+    if !torn {
+      return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                format!("unreadable op log entry at byte {} of {}", length, serialised.len())));
+    }
+  }
+  Ok((entries, length))
+}
+
+// the encoded length of the longest entry there can be
+fn longest_entry() -> io::Result<usize> {
+  let name = NameType([0xff; 64]);
+  let value = ::std::u64::MAX;
+  let longest = [OpLogEntry::Create { name: name.clone(), allowance: value },
+                 OpLogEntry::Put { name: name.clone(), size: value },
+                 OpLogEntry::Delete { name: name, size: value }];
+  let mut length = 0;
+  for entry in longest.iter() {
+    length = ::std::cmp::max(length, try!(encode(entry)).len());
+  }
+  Ok(length)
+}
+
+// whether database accepted the entry; a delete is rejected only for an unknown account
+fn apply(database: &mut MaidManagerDatabase, entry: OpLogEntry) -> bool {
+  match entry {
+    OpLogEntry::Create { name, allowance } => database.create_account(name, AccountCreation::new(allowance)).is_ok(),
+    OpLogEntry::Put { name, size } => database.put_data(&name, size).is_ok(),
+    OpLogEntry::Delete { name, size } => {
+      if !database.exist(&name) {
+        return false;
+      }
+      let _ = database.delete_data(&name, size);
+      true
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use rand;
+  use routing;
+  use routing::NameType;
+  use std::fs;
+  use std::io;
+  use std::io::Write;
+  use super::{OpLog, OpLogEntry};
+  use super::super::database::MaidManagerDatabase;
+
+  fn database() -> MaidManagerDatabase {
+    let mut db = MaidManagerDatabase::with_default_allowance(4096);
+    db.set_max_allowance(2 * 1073741824);
+    db
+  }
+
+  #[test]
+  fn replay_and_compact() {
+    let id = rand::random::<u64>();
+    let path = ::std::env::temp_dir().join(format!("maid_manager_{}.log", id));
+    let snapshot_path = ::std::env::temp_dir().join(format!("maid_manager_{}.db", id));
+    let mut replayed = database();
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap(), vec![]);
+    assert!(replayed.iter().next().is_none());
+
+    let names: Vec<NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+    let mut entries = Vec::new();
+    for name in names[..3].iter() {
+      entries.push(OpLogEntry::Create { name: name.clone(), allowance: 4096 });
+      entries.push(OpLogEntry::Put { name: name.clone(), size: 2048 });
+    }
+    entries.push(OpLogEntry::Delete { name: names[0].clone(), size: 1024 });
+    // rejected when first applied, and so again on replay
+    let rejected = OpLogEntry::Put { name: names[1].clone(), size: 4096 };
+    entries.push(rejected.clone());
+    // only allowed by the database's own max allowance
+    let large = vec![OpLogEntry::Create { name: names[3].clone(), allowance: 2 * 1073741824 },
+                     OpLogEntry::Put { name: names[3].clone(), size: 1073741824 + 1024 }];
+    entries.extend(large.iter().cloned());
+
+    let mut db = database();
+    let mut log = OpLog::open(&path).unwrap();
+    for entry in entries.iter() {
+      log.append(entry).unwrap();
+      let _ = super::apply(&mut db, entry.clone());
+    }
+    let mut replayed = database();
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap(), vec![rejected.clone()]);
+    assert!(replayed.semantically_eq(&db));
+    assert_eq!(replayed.data_stored(&names[0]), Some(1024));
+    assert_eq!(replayed.data_stored(&names[1]), Some(2048));
+    assert_eq!(replayed.data_stored(&names[3]), Some(1073741824 + 1024));
+    // a database with a smaller max allowance rejects the large account, and reports so
+    let mut replayed = MaidManagerDatabase::new();
+    let mut expected = vec![rejected.clone()];
+    expected.extend(large.iter().cloned());
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap(), expected);
+    assert_eq!(replayed.exist(&names[3]), false);
+
+    // a torn final entry is skipped, and cut off when the log is next opened so that entries
+    // appended after it are replayed
+    let complete = fs::metadata(&path).unwrap().len();
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[0x83, 0x01]).unwrap();
+    let mut replayed = database();
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap(), vec![rejected.clone()]);
+    assert!(replayed.semantically_eq(&db));
+    drop(file);
+    drop(log);
+    let mut log = OpLog::open(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+    let after_crash = OpLogEntry::Put { name: names[0].clone(), size: 512 };
+    log.append(&after_crash).unwrap();
+    let _ = super::apply(&mut db, after_crash);
+    let mut replayed = database();
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap(), vec![rejected.clone()]);
+    assert!(replayed.semantically_eq(&db));
+    assert_eq!(replayed.data_stored(&names[0]), Some(1536));
+    // reopening a log with nothing torn leaves it as it is
+    drop(log);
+    let mut log = OpLog::open(&path).unwrap();
+    assert!(fs::metadata(&path).unwrap().len() > complete);
+
+    log.compact(&db, &snapshot_path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    let more = vec![OpLogEntry::Put { name: names[2].clone(), size: 1024 },
+                    OpLogEntry::Delete { name: NameType([1u8; 64]), size: 1024 }];
+    for entry in more.iter() {
+      log.append(entry).unwrap();
+      let _ = super::apply(&mut db, entry.clone());
+    }
+    let mut recovered = database();
+    assert_eq!(OpLog::recover(&mut recovered, &snapshot_path, log.path()).unwrap(), vec![more[1].clone()]);
+    assert!(recovered.semantically_eq(&db));
+    assert_eq!(recovered.data_stored(&names[2]), Some(3072));
+    assert_eq!(recovered.data_stored(&names[3]), Some(1073741824 + 1024));
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&snapshot_path).unwrap();
+  }
+
+  #[test]
+  fn corrupt_entry() {
+    let path = ::std::env::temp_dir().join(format!("maid_manager_{}.log", rand::random::<u64>()));
+    let name: NameType = routing::test_utils::Random::generate_random();
+    let entries = vec![OpLogEntry::Create { name: name.clone(), allowance: 4096 },
+                       OpLogEntry::Put { name: name.clone(), size: 1024 },
+                       OpLogEntry::Put { name: name.clone(), size: 2048 }];
+    let mut log = OpLog::open(&path).unwrap();
+    for entry in entries.iter() {
+      log.append(entry).unwrap();
+    }
+    drop(log);
+
+    // overwrite the start of the middle entry, leaving a readable entry after it
+    let mut serialised = Vec::new();
+    for entry in entries.iter() {
+      serialised.extend(super::encode(entry).unwrap());
+    }
+    let middle = super::encode(&entries[0]).unwrap().len();
+    serialised[middle] = 0xff;
+    fs::File::create(&path).unwrap().write_all(&serialised).unwrap();
+
+    match OpLog::open(&path) {
+      Err(ref error) if error.kind() == io::ErrorKind::InvalidData => (),
+      Err(error) => panic!("Unexpected {:?}", error),
+      Ok(_) => panic!("a corrupt log was opened"),
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), serialised.len() as u64);
+    let mut replayed = database();
+    assert_eq!(OpLog::replay(&mut replayed, &path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(replayed.iter().next().is_none());
+
+    // a tail longer than any entry can't be a torn one either
+    let mut serialised = super::encode(&entries[0]).unwrap();
+    serialised.extend(vec![0xff; super::longest_entry().unwrap()]);
+    fs::File::create(&path).unwrap().write_all(&serialised).unwrap();
+    assert!(OpLog::open(&path).is_err());
+    assert_eq!(fs::metadata(&path).unwrap().len(), serialised.len() as u64);
+
+    fs::remove_file(&path).unwrap();
+  }
+}
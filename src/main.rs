@@ -36,14 +36,34 @@ extern crate routing;
 extern crate maidsafe_types;
 extern crate rand;
 extern crate lru_time_cache;
+extern crate libc;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "use-bincode")]
+extern crate bincode;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_cbor;
 
 mod data_manager;
 mod maid_manager;
 mod pmid_manager;
 mod version_handler;
 mod chunk_store;
+mod free_space;
+mod byte_size;
+mod proto;
+mod account_cache;
+mod accounting;
 mod pmid_node;
 mod vault;
+#[cfg(test)]
+mod churn_simulation;
 
 use vault::VaultFacade;
 
@@ -21,73 +21,656 @@ extern crate lru_time_cache;
 
 extern crate routing;
 
+#[cfg(feature = "use-bincode")]
+use bincode;
+#[cfg(feature = "serde")]
+use serde_cbor;
 use cbor;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use free_space;
+use proto;
 use routing::generic_sendable_type;
+use routing::sendable::Sendable;
 use self::lru_time_cache::LruCache;
+use account_cache::AccountCache;
 use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
-use std::collections;
+use rustc_serialize::json;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use super::{DecodeError, PutError, PMID_MANAGER_ACCOUNT_TAG};
+use byte_size;
+use byte_size::ByteSize;
 
 type Identity = self::routing::NameType; // pmidnode address
 
-#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AccountEventKind {
+  Put,
+  Delete,
+  Loss,
+  Recover,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AccountEvent {
+  pub kind : AccountEventKind,
+  pub size : u64,
+  pub time : SystemTime
+}
+
+// the most recent events, oldest first, kept contiguous so they can be handed out as a slice
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct EventLog {
+  capacity : usize,
+  events : VecDeque<AccountEvent>
+}
+
+// sizes of the most recent puts rejected for lack of space, oldest first
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct PendingPuts {
+  capacity : usize,
+  sizes : VecDeque<u64>
+}
+
+// how far beyond offered_space a put may take stored_total_size: Strict allows up to offered_space,
+// Ratio(r) up to offered_space * r, so Ratio(1.0) is the same as Strict and a ratio below 1.0
+// holds some of the offered space back
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum OverCommitPolicy {
+  Strict,
+  Ratio(f64),
+}
+
+impl OverCommitPolicy {
+  // the ratio is applied in floating point, saturating at u64::MAX; NaN and negative ratios allow nothing
+  fn limit(&self, offered_space : u64) -> u64 {
+    match *self {
+      OverCommitPolicy::Strict => offered_space,
+      OverCommitPolicy::Ratio(ratio) if ratio == 1.0 => offered_space,
+      OverCommitPolicy::Ratio(ratio) => {
+        let limit = offered_space as f64 * ratio;
+        if !(limit > 0.0) {
+          0
+        } else if limit >= ::std::u64::MAX as f64 {
+          ::std::u64::MAX
+        } else {
+          limit as u64
+        }
+      }
+    }
+  }
+}
+
+// the most space a pmid manager accepts a node offering, whatever it asks for and has free; see
+// PmidManagerAccount::negotiate
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct CapacityPolicy {
+  pub ceiling : u64,
+}
+
+impl CapacityPolicy {
+  pub fn new(ceiling : u64) -> CapacityPolicy {
+    CapacityPolicy { ceiling: ceiling }
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AccountStatus {
+  Online,
+  Offline { since : SystemTime },
+}
+
+// the reserved headroom is a local setting, and the status, suspected losses, last modified time,
+// event log and pending puts local records; none of them are serialised or sent on with the account
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PmidManagerAccount {
   stored_total_size : u64,
   lost_total_size : u64,
-  offered_space : u64
+  offered_space : u64,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  reserved_headroom : u64,
+  #[cfg_attr(feature = "serde", serde(skip, default = "online"))]
+  status : AccountStatus,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  suspected_lost : u64,
+  #[cfg_attr(feature = "serde", serde(skip, default = "SystemTime::now"))]
+  last_modified : SystemTime,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  events : Option<Box<EventLog>>,
+  #[cfg_attr(feature = "serde", serde(skip))]
+  pending_puts : Option<Box<PendingPuts>>
+}
+
+#[cfg(feature = "serde")]
+fn online() -> AccountStatus {
+  AccountStatus::Online
 }
 
+// last_modified is left out, a decoded copy can't have kept it
+impl PartialEq for PmidManagerAccount {
+  fn eq(&self, other: &PmidManagerAccount) -> bool {
+    self.stored_total_size == other.stored_total_size && self.lost_total_size == other.lost_total_size &&
+        self.offered_space == other.offered_space && self.reserved_headroom == other.reserved_headroom &&
+        self.status == other.status &&
+        self.suspected_lost == other.suspected_lost && self.events == other.events &&
+        self.pending_puts == other.pending_puts
+  }
+}
+
+impl Eq for PmidManagerAccount {}
+
 impl Clone for PmidManagerAccount {
   fn clone(&self) -> Self {
     PmidManagerAccount {
       stored_total_size : self.stored_total_size,
       lost_total_size : self.lost_total_size,
-      offered_space : self.offered_space
+      offered_space : self.offered_space,
+      reserved_headroom : self.reserved_headroom,
+      status : self.status,
+      suspected_lost : self.suspected_lost,
+      last_modified : self.last_modified,
+      events : self.events.clone(),
+      pending_puts : self.pending_puts.clone()
     }
   }
 }
 
+// accounts are ordered by remaining space (offered_space - stored_total_size, zero once over-committed),
+// so the account with the most room is the greatest. Ties are broken by the larger offered_space,
+// then the smaller stored_total_size, then the smaller lost_total_size; local records are ignored.
+impl Ord for PmidManagerAccount {
+  fn cmp(&self, other: &PmidManagerAccount) -> cmp::Ordering {
+    self.remaining_space().cmp(&other.remaining_space())
+        .then(self.offered_space.cmp(&other.offered_space))
+        .then(other.stored_total_size.cmp(&self.stored_total_size))
+        .then(other.lost_total_size.cmp(&self.lost_total_size))
+  }
+}
+
+impl PartialOrd for PmidManagerAccount {
+  fn partial_cmp(&self, other: &PmidManagerAccount) -> Option<cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Encodable for PmidManagerAccount {
+  fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+    e.emit_struct("PmidManagerAccount", 3, |e| {
+      try!(e.emit_struct_field("stored_total_size", 0, |e| self.stored_total_size.encode(e)));
+      try!(e.emit_struct_field("lost_total_size", 1, |e| self.lost_total_size.encode(e)));
+      e.emit_struct_field("offered_space", 2, |e| self.offered_space.encode(e))
+    })
+  }
+}
+
+impl Decodable for PmidManagerAccount {
+  fn decode<D: Decoder>(d: &mut D) -> Result<PmidManagerAccount, D::Error> {
+    d.read_struct("PmidManagerAccount", 3, |d| {
+      Ok(PmidManagerAccount {
+        stored_total_size: try!(d.read_struct_field("stored_total_size", 0, Decodable::decode)),
+        lost_total_size: try!(d.read_struct_field("lost_total_size", 1, Decodable::decode)),
+        offered_space: try!(d.read_struct_field("offered_space", 2, Decodable::decode)),
+        reserved_headroom: 0,
+        status: AccountStatus::Online,
+        suspected_lost: 0,
+        last_modified: SystemTime::now(),
+        events: None,
+        pending_puts: None,
+      })
+    })
+  }
+}
+
+// e.g. "512.00 MiB stored of 1.00 GiB offered (50.0%), 0 B lost", the percentage exceeding 100 once
+// over-committed and, as with utilization, 100 if nothing is offered; Debug remains the derived field
+// by field listing
+impl fmt::Display for PmidManagerAccount {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    write!(formatter, "{} stored of {} offered ({:.1}%), {} lost", byte_size::human_readable(self.stored_total_size),
+           byte_size::human_readable(self.offered_space),
+           self.utilization() * 100.0,
+           byte_size::human_readable(self.lost_total_size))
+  }
+}
+
 impl PmidManagerAccount {
   pub fn new() -> PmidManagerAccount {
     // FIXME : to bypass the AccountCreation process for simple network, capacity is assumed automatically
-    PmidManagerAccount { stored_total_size: 0, lost_total_size: 0, offered_space: 1073741824 }
+    PmidManagerAccount::with_offered_space(1073741824)
+  }
+
+  // a new account for a node offering requested bytes, of which it reports node_reported_free
+  // free: the space offered is the least of the two and the policy's ceiling
+  pub fn negotiate(requested : u64, node_reported_free : u64, policy : &CapacityPolicy) -> PmidManagerAccount {
+    PmidManagerAccount::with_offered_space(cmp::min(cmp::min(requested, node_reported_free), policy.ceiling))
+  }
+
+  // as with_offered_space, for a size given as e.g. "100GiB"
+  pub fn with_offered_size(offered : ByteSize) -> PmidManagerAccount {
+    PmidManagerAccount::with_offered_space(offered.as_u64())
+  }
+
+  // a node offering nothing; a zero sized put still succeeds, as it doesn't take the stored total
+  // past the space offered, but any larger one is rejected whatever the over-commit policy
+  pub fn empty() -> PmidManagerAccount {
+    PmidManagerAccount::with_offered_space(0)
+  }
+
+  // an online account part way through its life; stored may exceed offered, as it can once the
+  // offered space is reduced or under an over-commit policy
+  pub fn from_parts(stored : u64, lost : u64, offered : u64) -> PmidManagerAccount {
+    let mut account = PmidManagerAccount::with_offered_space(offered);
+    account.stored_total_size = stored;
+    account.lost_total_size = lost;
+    account
+  }
+
+  pub fn with_offered_space(offered_space : u64) -> PmidManagerAccount {
+    PmidManagerAccount {
+      stored_total_size: 0,
+      lost_total_size: 0,
+      offered_space: offered_space,
+      reserved_headroom: 0,
+      status: AccountStatus::Online,
+      suspected_lost: 0,
+      last_modified: SystemTime::now(),
+      events: None,
+      pending_puts: None
+    }
+  }
+
+  // starts recording puts, deletes, losses and recoveries, keeping the latest capacity of them;
+  // any events already recorded are discarded
+  pub fn enable_event_log(&mut self, capacity : usize) {
+    self.events = Some(Box::new(EventLog { capacity: capacity, events: VecDeque::with_capacity(capacity) }));
+  }
+
+  pub fn disable_event_log(&mut self) {
+    self.events = None;
+  }
+
+  // oldest first, empty if the event log isn't enabled
+  pub fn recent_events(&self) -> &[AccountEvent] {
+    match self.events {
+      Some(ref log) => log.events.as_slices().0,
+      None => &[],
+    }
+  }
+
+  // starts keeping the sizes of up to capacity puts rejected as NotEnoughSpace, dropping the oldest
+  // once full, for retry_pending to try again; any already kept are discarded
+  pub fn enable_pending_puts(&mut self, capacity : usize) {
+    self.pending_puts = Some(Box::new(PendingPuts { capacity: capacity, sizes: VecDeque::with_capacity(capacity) }));
   }
 
-  pub fn put_data(&mut self, size : u64) -> bool {
-    if (self.stored_total_size + size) > self.offered_space {
-      return false;
+  pub fn disable_pending_puts(&mut self) {
+    self.pending_puts = None;
+  }
+
+  // oldest first, empty if pending puts aren't enabled
+  pub fn pending_puts(&self) -> Vec<u64> {
+    match self.pending_puts {
+      Some(ref pending) => pending.sizes.iter().cloned().collect(),
+      None => Vec::new(),
+    }
+  }
+
+  // tries each pending put again, oldest first, against offered_space; those which now fit are
+  // stored and dropped from the queue, the rest stay in order. Returns how many were stored.
+  pub fn retry_pending(&mut self) -> usize {
+    let sizes = match self.pending_puts {
+      Some(ref mut pending) => ::std::mem::replace(&mut pending.sizes, VecDeque::new()),
+      None => return 0,
+    };
+    let mut stored = 0;
+    let mut remaining = VecDeque::with_capacity(sizes.len());
+    let limit = self.put_limit(OverCommitPolicy::Strict);
+    for size in sizes {
+      if self.store(size, limit).is_ok() {
+        self.record(AccountEventKind::Put, size);
+        self.last_modified = SystemTime::now();
+        stored += 1;
+      } else {
+        remaining.push_back(size);
+      }
+    }
+    if let Some(ref mut pending) = self.pending_puts {
+      pending.sizes = remaining;
+    }
+    stored
+  }
+
+  // bytes held on the heap for the event log and pending puts, beyond size_of::<PmidManagerAccount>()
+  fn heap_bytes(&self) -> usize {
+    let events = self.events.as_ref().map_or(0, |log| {
+      ::std::mem::size_of::<EventLog>() + log.events.capacity() * ::std::mem::size_of::<AccountEvent>()
+    });
+    let pending_puts = self.pending_puts.as_ref().map_or(0, |pending| {
+      ::std::mem::size_of::<PendingPuts>() + pending.sizes.capacity() * ::std::mem::size_of::<u64>()
+    });
+    events + pending_puts
+  }
+
+  fn record(&mut self, kind : AccountEventKind, size : u64) {
+    if let Some(ref mut log) = self.events {
+      if log.capacity == 0 {
+        return;
+      }
+      if log.events.len() == log.capacity {
+        let _ = log.events.pop_front();
+      }
+      log.events.push_back(AccountEvent { kind: kind, size: size, time: SystemTime::now() });
+      let _ = log.events.make_contiguous();
+    }
+  }
+
+  // offers all of the space currently free on the file system holding the node's storage
+  pub fn from_storage_path(path : &Path) -> io::Result<PmidManagerAccount> {
+    Ok(PmidManagerAccount::with_offered_space(try!(free_space::free_space(path))))
+  }
+
+  // fails with NotEnoughSpace unless stored_total_size + size fits within offered_space, so an account
+  // offering nothing rejects every nonzero put. put_data(0) changes nothing and so succeeds on such an
+  // account while it holds nothing, but like any other put is rejected once what it holds is over
+  // what it offers, e.g. after set_available_size(0).
+  pub fn put_data(&mut self, size : u64) -> Result<(), PutError> {
+    self.put_data_with_policy(size, OverCommitPolicy::Strict)
+  }
+
+  // as put_data, but with the limit on stored_total_size given by policy rather than offered_space
+  pub fn put_data_with_policy(&mut self, size : u64, policy : OverCommitPolicy) -> Result<(), PutError> {
+    let limit = self.put_limit(policy);
+    if let Err(error) = self.store(size, limit) {
+      if let (&PutError::NotEnoughSpace { .. }, Some(pending)) = (&error, self.pending_puts.as_mut()) {
+        if pending.capacity > 0 {
+          if pending.sizes.len() == pending.capacity {
+            let _ = pending.sizes.pop_front();
+          }
+          pending.sizes.push_back(size);
+        }
+      }
+      return Err(error);
     }
-    self.stored_total_size += size;
-    true
+    self.record(AccountEventKind::Put, size);
+    self.last_modified = SystemTime::now();
+    Ok(())
+  }
+
+  // space kept free of puts, so stored_total_size is held to offered_space less the headroom, or
+  // nothing once the headroom is at least the space offered; zero unless set
+  pub fn set_reserved_headroom(&mut self, headroom : u64) {
+    self.reserved_headroom = headroom;
+  }
+
+  pub fn get_reserved_headroom(&self) -> u64 {
+    self.reserved_headroom
+  }
+
+  // the over-commit policy applies to the space offered beyond the headroom
+  fn put_limit(&self, policy : OverCommitPolicy) -> u64 {
+    policy.limit(self.offered_space.saturating_sub(self.reserved_headroom))
   }
 
-  pub fn delete_data(&mut self, size : u64) {
-    if self.stored_total_size < size {
-      self.stored_total_size = 0;
-    } else {
-      self.stored_total_size -= size;
+  fn store(&mut self, size : u64, limit : u64) -> Result<(), PutError> {
+    match self.stored_total_size.checked_add(size) {
+      // the limit is inclusive, so a put exactly filling the account is accepted
+      Some(new_total) if new_total <= limit => {
+        self.stored_total_size = new_total;
+        Ok(())
+      }
+      Some(_) => Err(PutError::NotEnoughSpace {
+        requested: size,
+        available: limit.saturating_sub(self.stored_total_size)
+      }),
+      None => Err(PutError::Overflow)
     }
   }
 
+  // whether put_data(size) would succeed, without modifying the account
+  pub fn can_put(&self, size : u64) -> bool {
+    self.can_put_with_policy(size, OverCommitPolicy::Strict)
+  }
+
+  pub fn can_put_with_policy(&self, size : u64, policy : OverCommitPolicy) -> bool {
+    let limit = self.put_limit(policy);
+    self.stored_total_size.checked_add(size).map_or(false, |new_total| new_total <= limit)
+  }
+
+  // returns the bytes actually freed, which is less than size if less than that was stored
+  pub fn delete_data(&mut self, size : u64) -> u64 {
+    let freed = self.release(size);
+    self.record(AccountEventKind::Delete, size);
+    self.last_modified = SystemTime::now();
+    freed
+  }
+
+  fn release(&mut self, size : u64) -> u64 {
+    let freed = cmp::min(size, self.stored_total_size);
+    self.stored_total_size -= freed;
+    freed
+  }
+
+  // while the node is offline a loss is only suspected, its data may come back with it
   pub fn handle_lost_data(&mut self, size : u64) {
-    self.delete_data(size);
-    self.lost_total_size += size;
+    if let AccountStatus::Offline { .. } = self.status {
+      self.suspected_lost = self.suspected_lost.saturating_add(size);
+      return;
+    }
+    let _ = self.release(size);
+    self.lost_total_size = self.lost_total_size.saturating_add(size);
+    self.record(AccountEventKind::Loss, size);
+  }
+
+  // the time of going offline is kept if the node is already offline
+  pub fn mark_offline(&mut self) {
+    if self.status == AccountStatus::Online {
+      self.status = AccountStatus::Offline { since: SystemTime::now() };
+    }
+  }
+
+  // the node is back with its data, so the losses suspected while it was away are dropped
+  pub fn mark_online(&mut self) {
+    self.status = AccountStatus::Online;
+    self.suspected_lost = 0;
+    self.last_modified = SystemTime::now();
+  }
+
+  // once the node has been offline for at least timeout its suspected losses are taken as real,
+  // returning how much was committed to lost_total_size; the node stays offline
+  pub fn reconcile_suspected_lost(&mut self, timeout : Duration) -> u64 {
+    let offline_for = match self.status {
+      AccountStatus::Offline { since } => SystemTime::now().duration_since(since).unwrap_or(Duration::new(0, 0)),
+      AccountStatus::Online => return 0,
+    };
+    if offline_for < timeout || self.suspected_lost == 0 {
+      return 0;
+    }
+    let suspected = self.suspected_lost;
+    self.suspected_lost = 0;
+    let _ = self.release(suspected);
+    self.lost_total_size = self.lost_total_size.saturating_add(suspected);
+    self.record(AccountEventKind::Loss, suspected);
+    suspected
+  }
+
+  pub fn get_status(&self) -> AccountStatus {
+    self.status
+  }
+
+  pub fn get_suspected_lost(&self) -> u64 {
+    self.suspected_lost
+  }
+
+  // a pmid node that comes back online with lost chunks intact has that space stored again
+  pub fn recover_lost_data(&mut self, size : u64) -> Result<(), PutError> {
+    let recovered = cmp::min(size, self.lost_total_size);
+    try!(self.store(recovered, self.offered_space));
+    self.lost_total_size -= recovered;
+    self.record(AccountEventKind::Recover, recovered);
+    self.last_modified = SystemTime::now();
+    Ok(())
+  }
+
+  // time since the node was last heard from: the last put, delete, recovery, change of offered space
+  // or return online; losses don't count, they're reported by others
+  pub fn idle_since(&self) -> Duration {
+    SystemTime::now().duration_since(self.last_modified).unwrap_or(Duration::new(0, 0))
+  }
+
+  // takes everything stored as lost whatever the node's status, the suspected losses being part of
+  // it, returning how much was committed to lost_total_size
+  fn write_off(&mut self) -> u64 {
+    self.suspected_lost = 0;
+    let stored = self.release(::std::u64::MAX);
+    if stored > 0 {
+      self.lost_total_size = self.lost_total_size.saturating_add(stored);
+      self.record(AccountEventKind::Loss, stored);
+    }
+    stored
   }
 
   pub fn handle_falure(&mut self, size : u64) {
     self.handle_lost_data(size);
   }
 
+  // the space offered may shrink below what is already stored, stored_total_size is kept as is and
+  // the account reports itself over-committed until enough is deleted or more space is offered
   pub fn set_available_size(&mut self, available_size : u64) {
     self.offered_space = available_size;
+    self.last_modified = SystemTime::now();
+  }
+
+  pub fn is_over_committed(&self) -> bool {
+    self.stored_total_size > self.offered_space
   }
 
+  // applies the diff found by a periodic reconciliation of what the node holds against what it
+  // should: diff_size bytes are moved from stored to lost. Unlike handle_lost_data this is applied
+  // whatever the node's status and isn't logged as an event. stored_total_size stops at zero and
+  // lost_total_size at u64::MAX, so repeated diffs can't wrap either.
   pub fn update_account(&mut self, diff_size : u64) {
-    if self.stored_total_size < diff_size {
-      self.stored_total_size = 0;
-    } else {
-      self.stored_total_size -= diff_size;
+    self.stored_total_size = self.stored_total_size.saturating_sub(diff_size);
+    self.lost_total_size = self.lost_total_size.saturating_add(diff_size);
+  }
+
+  // stored_total_size as a fraction of offered_space, over 1.0 once over-committed; an account
+  // offering nothing is fully utilised at 1.0 whatever it holds
+  pub fn utilization(&self) -> f64 {
+    if self.offered_space == 0 {
+      return 1.0;
+    }
+    self.stored_total_size as f64 / self.offered_space as f64
+  }
+
+  // always true of an account offering nothing
+  pub fn is_full(&self) -> bool {
+    self.stored_total_size >= self.offered_space
+  }
+
+  // lost_total_size as a fraction of offered_space, which may exceed 1.0; an account offering nothing
+  // is at 1.0 once it has lost anything
+  pub fn loss_ratio(&self) -> f64 {
+    if self.lost_total_size == 0 {
+      return 0.0;
+    }
+    if self.offered_space == 0 {
+      return 1.0;
+    }
+    self.lost_total_size as f64 / self.offered_space as f64
+  }
+
+  // whether the loss ratio is over threshold, e.g. 0.25 to flag a node which has lost more than a
+  // quarter of the space it offers
+  pub fn is_unreliable(&self, threshold : f64) -> bool {
+    self.loss_ratio() > threshold
+  }
+
+  // a JSON object with the keys "stored_total_size", "lost_total_size" and "offered_space", all
+  // sizes in bytes
+  pub fn to_json(&self) -> String {
+    json::encode(self).unwrap()
+  }
+
+  pub fn from_json(serialised : &str) -> Result<PmidManagerAccount, json::DecoderError> {
+    json::decode(serialised)
+  }
+
+  // equal sizes, ignoring the local records
+  pub fn semantically_eq(&self, other: &PmidManagerAccount) -> bool {
+    self.stored_total_size == other.stored_total_size && self.lost_total_size == other.lost_total_size &&
+        self.offered_space == other.offered_space
+  }
+
+  // reconciles a copy of this account received from another manager: the larger stored size is
+  // kept while lost sizes are accumulated, as each manager may have recorded different losses
+  fn reconcile(&mut self, other: &PmidManagerAccount) {
+    self.stored_total_size = cmp::max(self.stored_total_size, other.stored_total_size);
+    self.lost_total_size = self.lost_total_size.saturating_add(other.lost_total_size);
+  }
+
+  // the fields a transferred copy carries, by which the same transfer received again is recognised
+  fn transferred(&self) -> (u64, u64, u64) {
+    (self.stored_total_size, self.lost_total_size, self.offered_space)
+  }
+
+  // takes the larger of each field, so copies of an account received from several holders converge
+  // to the same record whatever order they arrive in
+  pub fn merge(&mut self, other: &PmidManagerAccount) {
+    self.stored_total_size = cmp::max(self.stored_total_size, other.stored_total_size);
+    self.lost_total_size = cmp::max(self.lost_total_size, other.lost_total_size);
+    self.offered_space = cmp::max(self.offered_space, other.offered_space);
+  }
+
+  #[cfg(feature = "use-bincode")]
+  pub fn to_bincode(&self) -> Vec<u8> {
+    bincode::rustc_serialize::encode(self, bincode::SizeLimit::Infinite).unwrap()
+  }
+
+  #[cfg(feature = "use-bincode")]
+  pub fn from_bincode(serialised : &[u8]) -> Result<PmidManagerAccount, bincode::rustc_serialize::DecodingError> {
+    bincode::rustc_serialize::decode(serialised)
+  }
+
+  // the same bytes as the rustc-serialize cbor encoding, so either can decode the other's output
+  #[cfg(feature = "serde")]
+  pub fn to_serde_cbor(&self) -> Vec<u8> {
+    serde_cbor::to_vec(self).unwrap()
+  }
+
+  #[cfg(feature = "serde")]
+  pub fn from_serde_cbor(serialised : &[u8]) -> Result<PmidManagerAccount, serde_cbor::Error> {
+    serde_cbor::from_slice(serialised)
+  }
+
+  // a protobuf encoding for services not written in rust, of
+  //   message PmidManagerAccount {
+  //     uint64 stored_total_size = 1;
+  //     uint64 lost_total_size = 2;
+  //     uint64 offered_space = 3;
+  //   }
+  // written length-delimited, see proto. Only those three fields are carried: the account decoded
+  // is online and modified now, as one built by from_parts.
+  pub fn to_proto_bytes(&self) -> Vec<u8> {
+    proto::encode_delimited(&[(1, self.stored_total_size), (2, self.lost_total_size), (3, self.offered_space)])
+  }
+
+  pub fn from_proto_bytes(serialised : &[u8]) -> Result<PmidManagerAccount, DecodeError> {
+    if serialised.is_empty() {
+      return Err(DecodeError::Empty);
     }
-    self.lost_total_size += diff_size;
+    let mut fields = [0u64; 3];
+    try!(proto::decode_delimited(serialised, &mut fields).map_err(DecodeError::InvalidAccount));
+    Ok(PmidManagerAccount::from_parts(fields[0], fields[1], fields[2]))
   }
 
   pub fn get_offered_space(&self) -> u64 {
@@ -102,73 +685,2588 @@ impl PmidManagerAccount {
   pub fn get_stored_total_size(&self) -> u64 {
       self.stored_total_size.clone()
   }
+
+  // offered_space less stored_total_size, zero once over-committed; the reserved headroom and the
+  // over-commit policy aren't taken into account, see can_put_with_policy
+  pub fn remaining_space(&self) -> u64 {
+      self.offered_space.saturating_sub(self.stored_total_size)
+  }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PmidManagerStats {
+  pub account_count : usize,
+  pub total_stored : u64,
+  pub total_lost : u64,
+  pub total_offered : u64
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct CapacitySummary {
+  pub managed_nodes : usize,
+  pub total_offered : u64,
+  pub total_stored : u64,
+  pub utilisation : f64
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PmidManagerMetrics {
+  pub puts_ok : u64,
+  pub puts_rejected : u64,
+  pub losses : u64,
+  // accounts dropped to make room for a new one once the database holds its capacity
+  pub evictions : u64,
+  // of the latest transfer by any of the retrieve_all variants: how long it took to remove and
+  // encode the accounts, None until there has been one, and how many accounts and serialised bytes
+  // it produced. For retrieve_all_and_reset_chunked these are the totals of the chunks taken so far,
+  // and for retrieve_all_compressed the bytes are those of the compressed blob.
+  pub last_transfer_duration : Option<Duration>,
+  pub last_transfer_accounts : usize,
+  pub last_transfer_bytes : u64
+}
+
+// each differing field as (value in self, value in other), None where they agree
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AccountDelta {
+  pub stored_total_size : Option<(u64, u64)>,
+  pub lost_total_size : Option<(u64, u64)>,
+  pub offered_space : Option<(u64, u64)>
+}
+
+// the result of PmidManagerDatabase::diff, each list in name order
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DatabaseDiff {
+  pub only_in_self : Vec<Identity>,
+  pub only_in_other : Vec<Identity>,
+  pub differing : Vec<(Identity, AccountDelta)>
+}
+
+impl DatabaseDiff {
+  pub fn is_empty(&self) -> bool {
+    self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.differing.is_empty()
+  }
+}
+
+fn field_delta(ours : u64, theirs : u64) -> Option<(u64, u64)> {
+  if ours == theirs { None } else { Some((ours, theirs)) }
 }
 
 pub struct PmidManagerDatabase {
-  storage : collections::HashMap<Identity, PmidManagerAccount>,
+  storage : AccountCache<Identity, PmidManagerAccount>,
+  capacity : usize,
+  metrics : PmidManagerMetrics,
+  on_remove : Option<Box<dyn Fn(&Identity, &PmidManagerAccount) + Send>>,
+  name_validation : bool,
+  reserved_names : Vec<Identity>,
+  over_commit : OverCommitPolicy,
+  // kept up to date by every mutation made through the database, see recompute
+  total_stored : u64,
+  // the outcome of each put recently made by put_data_with_id, see enable_put_dedup
+  put_ids : Option<LruCache<[u8; 32], bool>>,
+  max_accounts : Option<usize>,
+  // the stored, lost and offered sizes of each transferred copy reconciled into an account held,
+  // so that the same transfer received again isn't counted twice, see accept_transfer
+  reconciled : HashMap<Identity, Vec<(u64, u64, u64)>>,
+}
+
+// only the accounts are encoded, as a sequence of (name, account) pairs; a decoded database is
+// otherwise as given by new
+impl Encodable for PmidManagerDatabase {
+  fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+    let accounts: Vec<(&Identity, &PmidManagerAccount)> = self.storage.peek_iter().collect();
+    accounts.encode(e)
+  }
+}
+
+impl Decodable for PmidManagerDatabase {
+  fn decode<D: Decoder>(d: &mut D) -> Result<PmidManagerDatabase, D::Error> {
+    let accounts: Vec<(Identity, PmidManagerAccount)> = try!(Decodable::decode(d));
+    let mut database = PmidManagerDatabase::new();
+    for (name, account) in accounts {
+      database.storage.insert(name, account);
+    }
+    database.recompute();
+    Ok(database)
+  }
 }
 
 impl PmidManagerDatabase {
   pub fn new () -> PmidManagerDatabase {
-      PmidManagerDatabase { storage: collections::HashMap::with_capacity(10000), }
+      PmidManagerDatabase {
+          storage: AccountCache::with_capacity(10000),
+          capacity: 10000,
+          metrics: PmidManagerMetrics { puts_ok: 0, puts_rejected: 0, losses: 0, evictions: 0,
+                                        last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 },
+          on_remove: None,
+          name_validation: false,
+          reserved_names: vec![routing::NameType([0u8; 64])],
+          over_commit: OverCommitPolicy::Strict,
+          total_stored: 0,
+          put_ids: None,
+          max_accounts: None,
+          reconciled: HashMap::new(),
+      }
+  }
+
+  // accounts beyond count, or untouched for longer than ttl, are evicted least recently used first
+  pub fn with_capacity(count: usize, ttl: Duration) -> PmidManagerDatabase {
+      PmidManagerDatabase {
+          storage: AccountCache::with_expiry_duration_and_capacity(ttl, count),
+          capacity: count,
+          metrics: PmidManagerMetrics { puts_ok: 0, puts_rejected: 0, losses: 0, evictions: 0,
+                                        last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 },
+          on_remove: None,
+          name_validation: false,
+          reserved_names: vec![routing::NameType([0u8; 64])],
+          over_commit: OverCommitPolicy::Strict,
+          total_stored: 0,
+          put_ids: None,
+          max_accounts: None,
+          reconciled: HashMap::new(),
+      }
+  }
+
+  // with validation on, put_data rejects the zero name and any others reserved
+  pub fn with_name_validation(enabled: bool) -> PmidManagerDatabase {
+      let mut database = PmidManagerDatabase::new();
+      database.name_validation = enabled;
+      database
+  }
+
+  // once limit accounts are held, a put for a name without one fails with AtCapacity rather than
+  // opening an account, where the capacity of with_capacity would evict to make room; the accounts
+  // already held may still be put to and deleted from, and accounts handed over on churn are still
+  // accepted
+  pub fn with_max_accounts(limit: usize) -> PmidManagerDatabase {
+      let mut database = PmidManagerDatabase::new();
+      database.max_accounts = Some(limit);
+      database
+  }
+
+  // applied by put_data and can_put to every account, Strict unless set
+  pub fn set_over_commit_policy(&mut self, policy: OverCommitPolicy) {
+      self.over_commit = policy;
+  }
+
+  pub fn reserve_name(&mut self, name: Identity) {
+      if !self.reserved_names.contains(&name) {
+          self.reserved_names.push(name);
+      }
+  }
+
+  fn is_reserved(&self, name: &Identity) -> bool {
+      self.name_validation && self.reserved_names.contains(name)
+  }
+
+  pub fn load_from_file(path: &Path) -> io::Result<PmidManagerDatabase> {
+      let mut file = match fs::File::open(path) {
+          Ok(file) => file,
+          Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(PmidManagerDatabase::new()),
+          Err(error) => return Err(error),
+      };
+      let mut serialised = Vec::new();
+      try!(file.read_to_end(&mut serialised));
+      let mut d = cbor::Decoder::from_bytes(serialised);
+      match d.decode().next() {
+          Some(Ok(database)) => Ok(database),
+          Some(Err(error)) => Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+          None => Ok(PmidManagerDatabase::new()),
+      }
+  }
+
+  // written to a temporary file in the same directory which is then renamed over path, so a crash
+  // part way through never leaves a truncated database behind
+  pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+      let mut e = cbor::Encoder::from_memory();
+      match e.encode(&[self]) {
+          Ok(_) => (),
+          Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+      }
+      let mut temp_name = path.file_name().unwrap_or(path.as_os_str()).to_os_string();
+      temp_name.push(".tmp");
+      let temp_path = path.with_file_name(temp_name);
+      {
+          let mut file = try!(fs::File::create(&temp_path));
+          try!(file.write_all(e.as_bytes()));
+          try!(file.sync_all());
+      }
+      fs::rename(&temp_path, path)
   }
 
   pub fn exist(&mut self, name : &Identity) -> bool {
+      self.contains(name)
+  }
+
+  pub fn contains(&self, name : &Identity) -> bool {
       self.storage.contains_key(name)
   }
 
-  pub fn put_data(&mut self, name : &Identity, size: u64) -> bool {
-      let entry = self.storage.entry(name.clone()).or_insert(PmidManagerAccount::new());
-      entry.put_data(size)
+  // (stored, lost, offered) of the account, if held
+  pub fn usage(&self, name : &Identity) -> Option<(u64, u64, u64)> {
+      self.storage.peek(name).map(|account| {
+          (account.stored_total_size, account.lost_total_size, account.offered_space)
+      })
   }
 
-    pub fn retrieve_all_and_reset(&mut self, close_group: &Vec<routing::NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
-      let data: Vec<_> = self.storage.drain().collect();
-      let mut sendable_data = Vec::with_capacity(data.len());
-      for element in data {
-          if close_group.iter().find(|a| **a == element.0).is_some() {
-              let mut e = cbor::Encoder::from_memory();
-              e.encode(&[&element.1]).unwrap();
-              let serialised_content = e.into_bytes();
-              sendable_data.push(generic_sendable_type::GenericSendableType::new(element.0, 0, serialised_content)); //TODO Get type_tag correct
+  // a header row then one row per account, with the name as lower case hex
+  pub fn export_csv<W: Write>(&self, w: &mut W) -> io::Result<()> {
+      try!(writeln!(w, "name,stored_total_size,lost_total_size,offered_space"));
+      for (name, account) in self.storage.peek_iter() {
+          try!(writeln!(w, "{},{},{},{}", name_as_hex(name), account.stored_total_size, account.lost_total_size,
+                        account.offered_space));
+      }
+      Ok(())
+  }
+
+  pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a Identity, &'a PmidManagerAccount)> {
+      self.storage.peek_iter()
+  }
+
+  // a copy of every account, which stays as it is however the database changes afterwards; unlike
+  // retrieve_all_and_reset nothing is removed, and the copy can be passed between readers cheaply
+  pub fn snapshot_accounts(&self) -> Arc<Vec<(Identity, PmidManagerAccount)>> {
+      Arc::new(self.storage.peek_iter().map(|(name, account)| (name.clone(), account.clone())).collect())
+  }
+
+  pub fn identities(&self) -> Vec<Identity> {
+      self.iter().map(|(name, _)| name.clone()).collect()
+  }
+
+  // whether both hold the same accounts with the same sizes, whatever order they were inserted in
+  // or recently used; metrics and configuration aren't compared
+  pub fn semantically_eq(&self, other: &PmidManagerDatabase) -> bool {
+      self.iter().count() == other.iter().count() &&
+          self.iter().all(|(name, account)| {
+              other.storage.peek(name).map_or(false, |theirs| account.semantically_eq(theirs))
+          })
+  }
+
+  // compares the accounts held by each database, neither is modified nor has its cache order changed;
+  // the event logs aren't compared
+  pub fn diff(&self, other: &PmidManagerDatabase) -> DatabaseDiff {
+      let mut diff = DatabaseDiff { only_in_self: Vec::new(), only_in_other: Vec::new(), differing: Vec::new() };
+      for (name, account) in self.iter() {
+          match other.storage.peek(name) {
+              Some(theirs) => {
+                  let delta = AccountDelta {
+                      stored_total_size: field_delta(account.stored_total_size, theirs.stored_total_size),
+                      lost_total_size: field_delta(account.lost_total_size, theirs.lost_total_size),
+                      offered_space: field_delta(account.offered_space, theirs.offered_space),
+                  };
+                  if delta.stored_total_size.is_some() || delta.lost_total_size.is_some() ||
+                     delta.offered_space.is_some() {
+                      diff.differing.push((name.clone(), delta));
+                  }
+              }
+              None => diff.only_in_self.push(name.clone()),
           }
       }
-      sendable_data
-    }
-}
+      diff.only_in_other = other.iter().map(|(name, _)| name).filter(|name| !self.contains(name)).cloned().collect();
+      diff.only_in_self.sort();
+      diff.only_in_other.sort();
+      diff.differing.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+      diff
+  }
+
+  // the n accounts with the most remaining space, most first; accounts which compare equal are
+  // returned in name order
+  pub fn least_loaded(&self, n: usize) -> Vec<Identity> {
+      let mut accounts: Vec<(&Identity, &PmidManagerAccount)> = self.iter().collect();
+      accounts.sort_by(|lhs, rhs| rhs.1.cmp(lhs.1).then(lhs.0.cmp(rhs.0)));
+      accounts.into_iter().take(n).map(|(name, _)| name.clone()).collect()
+  }
 
+  // puts size on whichever candidate able to take it would be left with the least space, the first
+  // listed winning a tie; an unknown candidate counts as a new account, as it would for put_data
+  pub fn put_best_fit(&mut self, candidates: &[Identity], size: u64) -> Option<Identity> {
+      let mut best: Option<(&Identity, u64)> = None;
+      for name in candidates.iter().filter(|name| self.can_put(name, size)) {
+          let slack = match self.storage.peek(name) {
+              Some(account) => account.put_limit(self.over_commit) - account.stored_total_size - size,
+              None => PmidManagerAccount::new().put_limit(self.over_commit) - size,
+          };
+          if best.map_or(true, |(_, least)| slack < least) {
+              best = Some((name, slack));
+          }
+      }
+      let chosen = match best {
+          Some((name, _)) => name.clone(),
+          None => return None,
+      };
+      match self.put_data(&chosen, size) {
+          Ok(()) => Some(chosen),
+          Err(_) => None,
+      }
+  }
 
-#[cfg(test)]
-mod test {
-  extern crate cbor;
-  extern crate maidsafe_types;
-  extern crate rand;
-  extern crate routing;
-  use super::{PmidManagerDatabase, PmidManagerAccount};
-  use self::routing::types::*;
+  pub fn stats(&self) -> PmidManagerStats {
+      let mut stats = PmidManagerStats { account_count: 0, total_stored: 0, total_lost: 0, total_offered: 0 };
+      for (_, account) in self.storage.peek_iter() {
+          stats.account_count += 1;
+          stats.total_stored = stats.total_stored.saturating_add(account.stored_total_size);
+          stats.total_lost = stats.total_lost.saturating_add(account.lost_total_size);
+          stats.total_offered = stats.total_offered.saturating_add(account.offered_space);
+      }
+      stats
+  }
 
-    #[test]
-    fn exist() {
-        let mut db = PmidManagerDatabase::new();
-        let name = routing::test_utils::Random::generate_random();
-        assert_eq!(db.exist(&name), false);
-        db.put_data(&name, 1024);
-        assert_eq!(db.exist(&name), true);
-    }
+  // a rough guide to the memory taken by the database: the cache keeps each name in its map and in
+  // its lru order, alongside the account, its last access time and the stamp of that access, which
+  // also keys the order, and each map is taken to add a further word per entry
+  pub fn estimated_memory_bytes(&self) -> usize {
+      let entry = 2 * ::std::mem::size_of::<Identity>() + ::std::mem::size_of::<PmidManagerAccount>() +
+                  ::std::mem::size_of::<::std::time::Instant>() + 2 * ::std::mem::size_of::<u64>() +
+                  2 * ::std::mem::size_of::<usize>();
+      self.storage.peek_iter().fold(::std::mem::size_of::<PmidManagerDatabase>(), |total, (_, account)| {
+          total + entry + account.heap_bytes()
+      })
+  }
 
-    #[test]
-    fn put_data() {
-        let mut db = PmidManagerDatabase::new();
-        let name = routing::test_utils::Random::generate_random();
-        assert_eq!(db.put_data(&name, 0), true);
-        assert_eq!(db.exist(&name), true);
-        assert_eq!(db.put_data(&name, 1), true);
-        assert_eq!(db.put_data(&name, 1073741823), true);
-        assert_eq!(db.put_data(&name, 1), false);
-        assert_eq!(db.put_data(&name, 1), false);
-        assert_eq!(db.put_data(&name, 0), true);
-        assert_eq!(db.put_data(&name, 1), false);
-        assert_eq!(db.exist(&name), true);
+  // each account storing more than it offers, with how much more, in name order
+  pub fn over_committed_accounts(&self) -> Vec<(Identity, u64)> {
+      self.storage.peek_iter()
+                  .filter(|&(_, account)| account.is_over_committed())
+                  .map(|(name, account)| (name.clone(), account.stored_total_size - account.offered_space))
+                  .collect()
+  }
+
+  // each account whose loss ratio is over threshold, see PmidManagerAccount::is_unreliable, in name
+  // order
+  pub fn unreliable_accounts(&self, threshold: f64) -> Vec<Identity> {
+      self.storage.peek_iter()
+                  .filter(|&(_, account)| account.is_unreliable(threshold))
+                  .map(|(name, _)| name.clone())
+                  .collect()
+  }
+
+  // the count accounts closest to target by XOR distance, nearest first, as a close group is chosen,
+  // each with its stored_total_size
+  pub fn accounts_within(&self, target: &routing::NameType, count: usize) -> Vec<(Identity, u64)> {
+      let mut accounts: Vec<(Identity, u64)> =
+          self.storage.peek_iter().map(|(name, account)| (name.clone(), account.stored_total_size)).collect();
+      // the distances compared byte by byte, most significant first, which is the order
+      // closer_to_target gives but total, equal names comparing equal
+      let distance = |name: &Identity| {
+          let mut distance = [0u8; 64];
+          for (byte, (lhs, rhs)) in distance.iter_mut().zip(name.0.iter().zip(target.0.iter())) {
+              *byte = lhs ^ rhs;
+          }
+          distance
+      };
+      accounts.sort_by(|lhs, rhs| distance(&lhs.0)[..].cmp(&distance(&rhs.0)[..]));
+      accounts.truncate(count);
+      accounts
+  }
+
+  // replaces the cache with a copy of itself, which drops the space it kept for accounts since
+  // removed and the room each event log and pending puts queue kept beyond the entries it holds; the
+  // order of use and the time each account was last used are kept. Returns the fall in
+  // estimated_memory_bytes.
+  pub fn compact(&mut self) -> usize {
+      let before = self.estimated_memory_bytes();
+      self.storage = self.storage.clone();
+      before.saturating_sub(self.estimated_memory_bytes())
+  }
+
+  // the returned counts are for stored_total_size in [0, buckets[0]), [buckets[0], buckets[1]), ...
+  // and finally [buckets[buckets.len() - 1], u64::MAX]; buckets must be nonempty and sorted
+  pub fn size_histogram(&self, buckets: &[u64]) -> Vec<usize> {
+      assert!(!buckets.is_empty(), "size_histogram needs at least one bucket boundary");
+      assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]), "size_histogram bucket boundaries must be sorted");
+      let mut counts = vec![0usize; buckets.len() + 1];
+      for (_, account) in self.storage.peek_iter() {
+          let index = buckets.iter().take_while(|&&edge| edge <= account.stored_total_size).count();
+          counts[index] += 1;
+      }
+      counts
+  }
+
+  // as with a single account, no space offered at all counts as fully utilised
+  pub fn capacity_summary(&self) -> CapacitySummary {
+      let stats = self.stats();
+      let utilisation = if stats.total_offered == 0 {
+          1.0
+      } else {
+          stats.total_stored as f64 / stats.total_offered as f64
+      };
+      CapacitySummary {
+          managed_nodes: stats.account_count,
+          total_offered: stats.total_offered,
+          total_stored: stats.total_stored,
+          utilisation: utilisation,
+      }
+  }
+
+  pub fn put_data(&mut self, name : &Identity, size: u64) -> Result<(), PutError> {
+      let result = if self.is_reserved(name) {
+          Err(PutError::InvalidName)
+      } else if !self.storage.contains_key(name) && self.at_max_accounts() {
+          Err(PutError::AtCapacity)
+      } else {
+          if !self.storage.contains_key(name) {
+              self.insert_account(name.clone(), PmidManagerAccount::new());
+          }
+          self.storage.get_mut(name).unwrap().put_data_with_policy(size, self.over_commit)
+      };
+      match result {
+          Ok(_) => {
+              self.metrics.puts_ok = self.metrics.puts_ok.saturating_add(1);
+              self.total_stored = self.total_stored.saturating_add(size);
+          }
+          Err(_) => self.metrics.puts_rejected = self.metrics.puts_rejected.saturating_add(1),
+      }
+      result
+  }
+
+  fn at_max_accounts(&self) -> bool {
+      self.max_accounts.map_or(false, |limit| self.storage.len() >= limit)
+  }
+
+  // remembers the outcome of the last capacity puts made by put_data_with_id, replacing any already
+  // remembered
+  pub fn enable_put_dedup(&mut self, capacity: usize) {
+      assert!(capacity > 0, "capacity must be positive");
+      self.put_ids = Some(LruCache::with_capacity(capacity));
+  }
+
+  pub fn disable_put_dedup(&mut self) {
+      self.put_ids = None;
+  }
+
+  // as put_data, returning whether the put was accepted, for a put which the network may deliver
+  // more than once: with enable_put_dedup, a repeated op_id still remembered isn't applied again and
+  // gets the answer given the first time. Without it every delivery is applied.
+  pub fn put_data_with_id(&mut self, name : &Identity, size: u64, op_id: [u8; 32]) -> bool {
+      if let Some(accepted) = self.put_ids.as_mut().and_then(|put_ids| put_ids.get(&op_id).cloned()) {
+          return accepted;
+      }
+      let accepted = self.put_data(name, size).is_ok();
+      if let Some(ref mut put_ids) = self.put_ids {
+          let _ = put_ids.insert(op_id, accepted);
+      }
+      accepted
+  }
+
+  // whether put_data would succeed, an unknown name is checked against the space a new account is
+  // given; nothing is inserted and the account's position in the cache is left alone
+  pub fn can_put(&self, name : &Identity, size: u64) -> bool {
+      if self.is_reserved(name) {
+          return false;
+      }
+      match self.storage.peek(name) {
+          Some(account) => account.can_put_with_policy(size, self.over_commit),
+          None => !self.at_max_accounts() && PmidManagerAccount::new().can_put_with_policy(size, self.over_commit),
+      }
+  }
+
+  pub fn handle_lost_data(&mut self, name : &Identity, size: u64) {
+      let lost = match self.storage.get_mut(name) {
+          Some(value) => {
+              let before = value.stored_total_size;
+              value.handle_lost_data(size);
+              before.saturating_sub(value.stored_total_size)
+          }
+          None => return,
+      };
+      self.total_stored = self.total_stored.saturating_sub(lost);
+      self.metrics.losses = self.metrics.losses.saturating_add(1);
+  }
+
+  // everything stored on a node not heard from for longer than max_idle is taken as lost, even if
+  // it's offline and the losses would otherwise only be suspected; returns the bytes moved into
+  // lost_total_size across all such accounts
+  pub fn sweep_stale(&mut self, max_idle: Duration) -> u64 {
+      let stale: Vec<Identity> = self.storage.peek_iter()
+                                     .filter(|&(_, account)| account.stored_total_size > 0 && account.idle_since() > max_idle)
+                                     .map(|(name, _)| name.clone())
+                                     .collect();
+      let mut swept = 0u64;
+      for name in stale.iter() {
+          let lost = match self.storage.get_mut(name) {
+              Some(account) => account.write_off(),
+              None => continue,
+          };
+          self.total_stored = self.total_stored.saturating_sub(lost);
+          self.metrics.losses = self.metrics.losses.saturating_add(1);
+          swept = swept.saturating_add(lost);
+      }
+      swept
+  }
+
+  pub fn metrics(&self) -> PmidManagerMetrics {
+      self.metrics.clone()
+  }
+
+  // returns the bytes actually freed, zero for an unknown name
+  pub fn delete_data(&mut self, name : &Identity, size: u64) -> u64 {
+      let freed = match self.storage.get_mut(name) {
+          Some(value) => value.delete_data(size),
+          None => 0,
+      };
+      self.total_stored = self.total_stored.saturating_sub(freed);
+      freed
+  }
+
+  pub fn remove_account(&mut self, name : &Identity) -> Option<PmidManagerAccount> {
+      let _ = self.reconciled.remove(name);
+      let removed = self.storage.remove(name);
+      if let Some(ref account) = removed {
+          self.total_stored = self.total_stored.saturating_sub(account.stored_total_size);
+      }
+      if let (Some(on_remove), Some(account)) = (self.on_remove.as_ref(), removed.as_ref()) {
+          on_remove(name, account);
+      }
+      removed
+  }
+
+  // called with each account dropped from the database, whether removed, handed on by
+  // retrieve_all_and_reset or evicted to make room for another; accounts which outlive the ttl are
+  // dropped silently
+  pub fn set_on_remove(&mut self, on_remove: Box<dyn Fn(&Identity, &PmidManagerAccount) + Send>) {
+      self.on_remove = Some(on_remove);
+  }
+
+  // moves the account held under from to to, every field kept as it is; false, with nothing changed,
+  // if from isn't held, to already is or to is reserved
+  pub fn rename_account(&mut self, from: &Identity, to: Identity) -> bool {
+      if self.is_reserved(&to) || self.storage.contains_key(&to) {
+          return false;
+      }
+      match self.storage.remove(from) {
+          Some(account) => {
+              let _ = self.storage.insert(to, account);
+              true
+          }
+          None => false,
+      }
+  }
+
+  // the sum of stored_total_size over every account, maintained as the accounts change rather than
+  // by iterating; accounts evicted to make room are subtracted, but those outliving the ttl aren't,
+  // so with a ttl set recompute should be called after anything which may have dropped accounts
+  pub fn total_stored(&self) -> u64 {
+      self.total_stored
+  }
+
+  // rebuilds total_stored from the accounts held
+  pub fn recompute(&mut self) {
+      self.total_stored = self.storage.peek_iter()
+                                      .fold(0u64, |total, (_, account)| total.saturating_add(account.stored_total_size));
+  }
+
+  fn insert_account(&mut self, name: Identity, account: PmidManagerAccount) {
+      // made room for here rather than by the insert, so that the account evicted is known
+      if let Some((evicted, evicted_account)) = self.storage.evict_for(&name) {
+          let _ = self.reconciled.remove(&evicted);
+          self.total_stored = self.total_stored.saturating_sub(evicted_account.stored_total_size);
+          self.metrics.evictions = self.metrics.evictions.saturating_add(1);
+          if let Some(ref on_remove) = self.on_remove {
+              on_remove(&evicted, &evicted_account);
+          }
+      }
+      self.total_stored = self.total_stored.saturating_add(account.stored_total_size);
+      if let Some(replaced) = self.storage.insert(name, account) {
+          self.total_stored = self.total_stored.saturating_sub(replaced.stored_total_size);
+      }
+  }
+
+  // unknown names are skipped
+  pub fn delete_data_batch(&mut self, items: &[(Identity, u64)]) {
+      for &(ref name, size) in items.iter() {
+          let _ = self.delete_data(name, size);
+      }
+  }
+
+  // removes accounts with nothing stored and no history of lost data, returning how many went
+  pub fn prune_empty(&mut self) -> usize {
+      let names: Vec<Identity> = self.storage.peek_iter()
+                                     .filter(|&(_, account)| account.stored_total_size == 0 && account.lost_total_size == 0)
+                                     .map(|(name, _)| name.clone())
+                                     .collect();
+      for name in names.iter() {
+          let _ = self.remove_account(name);
+      }
+      names.len()
+  }
+
+  pub fn handle_account_transfer(&mut self, name: Identity, serialised: &[u8]) -> Result<(), DecodeError> {
+      let account = try!(decode_transfer(serialised));
+      self.accept_transfer(name, account);
+      Ok(())
+  }
+
+  // applies each transfer as handle_account_transfer, so by the same policy, returning how many
+  // were applied; one which fails to decode is logged and skipped
+  pub fn load_transfers<I: IntoIterator<Item = (Identity, Vec<u8>)>>(&mut self, transfers: I) -> usize {
+      let mut applied = 0;
+      for (name, serialised) in transfers {
+          match self.handle_account_transfer(name.clone(), &serialised) {
+              Ok(()) => applied += 1,
+              Err(error) => warn!("PmidManager skipped the transfer of account {}: {}", name_as_hex(&name), error),
+          }
+      }
+      applied
+  }
+
+  // a transferred account is reconciled with the one held: the larger stored size is kept and the
+  // lost sizes summed, as each manager may have recorded different losses. A copy identical to one
+  // already reconciled into the account is the same transfer received again, which is ignored
+  // rather than counting its losses twice; an account not held is taken as it is.
+  fn accept_transfer(&mut self, name: Identity, account: PmidManagerAccount) {
+      let transferred = account.transferred();
+      if let Some(value) = self.storage.get_mut(&name) {
+          let reconciled = self.reconciled.entry(name).or_insert_with(Vec::new);
+          if reconciled.contains(&transferred) {
+              return;
+          }
+          reconciled.push(transferred);
+          let before = value.stored_total_size;
+          value.reconcile(&account);
+          self.total_stored = self.total_stored.saturating_sub(before).saturating_add(value.stored_total_size);
+          return;
+      }
+      let _ = self.reconciled.insert(name.clone(), vec![transferred]);
+      self.insert_account(name, account);
+  }
+
+  // as retrieve_all_and_reset, but with each account bincode encoded, which the receiving manager
+  // must pass to handle_account_transfer_bincode
+  #[cfg(feature = "use-bincode")]
+  pub fn retrieve_all_and_reset_bincode(&mut self, close_group: &Vec<routing::NameType>)
+          -> Vec<generic_sendable_type::GenericSendableType> {
+      let started = Instant::now();
+      let names = self.names_in_group(close_group);
+      let mut sendable_data = Vec::with_capacity(names.len());
+      for name in names {
+          if let Some(account) = self.remove_account(&name) {
+              sendable_data.push(generic_sendable_type::GenericSendableType::new(name, PMID_MANAGER_ACCOUNT_TAG,
+                                                                                 account.to_bincode()));
+          }
+      }
+      self.record_transfer(started.elapsed(), sendable_data.len(), serialised_bytes(&sendable_data));
+      sendable_data
+  }
+
+  #[cfg(feature = "use-bincode")]
+  pub fn handle_account_transfer_bincode(&mut self, name: Identity, serialised: &[u8]) -> Result<(), DecodeError> {
+      let account = try!(PmidManagerAccount::from_bincode(serialised)
+                             .map_err(|error| DecodeError::InvalidAccount(error.to_string())));
+      self.accept_transfer(name, account);
+      Ok(())
+  }
+
+    pub fn retrieve_all_and_reset(&mut self, close_group: &Vec<routing::NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      let started = Instant::now();
+      let names = self.names_in_group(close_group);
+      let mut sendable_data = Vec::with_capacity(names.len());
+      for name in names {
+          let account = match self.remove_account(&name) {
+              Some(account) => account,
+              None => continue,
+          };
+          sendable_data.extend(to_sendable(name, &account));
+      }
+      self.record_transfer(started.elapsed(), sendable_data.len(), serialised_bytes(&sendable_data));
+      sendable_data
+    }
+
+  fn record_transfer(&mut self, duration: Duration, accounts: usize, bytes: u64) {
+      self.metrics.last_transfer_duration = Some(duration);
+      self.metrics.last_transfer_accounts = accounts;
+      self.metrics.last_transfer_bytes = bytes;
+  }
+
+  // as retrieve_all_and_reset, but yielding at most chunk_size accounts at a time; each batch is
+  // only removed and encoded when the iterator reaches it, so any batches not consumed are retained
+  pub fn retrieve_all_and_reset_chunked<'a>(&'a mut self, close_group: &Vec<routing::NameType>, chunk_size: usize)
+          -> impl Iterator<Item = Vec<generic_sendable_type::GenericSendableType>> + 'a {
+      assert!(chunk_size > 0, "chunk_size must be positive");
+      let mut names = self.names_in_group(close_group).into_iter();
+      let (mut duration, mut accounts, mut bytes) = (Duration::new(0, 0), 0, 0);
+      ::std::iter::from_fn(move || {
+          let started = Instant::now();
+          let chunk: Vec<Identity> = names.by_ref().take(chunk_size).collect();
+          if chunk.is_empty() {
+              self.record_transfer(duration, accounts, bytes);
+              return None;
+          }
+          let sendable_data: Vec<generic_sendable_type::GenericSendableType> =
+              chunk.into_iter()
+                   .filter_map(|name| self.remove_account(&name).and_then(|account| to_sendable(name, &account)))
+                   .collect();
+          duration = duration + started.elapsed();
+          accounts += sendable_data.len();
+          bytes += serialised_bytes(&sendable_data);
+          self.record_transfer(duration, accounts, bytes);
+          Some(sendable_data)
+      })
+  }
+
+  // the in-group accounts as a single compressed blob, for ingest_compressed on the receiving manager;
+  // if they can't be encoded the error is returned and the accounts are left in place
+  pub fn retrieve_all_compressed(&mut self, close_group: &Vec<routing::NameType>) -> io::Result<Vec<u8>> {
+      let started = Instant::now();
+      let mut accounts = Vec::new();
+      for name in self.names_in_group(close_group) {
+          if let Some(account) = self.storage.peek(&name) {
+              accounts.push((name.clone(), account.clone()));
+          }
+      }
+      let compressed = try!(compress_accounts(&accounts));
+      for &(ref name, _) in accounts.iter() {
+          let _ = self.remove_account(name);
+      }
+      self.record_transfer(started.elapsed(), accounts.len(), compressed.len() as u64);
+      Ok(compressed)
+  }
+
+  // each account is merged as if received by handle_account_transfer, returning how many there were
+  pub fn ingest_compressed(&mut self, compressed: &[u8]) -> io::Result<usize> {
+      let accounts = try!(decompress_accounts(compressed));
+      let count = accounts.len();
+      for (name, account) in accounts {
+          self.accept_transfer(name, account);
+      }
+      Ok(count)
+  }
+
+  // the payload retrieve_all_and_reset would produce, leaving the accounts in place
+  pub fn snapshot(&self, close_group: &Vec<routing::NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      self.storage.peek_iter()
+                  .filter(|&(name, _)| close_group.iter().find(|a| *a == name).is_some())
+                  .filter_map(|(name, account)| to_sendable(name.clone(), account))
+                  .collect()
+  }
+
+  // only accounts in close_group are handed over, the others stay with us. The cache iterates in
+  // name order, so every retrieve_all_and_reset variant hands accounts over sorted by name, whatever
+  // order they were added or last used in.
+  fn names_in_group(&self, close_group: &Vec<routing::NameType>) -> Vec<Identity> {
+      self.storage.peek_iter()
+                  .map(|(name, _)| name)
+                  .filter(|name| close_group.iter().find(|a| a == name).is_some())
+                  .cloned()
+                  .collect()
+  }
+}
+
+// an 8 byte big endian account count, then the deflated cbor encoding of the accounts
+fn compress_accounts(accounts: &Vec<(Identity, PmidManagerAccount)>) -> io::Result<Vec<u8>> {
+    let count = accounts.len() as u64;
+    let mut compressed: Vec<u8> = (0..8).map(|i| (count >> (56 - 8 * i)) as u8).collect();
+    let mut e = cbor::Encoder::from_memory();
+    if let Err(error) = e.encode(&[accounts]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string()));
+    }
+    {
+        let mut encoder = DeflateEncoder::new(&mut compressed, Compression::default());
+        try!(encoder.write_all(e.as_bytes()));
+        let _ = try!(encoder.finish());
+    }
+    Ok(compressed)
+}
+
+fn decompress_accounts(compressed: &[u8]) -> io::Result<Vec<(Identity, PmidManagerAccount)>> {
+    if compressed.len() < 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "missing account count"));
+    }
+    let count = compressed[..8].iter().fold(0u64, |count, &byte| (count << 8) | byte as u64);
+    let mut serialised = Vec::new();
+    try!(DeflateDecoder::new(&compressed[8..]).read_to_end(&mut serialised));
+    let mut d = cbor::Decoder::from_bytes(serialised);
+    let accounts: Vec<(Identity, PmidManagerAccount)> = match d.decode().next() {
+        Some(Ok(accounts)) => accounts,
+        Some(Err(error)) => return Err(io::Error::new(io::ErrorKind::InvalidData, error.to_string())),
+        None => Vec::new(),
+    };
+    if accounts.len() as u64 != count {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("expected {} accounts, found {}", count, accounts.len())));
+    }
+    Ok(accounts)
+}
+
+// the cbor encoding of an account, as handed over by retrieve_all_and_reset
+fn decode_transfer(serialised: &[u8]) -> Result<PmidManagerAccount, DecodeError> {
+    let mut d = cbor::Decoder::from_bytes(serialised);
+    match d.decode().next() {
+        Some(Ok(account)) => Ok(account),
+        Some(Err(error)) => Err(DecodeError::InvalidAccount(error.to_string())),
+        None => Err(DecodeError::Empty),
+    }
+}
+
+fn name_as_hex(name: &Identity) -> String {
+    name.get_id().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn serialised_bytes(sent: &[generic_sendable_type::GenericSendableType]) -> u64 {
+    sent.iter().map(|sendable| sendable.serialised_contents().len() as u64).sum()
+}
+
+fn to_sendable(name: Identity, account: &PmidManagerAccount) -> Option<generic_sendable_type::GenericSendableType> {
+    encode_sendable(name, PMID_MANAGER_ACCOUNT_TAG, account)
+}
+
+// None if the element fails to encode, which is logged so the caller can carry on with the others
+fn encode_sendable<T: Encodable>(name: Identity, type_tag: u64, element: &T) -> Option<generic_sendable_type::GenericSendableType> {
+    let mut e = cbor::Encoder::from_memory();
+    match e.encode(&[element]) {
+        Ok(()) => Some(generic_sendable_type::GenericSendableType::new(name, type_tag, e.into_bytes())),
+        Err(error) => {
+            error!("PmidManager failed to encode account {} for transfer: {}", name_as_hex(&name), error);
+            None
+        }
+    }
+}
+
+// a PmidManagerDatabase which can be cloned and shared between threads, each call locks the
+// underlying database for its whole duration, retrieve_all_and_reset included
+#[derive(Clone)]
+pub struct SharedPmidManagerDatabase {
+  database : Arc<Mutex<PmidManagerDatabase>>,
+}
+
+impl SharedPmidManagerDatabase {
+  pub fn new(database: PmidManagerDatabase) -> SharedPmidManagerDatabase {
+      SharedPmidManagerDatabase { database: Arc::new(Mutex::new(database)) }
+  }
+
+  pub fn put_data(&self, name : &Identity, size: u64) -> Result<(), PutError> {
+      self.database.lock().unwrap().put_data(name, size)
+  }
+
+  pub fn delete_data(&self, name : &Identity, size: u64) -> u64 {
+      self.database.lock().unwrap().delete_data(name, size)
+  }
+
+  pub fn exist(&self, name : &Identity) -> bool {
+      self.database.lock().unwrap().contains(name)
+  }
+
+  pub fn stats(&self) -> PmidManagerStats {
+      self.database.lock().unwrap().stats()
+  }
+
+  // the lock is only held while the accounts are copied
+  pub fn snapshot_accounts(&self) -> Arc<Vec<(Identity, PmidManagerAccount)>> {
+      self.database.lock().unwrap().snapshot_accounts()
+  }
+
+  pub fn retrieve_all_and_reset(&self, close_group: &Vec<routing::NameType>) -> Vec<generic_sendable_type::GenericSendableType> {
+      self.database.lock().unwrap().retrieve_all_and_reset(close_group)
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  extern crate cbor;
+  extern crate maidsafe_types;
+  extern crate rand;
+  extern crate routing;
+  use super::{AccountDelta, AccountEventKind, AccountStatus, CapacityPolicy, CapacitySummary, OverCommitPolicy,
+              PmidManagerDatabase, PmidManagerAccount, PmidManagerMetrics, PmidManagerStats,
+              SharedPmidManagerDatabase};
+  use pmid_manager::{DecodeError, PutError};
+  use self::routing::types::*;
+
+    #[test]
+    fn exist() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.exist(&name), false);
+        let _ = db.put_data(&name, 1024);
+        assert_eq!(db.exist(&name), true);
+    }
+
+    #[test]
+    fn contains_and_usage() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.contains(&name), false);
+        assert_eq!(db.usage(&name), None);
+        assert_eq!(db.contains(&name), false);
+        assert_eq!(db.stats().account_count, 0);
+
+        assert_eq!(db.put_data(&name, 1024), Ok(()));
+        assert_eq!(db.contains(&name), true);
+        assert_eq!(db.usage(&name), Some((1024, 0, 1073741824)));
+        db.storage.get_mut(&name).unwrap().handle_lost_data(24);
+        assert_eq!(db.usage(&name), Some((1000, 24, 1073741824)));
+    }
+
+    #[test]
+    fn put_data() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name, 0), Ok(()));
+        assert_eq!(db.exist(&name), true);
+        assert_eq!(db.put_data(&name, 1), Ok(()));
+        assert_eq!(db.put_data(&name, 1073741823), Ok(()));
+        assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(db.put_data(&name, 0), Ok(()));
+        assert_eq!(db.put_data(&name, 1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(db.exist(&name), true);
+    }
+
+    #[test]
+    fn delete_data() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.delete_data(&name, 0), 0);
+        assert_eq!(db.exist(&name), false);
+        assert_eq!(db.put_data(&name, 0), Ok(()));
+        assert_eq!(db.exist(&name), true);
+        assert_eq!(db.delete_data(&name, 1), 0);
+        assert_eq!(db.exist(&name), true);
+        assert_eq!(db.put_data(&name, 1073741824), Ok(()));
+        assert!(db.put_data(&name, 1).is_err());
+        assert_eq!(db.delete_data(&name, 1), 1);
+        assert_eq!(db.put_data(&name, 1), Ok(()));
+        assert!(db.put_data(&name, 1).is_err());
+        assert_eq!(db.delete_data(&name, 1073741825), 1073741824);
+        assert_eq!(db.exist(&name), true);
+        assert!(db.put_data(&name, 1073741825).is_err());
+        assert_eq!(db.put_data(&name, 1073741824), Ok(()));
+    }
+
+    #[test]
+    fn remove_account() {
+        let mut db = PmidManagerDatabase::new();
+        let name: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.remove_account(&name), None);
+        assert_eq!(db.put_data(&name, 1024), Ok(()));
+        db.handle_lost_data(&name, 512);
+        let mut expected = PmidManagerAccount::new();
+        assert_eq!(expected.put_data(1024), Ok(()));
+        expected.handle_lost_data(512);
+        assert_eq!(db.remove_account(&name), Some(expected));
+        assert_eq!(db.exist(&name), false);
+        assert_eq!(db.remove_account(&name), None);
+    }
+
+    #[test]
+    fn rename_account() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..4u8).map(|i| routing::NameType([i; 64])).collect();
+        assert_eq!(db.put_data(&names[0], 2048), Ok(()));
+        db.handle_lost_data(&names[0], 512);
+        db.storage.get_mut(&names[0]).unwrap().enable_event_log(4);
+        db.storage.get_mut(&names[0]).unwrap().mark_offline();
+        let account = db.storage.peek(&names[0]).unwrap().clone();
+
+        assert_eq!(db.rename_account(&names[0], names[1].clone()), true);
+        assert_eq!(db.exist(&names[0]), false);
+        assert_eq!(db.storage.peek(&names[1]), Some(&account));
+        assert_eq!(db.total_stored(), 1536);
+
+        // a missing source or an existing destination leaves both accounts as they were
+        assert_eq!(db.rename_account(&names[0], names[2].clone()), false);
+        assert_eq!(db.exist(&names[2]), false);
+        assert_eq!(db.put_data(&names[2], 1024), Ok(()));
+        assert_eq!(db.rename_account(&names[1], names[2].clone()), false);
+        assert_eq!(db.storage.peek(&names[1]), Some(&account));
+        assert_eq!(db.usage(&names[2]), Some((1024, 0, 1073741824)));
+    }
+
+    #[test]
+    fn delete_data_batch() {
+        let mut db = PmidManagerDatabase::new();
+        let name_1: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_2: routing::NameType = routing::test_utils::Random::generate_random();
+        let unknown: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name_1, 8192), Ok(()));
+        assert_eq!(db.put_data(&name_2, 1024), Ok(()));
+
+        db.delete_data_batch(&[(name_1.clone(), 1024),
+                               (unknown.clone(), 1024),
+                               (name_1.clone(), 2048),
+                               (name_2.clone(), 4096),
+                               (name_1.clone(), 1024)]);
+        assert_eq!(db.usage(&name_1), Some((8192 - 4096, 0, 1073741824)));
+        assert_eq!(db.usage(&name_2), Some((0, 0, 1073741824)));
+        assert_eq!(db.contains(&unknown), false);
+        // the refunded space can be used again
+        assert_eq!(db.put_data(&name_1, 1073741824 - 4096), Ok(()));
+    }
+
+    #[test]
+    fn with_offered_space() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.get_offered_space(), 4096);
+        assert_eq!(account.put_data(4000), Ok(()));
+        assert_eq!(account.put_data(97), Err(PutError::NotEnoughSpace { requested: 97, available: 96 }));
+        assert_eq!(account.put_data(96), Ok(()));
+        assert_eq!(account.get_stored_total_size(), 4096);
+
+        let mut account = PmidManagerAccount::with_offered_space(0);
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.get_stored_total_size(), 0);
+
+        let account = PmidManagerAccount::from_storage_path(&::std::env::temp_dir()).unwrap();
+        assert!(account.get_offered_space() > 0);
+        assert_eq!(account.get_stored_total_size(), 0);
+    }
+
+    #[test]
+    fn put_data_errors() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name, 1024), Ok(()));
+        assert_eq!(db.put_data(&name, 1073741824),
+                   Err(PutError::NotEnoughSpace { requested: 1073741824, available: 1073741824 - 1024 }));
+        assert_eq!(db.put_data(&name, ::std::u64::MAX), Err(PutError::Overflow));
+    }
+
+    #[test]
+    fn over_commit_policy() {
+        let mut account = PmidManagerAccount::with_offered_space(1000);
+        assert_eq!(account.put_data_with_policy(1001, OverCommitPolicy::Strict),
+                   Err(PutError::NotEnoughSpace { requested: 1001, available: 1000 }));
+        assert_eq!(account.put_data_with_policy(1000, OverCommitPolicy::Strict), Ok(()));
+        assert_eq!(account.put_data_with_policy(1, OverCommitPolicy::Strict),
+                   Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.put_data_with_policy(1, OverCommitPolicy::Ratio(1.0)),
+                   Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+
+        assert_eq!(account.can_put_with_policy(200, OverCommitPolicy::Ratio(1.2)), true);
+        assert_eq!(account.can_put_with_policy(201, OverCommitPolicy::Ratio(1.2)), false);
+        assert_eq!(account.put_data_with_policy(201, OverCommitPolicy::Ratio(1.2)),
+                   Err(PutError::NotEnoughSpace { requested: 201, available: 200 }));
+        assert_eq!(account.put_data_with_policy(200, OverCommitPolicy::Ratio(1.2)), Ok(()));
+        assert!(account.is_over_committed());
+        assert!(account.put_data_with_policy(1, OverCommitPolicy::Ratio(1.2)).is_err());
+
+        // the policy is applied by the database to every account
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name, 1073741824), Ok(()));
+        assert!(db.put_data(&name, 1).is_err());
+        db.set_over_commit_policy(OverCommitPolicy::Ratio(1.2));
+        assert_eq!(db.can_put(&name, 214748364), true);
+        assert_eq!(db.put_data(&name, 214748364), Ok(()));
+        assert_eq!(db.can_put(&name, 1), false);
+        assert!(db.put_data(&name, 1).is_err());
+        assert_eq!(OverCommitPolicy::Ratio(::std::f64::NAN).limit(1000), 0);
+        assert_eq!(OverCommitPolicy::Ratio(1e30).limit(1000), ::std::u64::MAX);
+    }
+
+    #[test]
+    fn name_validation() {
+        let zero = routing::NameType([0u8; 64]);
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.put_data(&zero, 1024), Ok(()));
+        assert_eq!(db.contains(&zero), true);
+
+        let mut db = PmidManagerDatabase::with_name_validation(true);
+        assert_eq!(db.put_data(&zero, 1024), Err(PutError::InvalidName));
+        assert_eq!(db.can_put(&zero, 1024), false);
+        assert_eq!(db.contains(&zero), false);
+        let reserved = routing::NameType([7u8; 64]);
+        db.reserve_name(reserved.clone());
+        assert_eq!(db.put_data(&reserved, 1024), Err(PutError::InvalidName));
+        assert_eq!(db.metrics().puts_rejected, 2);
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name, 1024), Ok(()));
+    }
+
+    #[test]
+    fn can_put() {
+        let mut db = PmidManagerDatabase::new();
+        let name = routing::test_utils::Random::generate_random();
+        assert_eq!(db.can_put(&name, 1073741825), false);
+        assert_eq!(db.can_put(&name, 1024), true);
+        assert_eq!(db.contains(&name), false);
+        for &size in [1024, 1073741824, ::std::u64::MAX, 1073741824 - 2048, 1024, 1, 0].iter() {
+            let predicted = db.can_put(&name, size);
+            assert_eq!(db.put_data(&name, size).is_ok(), predicted);
+        }
+        assert_eq!(db.usage(&name), Some((1073741824, 0, 1073741824)));
+    }
+
+    #[test]
+    fn put_data_overflow() {
+        let mut account = PmidManagerAccount::new();
+        assert_eq!(account.put_data(::std::u64::MAX),
+                   Err(PutError::NotEnoughSpace { requested: ::std::u64::MAX, available: 1073741824 }));
+        assert_eq!(account.get_stored_total_size(), 0);
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::Overflow));
+        assert_eq!(account.get_stored_total_size(), 1024);
+        let remaining = account.get_offered_space() - 1024;
+        assert_eq!(account.put_data(remaining), Ok(()));
+        assert!(account.put_data(1).is_err());
+        assert_eq!(account.get_stored_total_size(), account.get_offered_space());
+    }
+
+    #[test]
+    fn utilization() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.utilization(), 0.0);
+        assert_eq!(account.is_full(), false);
+        assert_eq!(account.put_data(2048), Ok(()));
+        assert_eq!(account.utilization(), 0.5);
+        assert_eq!(account.is_full(), false);
+        assert_eq!(account.put_data(2048), Ok(()));
+        assert_eq!(account.utilization(), 1.0);
+        assert_eq!(account.is_full(), true);
+
+        let account = PmidManagerAccount::with_offered_space(0);
+        assert_eq!(account.utilization(), 1.0);
+        assert_eq!(account.is_full(), true);
+    }
+
+    #[test]
+    fn max_accounts() {
+        let mut db = PmidManagerDatabase::with_max_accounts(2);
+        let names: Vec<routing::NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+        assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[1], 1024), Ok(()));
+        assert_eq!(db.can_put(&names[2], 1024), false);
+        assert_eq!(db.put_data(&names[2], 1024), Err(PutError::AtCapacity));
+        assert_eq!(db.put_data(&names[2], 0), Err(PutError::AtCapacity));
+        assert_eq!(db.exist(&names[2]), false);
+        assert_eq!(db.put_best_fit(&[names[2].clone(), names[0].clone()], 1024), Some(names[0].clone()));
+
+        // the accounts held are still updated
+        assert_eq!(db.can_put(&names[1], 1024), true);
+        assert_eq!(db.put_data(&names[1], 1024), Ok(()));
+        assert_eq!(db.delete_data(&names[0], 512), 512);
+        assert_eq!(db.usage(&names[0]), Some((1536, 0, 1073741824)));
+        assert_eq!(db.usage(&names[1]), Some((2048, 0, 1073741824)));
+        assert_eq!(db.total_stored(), 3584);
+        assert_eq!(db.metrics().puts_rejected, 2);
+
+        assert!(db.remove_account(&names[1]).is_some());
+        assert_eq!(db.put_data(&names[2], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[3], 1024), Err(PutError::AtCapacity));
+    }
+
+    #[test]
+    fn retrieve_all_and_reset_order() {
+        use self::routing::sendable::Sendable;
+        let names: Vec<routing::NameType> = (1..6u8).map(|i| routing::NameType([i * 37; 64])).collect();
+        let retrieved = |order: &[usize]| {
+            let mut db = PmidManagerDatabase::new();
+            for &i in order.iter() {
+                assert_eq!(db.put_data(&names[i], 1024 * i as u64), Ok(()));
+            }
+            let close_group: Vec<routing::NameType> = order.iter().rev().map(|&i| names[i].clone()).collect();
+            db.retrieve_all_and_reset(&close_group).iter()
+              .map(|element| (element.name(), element.serialised_contents()))
+              .collect::<Vec<_>>()
+        };
+        let forwards = retrieved(&[0, 1, 2, 3, 4]);
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(forwards.iter().map(|&(ref name, _)| name.clone()).collect::<Vec<_>>(), sorted);
+        // the same bytes for the same accounts, however they were added
+        assert_eq!(retrieved(&[4, 3, 2, 1, 0]), forwards);
+        assert_eq!(retrieved(&[2, 0, 4, 1, 3]), forwards);
+    }
+
+    #[test]
+    fn put_data_with_id() {
+        let mut db = PmidManagerDatabase::new();
+        let name: routing::NameType = routing::test_utils::Random::generate_random();
+        db.enable_put_dedup(2);
+        assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+        assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+        assert_eq!(db.usage(&name), Some((1024, 0, 1073741824)));
+        assert_eq!(db.total_stored(), 1024);
+        assert_eq!(db.metrics().puts_ok, 1);
+
+        assert_eq!(db.put_data_with_id(&name, 1073741824, [2u8; 32]), false);
+        assert_eq!(db.delete_data(&name, 1024), 1024);
+        assert_eq!(db.put_data_with_id(&name, 1073741824, [2u8; 32]), false);
+        assert_eq!(db.usage(&name), Some((0, 0, 1073741824)));
+
+        // only the last two ids are remembered
+        assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+        assert_eq!(db.put_data_with_id(&name, 1024, [1u8; 32]), true);
+        assert_eq!(db.usage(&name), Some((2048, 0, 1073741824)));
+
+        db.disable_put_dedup();
+        assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+        assert_eq!(db.put_data_with_id(&name, 1024, [3u8; 32]), true);
+        assert_eq!(db.usage(&name), Some((4096, 0, 1073741824)));
+    }
+
+    #[test]
+    fn put_at_boundary() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert_eq!(account.can_put(3072), true);
+        assert_eq!(account.can_put(3073), false);
+        assert_eq!(account.put_data(3072), Ok(()));
+        assert_eq!(account.get_stored_total_size(), account.get_offered_space());
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(4097), Err(PutError::NotEnoughSpace { requested: 4097, available: 4096 }));
+        assert_eq!(account.put_data(4096), Ok(()));
+        let mut account = PmidManagerAccount::with_offered_space(::std::u64::MAX);
+        assert_eq!(account.put_data(::std::u64::MAX), Ok(()));
+        assert_eq!(account.put_data(1), Err(PutError::Overflow));
+
+        // the same holds against the limit given by a policy
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data_with_policy(6144, OverCommitPolicy::Ratio(1.5)), Ok(()));
+        assert_eq!(account.put_data_with_policy(1, OverCommitPolicy::Ratio(1.5)),
+                   Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+    }
+
+    #[test]
+    fn loss_ratio() {
+        let account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.loss_ratio(), 0.0);
+        assert_eq!(account.is_unreliable(0.25), false);
+        assert_eq!(PmidManagerAccount::from_parts(0, 1024, 4096).loss_ratio(), 0.25);
+        assert_eq!(PmidManagerAccount::from_parts(0, 1024, 4096).is_unreliable(0.25), false);
+        assert_eq!(PmidManagerAccount::from_parts(0, 1025, 4096).is_unreliable(0.25), true);
+        assert_eq!(PmidManagerAccount::from_parts(0, 8192, 4096).loss_ratio(), 2.0);
+        assert_eq!(PmidManagerAccount::from_parts(0, 0, 0).loss_ratio(), 0.0);
+        assert_eq!(PmidManagerAccount::from_parts(0, 1, 0).loss_ratio(), 1.0);
+        assert_eq!(PmidManagerAccount::from_parts(0, 1, 0).is_unreliable(0.25), true);
+
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..7u8).map(|i| routing::NameType([i; 64])).collect();
+        let accounts = [(0, 4096), (512, 4096), (1024, 4096), (2048, 4096), (4096, 1024), (1, 0)];
+        for (name, &(lost, offered)) in names.iter().zip(accounts.iter()) {
+            db.insert_account(name.clone(), PmidManagerAccount::from_parts(0, lost, offered));
+        }
+        assert_eq!(db.unreliable_accounts(0.25), vec![names[3].clone(), names[4].clone(), names[5].clone()]);
+        assert_eq!(db.unreliable_accounts(0.0), names[1..].to_vec());
+        assert_eq!(db.unreliable_accounts(4.0), vec![]);
+    }
+
+    #[test]
+    fn remaining_space() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.remaining_space(), 4096);
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert_eq!(account.remaining_space(), 3072);
+        assert_eq!(account.put_data(3072), Ok(()));
+        assert_eq!(account.remaining_space(), 0);
+        account.set_available_size(2048);
+        assert_eq!(account.is_over_committed(), true);
+        assert_eq!(account.remaining_space(), 0);
+        assert_eq!(PmidManagerAccount::from_parts(::std::u64::MAX, 0, 0).remaining_space(), 0);
+        assert_eq!(PmidManagerAccount::with_offered_space(0).remaining_space(), 0);
+    }
+
+    #[test]
+    fn zero_offered_space() {
+        let mut account = PmidManagerAccount::with_offered_space(0);
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::NotEnoughSpace { requested: ::std::u64::MAX, available: 0 }));
+        assert_eq!(account.put_data_with_policy(1, OverCommitPolicy::Ratio(1.5)),
+                   Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.can_put(1), false);
+        assert_eq!(account.put_data(0), Ok(()));
+        assert_eq!(account.can_put(0), true);
+        assert_eq!(account.get_stored_total_size(), 0);
+        assert_eq!(account.utilization(), 1.0);
+        assert_eq!(account.is_full(), true);
+        assert_eq!(account.to_string(), "0 B stored of 0 B offered (100.0%), 0 B lost");
+
+        // shrunk to nothing while holding data, even a zero size put is rejected
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(1024), Ok(()));
+        account.set_available_size(0);
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.put_data(0), Err(PutError::NotEnoughSpace { requested: 0, available: 0 }));
+        assert_eq!(account.utilization(), 1.0);
+        assert!(account.utilization().is_finite());
+        assert_eq!(account.is_full(), true);
+        assert_eq!(account.is_over_committed(), true);
+        assert_eq!(account.to_string(), "1.00 KiB stored of 0 B offered (100.0%), 0 B lost");
+        assert_eq!(account.delete_data(1024), 1024);
+        assert_eq!(account.put_data(0), Ok(()));
+    }
+
+    #[test]
+    fn set_available_size() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(3072), Ok(()));
+        assert_eq!(account.is_over_committed(), false);
+
+        account.set_available_size(8192);
+        assert_eq!(account.get_offered_space(), 8192);
+        assert_eq!(account.is_over_committed(), false);
+        account.set_available_size(8192);
+        assert_eq!(account.get_offered_space(), 8192);
+        assert_eq!(account.is_over_committed(), false);
+
+        account.set_available_size(2048);
+        assert_eq!(account.get_stored_total_size(), 3072);
+        assert_eq!(account.is_over_committed(), true);
+        assert_eq!(account.is_full(), true);
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        account.delete_data(1024);
+        assert_eq!(account.is_over_committed(), false);
+        account.set_available_size(1024);
+        assert_eq!(account.is_over_committed(), true);
+        account.set_available_size(4096);
+        assert_eq!(account.is_over_committed(), false);
+        assert_eq!(account.put_data(2048), Ok(()));
+    }
+
+    #[test]
+    fn update_account() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(2048), Ok(()));
+        account.mark_offline();
+        account.update_account(512);
+        assert_eq!(account.get_stored_total_size(), 1536);
+        assert_eq!(account.get_lost_total_size(), 512);
+        assert_eq!(account.get_suspected_lost(), 0);
+
+        // stored clamps at zero while lost saturates
+        account.update_account(::std::u64::MAX - 1024);
+        assert_eq!(account.get_stored_total_size(), 0);
+        assert_eq!(account.get_lost_total_size(), ::std::u64::MAX - 512);
+        for _ in 0..3 {
+            account.update_account(::std::u64::MAX);
+        }
+        assert_eq!(account.get_stored_total_size(), 0);
+        assert_eq!(account.get_lost_total_size(), ::std::u64::MAX);
+
+        let mut lossy = PmidManagerAccount::from_parts(1024, ::std::u64::MAX - 1, 4096);
+        lossy.handle_lost_data(1024);
+        assert_eq!(lossy.get_lost_total_size(), ::std::u64::MAX);
+    }
+
+    #[test]
+    fn reserved_headroom() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.get_reserved_headroom(), 0);
+        account.set_reserved_headroom(1024);
+        assert_eq!(account.can_put(3072), true);
+        assert_eq!(account.can_put(3073), false);
+        assert_eq!(account.put_data(2048), Ok(()));
+        assert_eq!(account.put_data(2048), Err(PutError::NotEnoughSpace { requested: 2048, available: 1024 }));
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        assert_eq!(account.can_put_with_policy(1024, OverCommitPolicy::Ratio(1.5)), true);
+
+        // headroom beyond the space offered leaves no room at all
+        account.set_reserved_headroom(8192);
+        assert_eq!(account.put_data(1), Err(PutError::NotEnoughSpace { requested: 1, available: 0 }));
+        account.set_reserved_headroom(0);
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert_eq!(account.is_full(), true);
+
+        account.set_reserved_headroom(512);
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&account]).unwrap();
+        let mut d = cbor::Decoder::from_bytes(e.as_bytes());
+        let decoded: PmidManagerAccount = d.decode().next().unwrap().unwrap();
+        assert_eq!(decoded.get_reserved_headroom(), 0);
+    }
+
+    #[test]
+    fn retry_pending() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.retry_pending(), 0);
+        account.enable_pending_puts(3);
+        assert_eq!(account.put_data(4096), Ok(()));
+        for &size in [1024, 4096, 2048, 512].iter() {
+            assert!(account.put_data(size).is_err());
+        }
+        // only the latest three rejections are kept
+        assert_eq!(account.pending_puts(), vec![4096, 2048, 512]);
+        assert_eq!(account.retry_pending(), 0);
+        assert_eq!(account.pending_puts(), vec![4096, 2048, 512]);
+
+        account.set_available_size(4096 + 2560);
+        assert_eq!(account.retry_pending(), 2);
+        assert_eq!(account.pending_puts(), vec![4096]);
+        assert_eq!(account.get_stored_total_size(), 4096 + 2560);
+
+        account.set_available_size(8192 + 2560);
+        assert_eq!(account.retry_pending(), 1);
+        assert_eq!(account.pending_puts(), Vec::<u64>::new());
+        assert_eq!(account.get_stored_total_size(), 8192 + 2560);
+
+        // an overflowing put is never going to fit, so isn't kept
+        assert_eq!(account.put_data(::std::u64::MAX), Err(PutError::Overflow));
+        assert_eq!(account.pending_puts(), Vec::<u64>::new());
+        account.disable_pending_puts();
+        assert!(account.put_data(1).is_err());
+        assert_eq!(account.pending_puts(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn encode_sendable_failure() {
+        // cbor can't encode a map with non-string keys
+        let mut unencodable = ::std::collections::BTreeMap::new();
+        let _ = unencodable.insert(1u64, 1u64);
+        let name: routing::NameType = routing::test_utils::Random::generate_random();
+        assert!(super::encode_sendable(name.clone(), 0, &unencodable).is_none());
+        let _ = unencodable.remove(&1);
+        assert!(super::encode_sendable(name, 0, &unencodable).is_some());
+    }
+
+    #[test]
+    fn account_ordering() {
+        let empty = PmidManagerAccount::with_offered_space(4096);
+        let mut half = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(half.put_data(2048), Ok(()));
+        let mut full = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(full.put_data(4096), Ok(()));
+        assert!(empty > half && half > full);
+
+        // the same room left on a larger node ranks higher
+        let mut larger = PmidManagerAccount::with_offered_space(8192);
+        assert_eq!(larger.put_data(6144), Ok(()));
+        assert!(larger > half && larger < empty);
+
+        // a node with no room left and one over-committed both have none remaining
+        let mut over_committed = full.clone();
+        over_committed.set_available_size(1024);
+        let zero_offered = PmidManagerAccount::with_offered_space(0);
+        assert!(full > over_committed && over_committed > zero_offered);
+
+        let lossy = PmidManagerAccount::from_parts(2048, 1024, 4096);
+        assert!(half > lossy);
+        assert_eq!(half.cmp(&half.clone()), ::std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn semantically_eq() {
+        let names: Vec<routing::NameType> = (1..5u8).map(|i| routing::NameType([i; 64])).collect();
+        let mut forwards = PmidManagerDatabase::new();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(forwards.put_data(name, 1024 * i as u64), Ok(()));
+        }
+        forwards.handle_lost_data(&names[1], 512);
+        let mut backwards = PmidManagerDatabase::with_name_validation(true);
+        backwards.handle_lost_data(&names[1], 512);
+        for (i, name) in names.iter().enumerate().rev() {
+            assert_eq!(backwards.put_data(name, 1024 * i as u64), Ok(()));
+        }
+        assert!(!forwards.semantically_eq(&backwards));
+        backwards.handle_lost_data(&names[1], 512);
+        assert!(forwards.semantically_eq(&backwards) && backwards.semantically_eq(&forwards));
+
+        backwards.storage.get_mut(&names[0]).unwrap().enable_event_log(4);
+        assert!(forwards.semantically_eq(&backwards));
+        assert_eq!(backwards.put_data(&names[0], 1), Ok(()));
+        assert!(!forwards.semantically_eq(&backwards));
+        assert_eq!(forwards.put_data(&names[0], 1), Ok(()));
+        assert_eq!(forwards.put_data(&routing::NameType([9u8; 64]), 1), Ok(()));
+        assert!(!forwards.semantically_eq(&backwards) && !backwards.semantically_eq(&forwards));
+    }
+
+    #[test]
+    fn diff() {
+        let mut ours = PmidManagerDatabase::new();
+        let mut theirs = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..7u8).map(|i| routing::NameType([i; 64])).collect();
+        // names 0 and 1 agree, 2 and 3 differ, 4 is only ours and 5 only theirs
+        for name in names[..4].iter() {
+            assert_eq!(ours.put_data(name, 1024), Ok(()));
+            assert_eq!(theirs.put_data(name, 1024), Ok(()));
+        }
+        assert_eq!(ours.put_data(&names[2], 1024), Ok(()));
+        theirs.handle_lost_data(&names[3], 512);
+        theirs.storage.get_mut(&names[3]).unwrap().set_available_size(4096);
+        assert_eq!(ours.put_data(&names[4], 1024), Ok(()));
+        assert_eq!(theirs.put_data(&names[5], 1024), Ok(()));
+
+        let diff = ours.diff(&theirs);
+        assert_eq!(diff.only_in_self, vec![names[4].clone()]);
+        assert_eq!(diff.only_in_other, vec![names[5].clone()]);
+        assert_eq!(diff.differing,
+                   vec![(names[2].clone(), AccountDelta { stored_total_size: Some((2048, 1024)),
+                                                          lost_total_size: None,
+                                                          offered_space: None }),
+                        (names[3].clone(), AccountDelta { stored_total_size: Some((1024, 512)),
+                                                          lost_total_size: Some((0, 512)),
+                                                          offered_space: Some((1073741824, 4096)) })]);
+        assert!(!diff.is_empty());
+
+        let reversed = theirs.diff(&ours);
+        assert_eq!(reversed.only_in_self, vec![names[5].clone()]);
+        assert_eq!(reversed.differing[0].1.stored_total_size, Some((1024, 2048)));
+        assert!(ours.diff(&ours).is_empty());
+    }
+
+    #[test]
+    fn least_loaded() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..6u8).map(|i| routing::NameType([i; 64])).collect();
+        for (name, &stored) in names.iter().zip([1024, 0, 1073741824, 0, 4096].iter()) {
+            assert_eq!(db.put_data(name, stored), Ok(()));
+        }
+        assert_eq!(db.least_loaded(0), Vec::<routing::NameType>::new());
+        // the two empty accounts tie and come in name order, the full one is last
+        assert_eq!(db.least_loaded(2), vec![names[1].clone(), names[3].clone()]);
+        assert_eq!(db.least_loaded(10), vec![names[1].clone(), names[3].clone(), names[0].clone(),
+                                             names[4].clone(), names[2].clone()]);
+    }
+
+    #[test]
+    fn put_best_fit() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..5u8).map(|i| routing::NameType([i; 64])).collect();
+        // leaves 4096, 1024, 2048 and 1024 bytes free respectively
+        for (name, &free) in names.iter().zip([4096u64, 1024, 2048, 1024].iter()) {
+            assert_eq!(db.put_data(name, 1073741824 - free), Ok(()));
+        }
+        assert_eq!(db.put_best_fit(&names, 1500), Some(names[2].clone()));
+        assert_eq!(db.usage(&names[2]).unwrap().0, 1073741824 - 548);
+        // the two with 1024 free tie, so the first listed is used
+        assert_eq!(db.put_best_fit(&names, 1000), Some(names[1].clone()));
+        assert_eq!(db.put_best_fit(&[names[3].clone(), names[1].clone()], 24), Some(names[1].clone()));
+        assert_eq!(db.put_best_fit(&names, 4097), None);
+        assert_eq!(db.put_best_fit(&[], 1), None);
+        assert_eq!(db.usage(&names[0]).unwrap().0, 1073741824 - 4096);
+
+        let unknown = routing::NameType([9u8; 64]);
+        assert_eq!(db.put_best_fit(&[unknown.clone()], 4097), Some(unknown.clone()));
+        assert_eq!(db.usage(&unknown).unwrap().0, 4097);
+    }
+
+    #[test]
+    fn sweep_stale() {
+        let an_hour_ago = ::std::time::SystemTime::now() - ::std::time::Duration::from_secs(3600);
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert!(account.idle_since() < ::std::time::Duration::from_secs(60));
+        account.last_modified = an_hour_ago;
+        account.handle_lost_data(0);
+        assert!(account.idle_since() >= ::std::time::Duration::from_secs(3600));
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert!(account.idle_since() < ::std::time::Duration::from_secs(60));
+
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..5u8).map(|i| routing::NameType([i; 64])).collect();
+        for name in names.iter() {
+            assert_eq!(db.put_data(name, 2048), Ok(()));
+        }
+        db.handle_lost_data(&names[1], 512);
+        db.storage.get_mut(&names[2]).unwrap().mark_offline();
+        db.handle_lost_data(&names[2], 1024);
+        for name in names[1..3].iter() {
+            db.storage.get_mut(name).unwrap().last_modified = an_hour_ago;
+        }
+        let losses = db.metrics().losses;
+
+        assert_eq!(db.sweep_stale(::std::time::Duration::from_secs(60)), 1536 + 2048);
+        assert_eq!(db.usage(&names[0]), Some((2048, 0, 1073741824)));
+        assert_eq!(db.usage(&names[1]), Some((0, 2048, 1073741824)));
+        assert_eq!(db.usage(&names[2]), Some((0, 2048, 1073741824)));
+        assert_eq!(db.storage.peek(&names[2]).unwrap().get_suspected_lost(), 0);
+        assert_eq!(db.usage(&names[3]), Some((2048, 0, 1073741824)));
+        assert_eq!(db.metrics().losses, losses + 2);
+        assert_eq!(db.total_stored(), 4096);
+        // nothing is left to sweep from them
+        assert_eq!(db.sweep_stale(::std::time::Duration::from_secs(60)), 0);
+        assert_eq!(db.sweep_stale(::std::time::Duration::from_secs(7200)), 0);
+    }
+
+    #[test]
+    fn offline_losses() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(4096), Ok(()));
+        account.mark_offline();
+        let since = match account.get_status() {
+            AccountStatus::Offline { since } => since,
+            AccountStatus::Online => panic!("Unexpected"),
+        };
+        account.handle_lost_data(1024);
+        account.handle_lost_data(512);
+        account.mark_offline();
+        assert_eq!(account.get_status(), AccountStatus::Offline { since: since });
+        assert_eq!(account.get_suspected_lost(), 1536);
+        assert_eq!(account.get_lost_total_size(), 0);
+        assert_eq!(account.get_stored_total_size(), 4096);
+
+        // not offline for long enough yet
+        assert_eq!(account.reconcile_suspected_lost(::std::time::Duration::from_secs(3600)), 0);
+        assert_eq!(account.get_suspected_lost(), 1536);
+        // the node coming back in time means nothing was lost
+        account.mark_online();
+        assert_eq!(account.get_status(), AccountStatus::Online);
+        assert_eq!(account.get_suspected_lost(), 0);
+        assert_eq!(account.get_lost_total_size(), 0);
+
+        account.mark_offline();
+        account.handle_lost_data(1024);
+        ::std::thread::sleep(::std::time::Duration::from_millis(20));
+        assert_eq!(account.reconcile_suspected_lost(::std::time::Duration::from_millis(10)), 1024);
+        assert_eq!(account.get_suspected_lost(), 0);
+        assert_eq!(account.get_lost_total_size(), 1024);
+        assert_eq!(account.get_stored_total_size(), 3072);
+        assert_eq!(account.reconcile_suspected_lost(::std::time::Duration::from_millis(10)), 0);
+
+        // once online, losses are committed straight away again
+        account.mark_online();
+        account.handle_lost_data(1024);
+        assert_eq!(account.get_lost_total_size(), 2048);
+        assert_eq!(account.reconcile_suspected_lost(::std::time::Duration::new(0, 0)), 0);
+    }
+
+    #[test]
+    fn recent_events() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(1024), Ok(()));
+        assert!(account.recent_events().is_empty());
+
+        account.enable_event_log(3);
+        assert_eq!(account.put_data(2048), Ok(()));
+        account.delete_data(512);
+        account.handle_lost_data(256);
+        {
+            let kinds: Vec<(AccountEventKind, u64)> =
+                account.recent_events().iter().map(|event| (event.kind, event.size)).collect();
+            assert_eq!(kinds, vec![(AccountEventKind::Put, 2048), (AccountEventKind::Delete, 512),
+                                   (AccountEventKind::Loss, 256)]);
+        }
+
+        // a rejected put isn't recorded, and the oldest event makes way once the log is full
+        assert!(account.put_data(4096).is_err());
+        assert_eq!(account.recover_lost_data(1024), Ok(()));
+        let kinds: Vec<(AccountEventKind, u64)> =
+            account.recent_events().iter().map(|event| (event.kind, event.size)).collect();
+        assert_eq!(kinds, vec![(AccountEventKind::Delete, 512), (AccountEventKind::Loss, 256),
+                               (AccountEventKind::Recover, 256)]);
+        let events = account.recent_events();
+        assert!(events[0].time <= events[1].time && events[1].time <= events[2].time);
+
+        // the log isn't sent on with the account
+        let decoded = PmidManagerAccount::from_json(&account.to_json()).unwrap();
+        assert!(decoded.recent_events().is_empty());
+        account.disable_event_log();
+        assert_eq!(decoded, account);
+    }
+
+    #[test]
+    fn recover_lost_data() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(2048), Ok(()));
+        account.handle_lost_data(1024);
+        assert_eq!(account.recover_lost_data(512), Ok(()));
+        assert_eq!(account.get_stored_total_size(), 1024 + 512);
+        assert_eq!(account.get_lost_total_size(), 512);
+
+        // recovering more than was lost only recovers the lost amount
+        assert_eq!(account.recover_lost_data(4096), Ok(()));
+        assert_eq!(account.get_stored_total_size(), 2048);
+        assert_eq!(account.get_lost_total_size(), 0);
+        assert_eq!(account.recover_lost_data(1024), Ok(()));
+        assert_eq!(account.get_stored_total_size(), 2048);
+
+        // recovery is rejected when the space has since been used for other data
+        account.handle_lost_data(1024);
+        assert_eq!(account.put_data(3072), Ok(()));
+        assert_eq!(account.recover_lost_data(1024),
+                   Err(PutError::NotEnoughSpace { requested: 1024, available: 0 }));
+        assert_eq!(account.get_stored_total_size(), 4096);
+        assert_eq!(account.get_lost_total_size(), 1024);
+    }
+
+    #[test]
+    fn prune_empty() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.prune_empty(), 0);
+        let names: Vec<routing::NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+        assert_eq!(db.put_data(&names[0], 0), Ok(()));
+        assert_eq!(db.put_data(&names[1], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[2], 1024), Ok(()));
+        db.delete_data(&names[2], 1024);
+        // lost data history keeps an otherwise empty account
+        assert_eq!(db.put_data(&names[3], 1024), Ok(()));
+        db.storage.get_mut(&names[3]).unwrap().handle_lost_data(1024);
+
+        assert_eq!(db.prune_empty(), 2);
+        assert_eq!(db.exist(&names[0]), false);
+        assert_eq!(db.exist(&names[1]), true);
+        assert_eq!(db.exist(&names[2]), false);
+        assert_eq!(db.exist(&names[3]), true);
+        assert_eq!(db.prune_empty(), 0);
+    }
+
+    #[test]
+    fn shared_database() {
+        let shared = SharedPmidManagerDatabase::new(PmidManagerDatabase::new());
+        let names: Vec<routing::NameType> = (0..8).map(|_| routing::test_utils::Random::generate_random()).collect();
+        let writers: Vec<_> = names.iter().cloned().map(|name| {
+            let shared = shared.clone();
+            ::std::thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(shared.put_data(&name, 1024), Ok(()));
+                }
+                shared.delete_data(&name, 1024 * 50);
+            })
+        }).collect();
+        let reader = {
+            let shared = shared.clone();
+            ::std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let stats = shared.stats();
+                    assert!(stats.account_count <= 8);
+                    assert!(stats.total_stored <= 8 * 1024 * 100);
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        let stats = shared.stats();
+        assert_eq!(stats.account_count, names.len());
+        assert_eq!(stats.total_stored, names.len() as u64 * 1024 * 50);
+        assert_eq!(stats.total_lost, 0);
+        for name in names.iter() {
+            assert_eq!(shared.exist(name), true);
+        }
+
+        let sendable = shared.retrieve_all_and_reset(&names);
+        assert_eq!(sendable.len(), names.len());
+        assert_eq!(shared.stats().account_count, 0);
+    }
+
+    #[test]
+    fn transfer_metrics() {
+        use self::routing::sendable::Sendable;
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (0..100).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(db.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+        }
+        assert_eq!(db.metrics().last_transfer_duration, None);
+
+        let sendable = db.retrieve_all_and_reset(&names[..60].to_vec());
+        let metrics = db.metrics();
+        assert!(metrics.last_transfer_duration.is_some());
+        assert_eq!(metrics.last_transfer_accounts, 60);
+        assert_eq!(metrics.last_transfer_bytes,
+                   sendable.iter().map(|element| element.serialised_contents().len() as u64).sum::<u64>());
+        assert!(metrics.last_transfer_bytes > 0);
+
+        // a transfer of nothing replaces the figures of the last
+        assert!(db.retrieve_all_and_reset(&names[..60].to_vec()).is_empty());
+        assert!(db.metrics().last_transfer_duration.is_some());
+        assert_eq!(db.metrics().last_transfer_accounts, 0);
+        assert_eq!(db.metrics().last_transfer_bytes, 0);
+        // the chunks taken so far are totalled
+        let mut chunks = db.retrieve_all_and_reset_chunked(&names[60..90].to_vec(), 8);
+        let first = chunks.next().unwrap();
+        let second = chunks.next().unwrap();
+        drop(chunks);
+        assert_eq!(db.metrics().last_transfer_accounts, 16);
+        assert_eq!(db.metrics().last_transfer_bytes,
+                   first.iter().chain(second.iter()).map(|element| element.serialised_contents().len() as u64).sum::<u64>());
+        assert_eq!(db.retrieve_all_and_reset_chunked(&names[60..90].to_vec(), 8).count(), 2);
+        assert_eq!(db.metrics().last_transfer_accounts, 14);
+
+        let compressed = db.retrieve_all_compressed(&names[90..].to_vec()).unwrap();
+        assert_eq!(db.metrics().last_transfer_accounts, 10);
+        assert_eq!(db.metrics().last_transfer_bytes, compressed.len() as u64);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.metrics(), PmidManagerMetrics { puts_ok: 0, puts_rejected: 0, losses: 0, evictions: 0,
+                                                      last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 });
+        let name_1: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_2: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+        assert_eq!(db.put_data(&name_1, 1073741824 - 1024), Ok(()));
+        assert!(db.put_data(&name_1, 1).is_err());
+        assert_eq!(db.put_data(&name_2, 1024), Ok(()));
+        assert!(db.put_data(&name_2, ::std::u64::MAX).is_err());
+        db.handle_lost_data(&name_1, 512);
+        db.handle_lost_data(&name_2, 512);
+        // losses against unknown accounts aren't recorded
+        db.handle_lost_data(&routing::test_utils::Random::generate_random(), 512);
+
+        assert_eq!(db.metrics(), PmidManagerMetrics { puts_ok: 3, puts_rejected: 2, losses: 2, evictions: 0,
+                                                      last_transfer_duration: None, last_transfer_accounts: 0, last_transfer_bytes: 0 });
+        assert_eq!(db.usage(&name_2), Some((512, 512, 1073741824)));
+    }
+
+    #[test]
+    fn snapshot() {
+        use self::routing::sendable::Sendable;
+        let mut db = PmidManagerDatabase::new();
+        let name_1: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_2: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+        assert_eq!(db.put_data(&name_2, 2048), Ok(()));
+
+        let snapshot = db.snapshot(&vec![name_1.clone()]);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(db.exist(&name_1), true);
+        assert_eq!(db.exist(&name_2), true);
+        assert_eq!(db.usage(&name_1), Some((1024, 0, 1073741824)));
+        assert_eq!(db.snapshot(&vec![name_1.clone(), name_2.clone()]).len(), 2);
+
+        let sendable = db.retrieve_all_and_reset(&vec![name_1.clone()]);
+        assert_eq!(sendable.len(), 1);
+        assert_eq!(snapshot[0].name(), sendable[0].name());
+        assert_eq!(snapshot[0].type_tag(), sendable[0].type_tag());
+        assert_eq!(snapshot[0].serialised_contents(), sendable[0].serialised_contents());
+        assert!(db.snapshot(&vec![name_1.clone()]).is_empty());
+    }
+
+    #[test]
+    fn compressed_transfer() {
+        use self::routing::sendable::Sendable;
+        let mut db = PmidManagerDatabase::new();
+        let mut copy = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (0..1000).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(db.put_data(name, 1024 * (i as u64 % 8)), Ok(()));
+            assert_eq!(copy.put_data(name, 1024 * (i as u64 % 8)), Ok(()));
+        }
+        let out_of_group: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&out_of_group, 1024), Ok(()));
+
+        // each element also carries a 64 byte name and an 8 byte type tag
+        let blobs_size = copy.retrieve_all_and_reset(&names).iter()
+                                                      .fold(0, |total, element| total + 64 + 8 + element.serialised_contents().len());
+        let compressed = db.retrieve_all_compressed(&names).unwrap();
+        assert!(compressed.len() < blobs_size);
+        assert_eq!(db.stats().account_count, 1);
+        assert_eq!(db.exist(&out_of_group), true);
+
+        let mut receiver = PmidManagerDatabase::new();
+        assert_eq!(receiver.ingest_compressed(&compressed).unwrap(), names.len());
+        assert_eq!(receiver.stats().account_count, names.len());
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(receiver.usage(name), Some((1024 * (i as u64 % 8), 0, 1073741824)));
+        }
+
+        // a count which doesn't match the accounts, or a truncated blob, is rejected
+        let mut corrupted = compressed.clone();
+        corrupted[7] ^= 1;
+        assert_eq!(receiver.ingest_compressed(&corrupted).unwrap_err().kind(), ::std::io::ErrorKind::InvalidData);
+        assert!(receiver.ingest_compressed(&compressed[..compressed.len() / 2]).is_err());
+        assert!(receiver.ingest_compressed(&compressed[..4]).is_err());
+    }
+
+    #[test]
+    fn retrieve_all_and_reset_retains_out_of_group() {
+        use self::routing::sendable::Sendable;
+        let mut db = PmidManagerDatabase::new();
+        let name_1: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_2: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_3: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+        assert_eq!(db.put_data(&name_2, 1024), Ok(()));
+        assert_eq!(db.put_data(&name_3, 1024), Ok(()));
+
+        let sendable = db.retrieve_all_and_reset(&vec![name_2.clone()]);
+        assert_eq!(sendable.len(), 1);
+        assert_eq!(sendable[0].name(), name_2);
+        assert_eq!(sendable[0].type_tag(), ::pmid_manager::PMID_MANAGER_ACCOUNT_TAG);
+        assert_eq!(db.exist(&name_1), true);
+        assert_eq!(db.exist(&name_2), false);
+        assert_eq!(db.exist(&name_3), true);
+    }
+
+    #[test]
+    fn load_transfers() {
+        use self::routing::sendable::Sendable;
+        let mut sender = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..5u8).map(|i| routing::NameType([i; 64])).collect();
+        for name in names.iter() {
+            assert_eq!(sender.put_data(name, 2048), Ok(()));
+        }
+        sender.handle_lost_data(&names[3], 256);
+        let mut transfers: Vec<(routing::NameType, Vec<u8>)> = sender.retrieve_all_and_reset(&names).iter()
+            .map(|element| (element.name(), element.serialised_contents()))
+            .collect();
+        transfers.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        // one truncated, one not cbor at all
+        let length = transfers[1].1.len();
+        transfers[1].1.truncate(length / 2);
+        transfers[2].1 = vec![0xff; 8];
+
+        let mut receiver = PmidManagerDatabase::new();
+        assert_eq!(receiver.put_data(&names[3], 1024), Ok(()));
+        receiver.handle_lost_data(&names[3], 512);
+        let replayed = transfers[3].clone();
+        assert_eq!(receiver.load_transfers(transfers), 2);
+        assert_eq!(receiver.usage(&names[0]), Some((2048, 0, 1073741824)));
+        assert_eq!(receiver.contains(&names[1]), false);
+        assert_eq!(receiver.contains(&names[2]), false);
+        // reconciled with the account already held, as handle_account_transfer: the larger stored
+        // size and the losses of both
+        assert_eq!(receiver.usage(&names[3]), Some((2048 - 256, 512 + 256, 1073741824)));
+        // a replayed transfer doesn't count its losses again
+        assert_eq!(receiver.load_transfers(vec![replayed.clone()]), 1);
+        assert_eq!(receiver.handle_account_transfer(replayed.0.clone(), &replayed.1), Ok(()));
+        assert_eq!(receiver.usage(&names[3]), Some((2048 - 256, 512 + 256, 1073741824)));
+        assert_eq!(receiver.total_stored(), 2048 + 2048 - 256);
+        // but once the account has been handed on, receiving it again takes it as it is
+        let _ = receiver.remove_account(&names[3]);
+        assert_eq!(receiver.handle_account_transfer(replayed.0, &replayed.1), Ok(()));
+        assert_eq!(receiver.usage(&names[3]), Some((2048 - 256, 256, 1073741824)));
+        assert_eq!(receiver.load_transfers(Vec::new()), 0);
+    }
+
+    #[test]
+    fn account_reconcile() {
+        let mut account = PmidManagerAccount::new();
+        let mut other = PmidManagerAccount::new();
+        assert_eq!(account.put_data(1024), Ok(()));
+        account.handle_lost_data(512);
+        assert_eq!(other.put_data(4096), Ok(()));
+        other.handle_lost_data(256);
+
+        account.reconcile(&other);
+        assert_eq!(account.get_stored_total_size(), 4096 - 256);
+        assert_eq!(account.get_lost_total_size(), 512 + 256);
+    }
+
+    #[test]
+    fn retrieve_all_and_reset_chunked() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (0..2500).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in names.iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+        let out_of_group: Vec<routing::NameType> = (0..10).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in out_of_group.iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+
+        let sizes: Vec<usize> = db.retrieve_all_and_reset_chunked(&names, 1000).map(|batch| batch.len()).collect();
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+        assert_eq!(db.stats().account_count, out_of_group.len());
+        assert!(out_of_group.iter().all(|name| db.contains(name)));
+
+        // batches which aren't consumed stay in the database
+        let group: Vec<routing::NameType> = out_of_group[..5].to_vec();
+        assert_eq!(db.retrieve_all_and_reset_chunked(&group, 2).next().map(|batch| batch.len()), Some(2));
+        assert_eq!(db.stats().account_count, out_of_group.len() - 2);
+    }
+
+    #[test]
+    fn handle_account_transfer() {
+        use self::routing::sendable::Sendable;
+        let mut sender = PmidManagerDatabase::new();
+        let mut receiver = PmidManagerDatabase::new();
+        let name_1: routing::NameType = routing::test_utils::Random::generate_random();
+        let name_2: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(sender.put_data(&name_1, 1024), Ok(()));
+        assert_eq!(sender.put_data(&name_2, 4096), Ok(()));
+        assert_eq!(receiver.put_data(&name_2, 2048), Ok(()));
+
+        let sendable = sender.retrieve_all_and_reset(&vec![name_1.clone(), name_2.clone()]);
+        assert_eq!(sendable.len(), 2);
+        for element in sendable.iter() {
+            assert_eq!(receiver.handle_account_transfer(element.name(), &element.serialised_contents()), Ok(()));
+        }
+        assert_eq!(receiver.exist(&name_1), true);
+        assert_eq!(receiver.exist(&name_2), true);
+
+        // the larger stored size wins, so a put of all the remaining space from 4096 succeeds
+        assert!(receiver.put_data(&name_2, 1073741824 - 4095).is_err());
+        assert_eq!(receiver.put_data(&name_2, 1073741824 - 4096), Ok(()));
+        assert!(receiver.put_data(&name_1, 1073741824 - 1023).is_err());
+        assert_eq!(receiver.put_data(&name_1, 1073741824 - 1024), Ok(()));
+
+        // undecodable payloads are rejected
+        let name_3: routing::NameType = routing::test_utils::Random::generate_random();
+        match receiver.handle_account_transfer(name_3.clone(), &[0u8, 1, 2, 3]) {
+            Err(DecodeError::InvalidAccount(_)) => (),
+            result => panic!("Unexpected {:?}", result),
+        }
+        assert_eq!(receiver.handle_account_transfer(name_3.clone(), &[]), Err(DecodeError::Empty));
+        assert_eq!(receiver.exist(&name_3), false);
+    }
+
+    #[test]
+    fn account_merge() {
+        let mut first = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(first.put_data(3072), Ok(()));
+        first.handle_lost_data(1024);
+        let mut second = PmidManagerAccount::with_offered_space(8192);
+        assert_eq!(second.put_data(1024), Ok(()));
+        second.handle_lost_data(512);
+        let mut third = PmidManagerAccount::with_offered_space(2048);
+        assert_eq!(third.put_data(2048), Ok(()));
+        third.handle_lost_data(2048);
+
+        let mut merged = first.clone();
+        merged.merge(&second);
+        assert_eq!(merged.get_stored_total_size(), 2048);
+        assert_eq!(merged.get_lost_total_size(), 1024);
+        assert_eq!(merged.get_offered_space(), 8192);
+        merged.merge(&third);
+        assert_eq!(merged.get_stored_total_size(), 2048);
+        assert_eq!(merged.get_lost_total_size(), 2048);
+        assert_eq!(merged.get_offered_space(), 8192);
+        let before = merged.clone();
+        merged.merge(&before);
+        assert_eq!(merged, before);
+
+        let accounts = vec![first, second, third];
+        let orders = vec![[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+        for order in orders.iter() {
+            let mut other_order = accounts[order[0]].clone();
+            other_order.merge(&accounts[order[1]]);
+            other_order.merge(&accounts[order[2]]);
+            assert_eq!(other_order, merged);
+        }
+    }
+
+    #[test]
+    fn eviction() {
+        use self::routing::sendable::Sendable;
+        let mut db = PmidManagerDatabase::with_capacity(3, ::std::time::Duration::from_secs(3600));
+        let names: Vec<routing::NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in names.iter().take(3) {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+        assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[3], 1024), Ok(()));
+        // names[1] is now the least recently used
+        assert_eq!(db.exist(&names[0]), true);
+        assert_eq!(db.exist(&names[1]), false);
+        assert_eq!(db.exist(&names[2]), true);
+        assert_eq!(db.exist(&names[3]), true);
+
+        let sendable = db.retrieve_all_and_reset(&vec![names[0].clone(), names[1].clone()]);
+        assert_eq!(sendable.len(), 1);
+        assert_eq!(sendable[0].name(), names[0]);
+        assert_eq!(db.exist(&names[0]), false);
+        assert_eq!(db.exist(&names[2]), true);
+        assert_eq!(db.exist(&names[3]), true);
+
+        let mut db = PmidManagerDatabase::with_capacity(10, ::std::time::Duration::from_millis(10));
+        assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+        ::std::thread::sleep(::std::time::Duration::from_millis(20));
+        assert_eq!(db.exist(&names[0]), false);
+        assert!(db.retrieve_all_and_reset(&vec![names[0].clone()]).is_empty());
+    }
+
+    #[test]
+    fn eviction_accounting() {
+        let mut db = PmidManagerDatabase::with_capacity(3, ::std::time::Duration::from_secs(3600));
+        let names: Vec<routing::NameType> = (0..5).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for (i, name) in names.iter().take(3).enumerate() {
+            assert_eq!(db.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+        }
+        assert_eq!(db.total_stored(), 6144);
+
+        // names[0] is the least recently used, and goes to make room
+        assert_eq!(db.put_data(&names[3], 4096), Ok(()));
+        assert_eq!(db.exist(&names[0]), false);
+        assert_eq!(db.metrics().evictions, 1);
+        assert_eq!(db.total_stored(), 2048 + 3072 + 4096);
+
+        // a rejected put still opens an account for the new name, so evicts all the same
+        assert!(db.put_data(&names[4], ::std::u64::MAX).is_err());
+        assert_eq!(db.exist(&names[1]), false);
+        assert_eq!(db.metrics().evictions, 2);
+        assert_eq!(db.total_stored(), 3072 + 4096);
+        let total = db.total_stored();
+        db.recompute();
+        assert_eq!(db.total_stored(), total);
+
+        // puts to an account already held evict nothing
+        assert_eq!(db.put_data(&names[2], 1024), Ok(()));
+        assert_eq!(db.metrics().evictions, 2);
+    }
+
+    #[test]
+    fn on_remove() {
+        use std::sync::{Arc, Mutex};
+        let mut db = PmidManagerDatabase::with_capacity(2, ::std::time::Duration::from_secs(3600));
+        let removed: Arc<Mutex<Vec<(routing::NameType, u64)>>> = Arc::new(Mutex::new(Vec::new()));
+        {
+            let removed = removed.clone();
+            db.set_on_remove(Box::new(move |name: &routing::NameType, account: &PmidManagerAccount| {
+                removed.lock().unwrap().push((name.clone(), account.get_stored_total_size()));
+            }));
+        }
+        let names: Vec<routing::NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+        assert_eq!(db.remove_account(&names[0]), None);
+        assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+        assert!(db.remove_account(&names[0]).is_some());
+        assert_eq!(*removed.lock().unwrap(), vec![(names[0].clone(), 1024)]);
+
+        // evicted to make room
+        assert_eq!(db.put_data(&names[1], 2048), Ok(()));
+        assert_eq!(db.put_data(&names[2], 3072), Ok(()));
+        assert_eq!(db.put_data(&names[1], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[3], 4096), Ok(()));
+        assert_eq!(db.exist(&names[2]), false);
+        assert_eq!(removed.lock().unwrap()[1], (names[2].clone(), 3072));
+
+        let _ = db.retrieve_all_and_reset(&vec![names[1].clone()]);
+        assert_eq!(removed.lock().unwrap()[2], (names[1].clone(), 3072));
+        assert_eq!(removed.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn iter() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.iter().count(), 0);
+        let names: Vec<routing::NameType> = (0..5).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(db.put_data(name, 1024 * i as u64), Ok(()));
+        }
+        let accounts: ::std::collections::HashMap<routing::NameType, PmidManagerAccount> =
+            db.iter().map(|(name, account)| (name.clone(), account.clone())).collect();
+        assert_eq!(accounts.len(), names.len());
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(accounts[name].get_stored_total_size(), 1024 * i as u64);
+        }
+        let mut identities = db.identities();
+        identities.sort();
+        let mut expected = names.clone();
+        expected.sort();
+        assert_eq!(identities, expected);
+        assert_eq!(db.stats().account_count, names.len());
+    }
+
+    #[test]
+    fn over_committed_accounts() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..6u8).map(|i| routing::NameType([i; 64])).collect();
+        for name in names.iter() {
+            assert_eq!(db.put_data(name, 4096), Ok(()));
+        }
+        assert_eq!(db.over_committed_accounts(), vec![]);
+        // under, at, just over, well over and with nothing offered
+        for (name, &offered) in names.iter().zip([8192u64, 4096, 4095, 1024, 0].iter()) {
+            db.storage.get_mut(name).unwrap().set_available_size(offered);
+        }
+        assert_eq!(db.over_committed_accounts(), vec![(names[2].clone(), 1), (names[3].clone(), 3072),
+                                                      (names[4].clone(), 4096)]);
+        assert_eq!(db.delete_data(&names[3], 3072), 3072);
+        assert_eq!(db.over_committed_accounts(), vec![(names[2].clone(), 1), (names[4].clone(), 4096)]);
+    }
+
+    #[test]
+    fn accounts_within() {
+        let mut db = PmidManagerDatabase::new();
+        for &byte in [0x01u8, 0x02, 0x04, 0x08, 0x10].iter() {
+            assert_eq!(db.put_data(&routing::NameType([byte; 64]), byte as u64 * 1024), Ok(()));
+        }
+        // XOR distances from the target are 0x02, 0x01, 0x07, 0x0b and 0x13 in each byte
+        let target = routing::NameType([0x03u8; 64]);
+        assert_eq!(db.accounts_within(&target, 3),
+                   vec![(routing::NameType([0x02u8; 64]), 2048), (routing::NameType([0x01u8; 64]), 1024),
+                        (routing::NameType([0x04u8; 64]), 4096)]);
+        let all: Vec<routing::NameType> = db.accounts_within(&target, 10).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(all, [0x02u8, 0x01, 0x04, 0x08, 0x10].iter().map(|&byte| routing::NameType([byte; 64])).collect::<Vec<_>>());
+        assert_eq!(db.accounts_within(&routing::NameType([0x10u8; 64]), 1), vec![(routing::NameType([0x10u8; 64]), 16384)]);
+        assert!(db.accounts_within(&target, 0).is_empty());
+
+        // over many names the order agrees with closer_to_target throughout
+        for _ in 0..200 {
+            let name: routing::NameType = routing::test_utils::Random::generate_random();
+            assert_eq!(db.put_data(&name, 1024), Ok(()));
+        }
+        let target: routing::NameType = routing::test_utils::Random::generate_random();
+        let nearest: Vec<routing::NameType> = db.accounts_within(&target, 100).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(nearest.len(), 100);
+        assert!(nearest.windows(2).all(|pair| routing::closer_to_target(&pair[0], &pair[1], &target)));
+    }
+
+    #[test]
+    fn compact() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (0..10).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in names.iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+            let account = db.storage.get_mut(name).unwrap();
+            account.enable_event_log(64);
+            account.enable_pending_puts(64);
+            assert_eq!(db.delete_data(name, 512), 512);
+        }
+        for name in names[5..].iter() {
+            let _ = db.remove_account(name);
+        }
+        let before = db.estimated_memory_bytes();
+        let reclaimed = db.compact();
+        assert!(reclaimed > 0);
+        assert_eq!(db.estimated_memory_bytes(), before - reclaimed);
+
+        // nothing held is changed, and the logs still take new events
+        assert_eq!(db.storage.len(), 5);
+        assert_eq!(db.total_stored(), 5 * 512);
+        let account = db.storage.get_mut(&names[0]).unwrap();
+        assert_eq!(account.recent_events().len(), 1);
+        assert_eq!(account.put_data(512), Ok(()));
+        assert_eq!(account.recent_events().len(), 2);
+    }
+
+    #[test]
+    fn estimated_memory_bytes() {
+        let mut db = PmidManagerDatabase::new();
+        let empty = db.estimated_memory_bytes();
+        assert!(empty > 0);
+        let names: Vec<routing::NameType> = (0..20).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in names[..10].iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+        let ten = db.estimated_memory_bytes();
+        for name in names[10..].iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+        let twenty = db.estimated_memory_bytes();
+        assert!(ten > empty);
+        assert_eq!(twenty - ten, ten - empty);
+
+        // event logs and pending puts add to the account's share
+        db.storage.get_mut(&names[0]).unwrap().enable_event_log(16);
+        let logged = db.estimated_memory_bytes();
+        assert!(logged > twenty);
+        db.storage.get_mut(&names[1]).unwrap().enable_pending_puts(16);
+        assert!(db.estimated_memory_bytes() > logged);
+    }
+
+    #[test]
+    fn stats() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.stats(), PmidManagerStats { account_count: 0, total_stored: 0, total_lost: 0, total_offered: 0 });
+        let names: Vec<routing::NameType> = (0..3).map(|_| routing::test_utils::Random::generate_random()).collect();
+        assert_eq!(db.put_data(&names[0], 1024), Ok(()));
+        assert_eq!(db.put_data(&names[1], 2048), Ok(()));
+        assert_eq!(db.put_data(&names[2], 4096), Ok(()));
+        db.storage.get_mut(&names[2]).unwrap().handle_lost_data(1000);
+
+        let stats = db.stats();
+        assert_eq!(stats.account_count, 3);
+        assert_eq!(stats.total_stored, 1024 + 2048 + 4096 - 1000);
+        assert_eq!(stats.total_lost, 1000);
+        assert_eq!(stats.total_offered, 3 * 1073741824);
+        assert_eq!(db.exist(&names[0]), true);
+        assert_eq!(db.stats(), stats);
+
+        db.storage.get_mut(&names[0]).unwrap().set_available_size(::std::u64::MAX);
+        assert_eq!(db.stats().total_offered, ::std::u64::MAX);
+    }
+
+    #[test]
+    fn capacity_summary() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.capacity_summary(),
+                   CapacitySummary { managed_nodes: 0, total_offered: 0, total_stored: 0, utilisation: 1.0 });
+        let names: Vec<routing::NameType> = (0..4).map(|_| routing::test_utils::Random::generate_random()).collect();
+        assert_eq!(db.put_data(&names[0], 1073741824), Ok(()));
+        assert_eq!(db.put_data(&names[1], 536870912), Ok(()));
+        assert_eq!(db.put_data(&names[2], 0), Ok(()));
+        assert_eq!(db.put_data(&names[3], 536870912), Ok(()));
+        db.handle_lost_data(&names[3], 536870912);
+
+        assert_eq!(db.capacity_summary(),
+                   CapacitySummary { managed_nodes: 4, total_offered: 4 * 1073741824, total_stored: 1073741824 + 536870912,
+                                     utilisation: 0.375 });
+
+        for name in names.iter() {
+            db.storage.get_mut(name).unwrap().set_available_size(0);
+        }
+        let summary = db.capacity_summary();
+        assert_eq!(summary.total_offered, 0);
+        assert_eq!(summary.utilisation, 1.0);
+    }
+
+    #[test]
+    fn size_histogram() {
+        let mut db = PmidManagerDatabase::new();
+        assert_eq!(db.size_histogram(&[1024]), vec![0, 0]);
+        for size in vec![0, 1, 1023, 1024, 1025, 4095, 4096, 1073741824] {
+            let name: routing::NameType = routing::test_utils::Random::generate_random();
+            assert_eq!(db.put_data(&name, size), Ok(()));
+        }
+        assert_eq!(db.size_histogram(&[1024, 4096]), vec![3, 3, 2]);
+        assert_eq!(db.size_histogram(&[1, 1024, 4096]), vec![1, 2, 3, 2]);
+        assert_eq!(db.size_histogram(&[0]), vec![0, 8]);
+        assert_eq!(db.size_histogram(&[1073741825]), vec![8, 0]);
+        assert_eq!(db.size_histogram(&[1024, 1024]), vec![3, 0, 5]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn size_histogram_unsorted() {
+        let db = PmidManagerDatabase::new();
+        let _ = db.size_histogram(&[4096, 1024]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn size_histogram_empty() {
+        let db = PmidManagerDatabase::new();
+        let _ = db.size_histogram(&[]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = ::std::env::temp_dir().join(format!("pmid_manager_{}.db", rand::random::<u64>()));
+        let loaded = PmidManagerDatabase::load_from_file(&path).unwrap();
+        assert!(loaded.storage.is_empty());
+
+        let mut db = PmidManagerDatabase::new();
+        let mut names: Vec<routing::NameType> = Vec::new();
+        for i in 0..10 {
+            let name: routing::NameType = routing::test_utils::Random::generate_random();
+            assert_eq!(db.put_data(&name, 1024 * i), Ok(()));
+            names.push(name);
+        }
+        db.storage.get_mut(&names[0]).unwrap().handle_lost_data(512);
+        db.save_to_file(&path).unwrap();
+
+        let mut loaded = PmidManagerDatabase::load_from_file(&path).unwrap();
+        assert_eq!(loaded.storage.len(), names.len());
+        for name in names.iter() {
+            assert_eq!(loaded.storage.peek(name), db.storage.peek(name));
+            assert_eq!(loaded.exist(name), true);
+        }
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn database_serialisation() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (1..6u8).map(|i| routing::NameType([i; 64])).collect();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(db.put_data(name, 1024 * (i as u64 + 1)), Ok(()));
+        }
+        db.handle_lost_data(&names[1], 512);
+        db.storage.get_mut(&names[2]).unwrap().set_available_size(4096);
+
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&db]).unwrap();
+        let mut d = cbor::Decoder::from_bytes(e.as_bytes());
+        let decoded: PmidManagerDatabase = d.decode().next().unwrap().unwrap();
+
+        let mut expected: Vec<(&routing::NameType, &PmidManagerAccount)> = db.iter().collect();
+        let mut actual: Vec<(&routing::NameType, &PmidManagerAccount)> = decoded.iter().collect();
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+        assert_eq!(decoded.total_stored(), db.total_stored());
+    }
+
+    #[test]
+    fn snapshot_accounts() {
+        let shared = SharedPmidManagerDatabase::new(PmidManagerDatabase::new());
+        let names: Vec<routing::NameType> = (1..4u8).map(|i| routing::NameType([i; 64])).collect();
+        for name in names.iter() {
+            assert_eq!(shared.put_data(name, 1024), Ok(()));
+        }
+        let snapshot = shared.snapshot_accounts();
+        let held = snapshot.clone();
+        assert_eq!(snapshot.len(), 3);
+
+        assert_eq!(shared.put_data(&names[0], 1024), Ok(()));
+        assert_eq!(shared.delete_data(&names[1], 1024), 1024);
+        assert_eq!(shared.put_data(&routing::NameType([9u8; 64]), 1024), Ok(()));
+        let _ = shared.retrieve_all_and_reset(&names);
+        assert_eq!(shared.stats().account_count, 1);
+
+        assert_eq!(held.len(), 3);
+        for &(ref name, ref account) in held.iter() {
+            assert!(names.contains(name));
+            assert_eq!(account.get_stored_total_size(), 1024);
+        }
+        assert_eq!(shared.snapshot_accounts().len(), 1);
+    }
+
+    #[test]
+    fn json() {
+        let mut account = PmidManagerAccount::with_offered_space(4096);
+        assert_eq!(account.put_data(2048), Ok(()));
+        account.handle_lost_data(1024);
+        let serialised = account.to_json();
+        assert_eq!(serialised, "{\"stored_total_size\":1024,\"lost_total_size\":1024,\"offered_space\":4096}");
+        assert_eq!(PmidManagerAccount::from_json(&serialised).unwrap(), account);
+        assert!(PmidManagerAccount::from_json("{\"stored_total_size\":1024}").is_err());
+        assert!(PmidManagerAccount::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn export_csv() {
+        let mut db = PmidManagerDatabase::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        db.export_csv(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "name,stored_total_size,lost_total_size,offered_space\n");
+
+        let name_1 = routing::NameType([1u8; 64]);
+        let name_2 = routing::NameType([0xabu8; 64]);
+        assert_eq!(db.put_data(&name_1, 1024), Ok(()));
+        assert_eq!(db.put_data(&name_2, 4096), Ok(()));
+        db.handle_lost_data(&name_2, 1024);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        db.export_csv(&mut buffer).unwrap();
+        let exported = String::from_utf8(buffer).unwrap();
+        let mut lines: Vec<&str> = exported.lines().collect();
+        assert_eq!(lines.remove(0), "name,stored_total_size,lost_total_size,offered_space");
+        lines.sort();
+        let hex_1: String = (0..64).map(|_| "01").collect();
+        let hex_2: String = (0..64).map(|_| "ab").collect();
+        assert_eq!(lines, vec![format!("{},1024,0,1073741824", hex_1), format!("{},3072,1024,1073741824", hex_2)]);
+        assert_eq!(db.stats().account_count, 2);
+    }
+
+    #[cfg(feature = "use-bincode")]
+    #[test]
+    fn bincode() {
+        use self::routing::sendable::Sendable;
+        let mut account = PmidManagerAccount::new();
+        assert_eq!(account.put_data(1024), Ok(()));
+        account.handle_lost_data(512);
+        let serialised = account.to_bincode();
+        assert_eq!(PmidManagerAccount::from_bincode(&serialised).unwrap(), account);
+        assert!(PmidManagerAccount::from_bincode(&serialised[1..]).is_err());
+
+        // three fixed width u64s, against 56 bytes of cbor which carries the field names
+        let default_account = PmidManagerAccount::new();
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&default_account]).unwrap();
+        assert_eq!(default_account.to_bincode().len(), 24);
+        assert!(default_account.to_bincode().len() < e.as_bytes().len());
+
+        let mut sender = PmidManagerDatabase::new();
+        let mut receiver = PmidManagerDatabase::new();
+        let name: routing::NameType = routing::test_utils::Random::generate_random();
+        assert_eq!(sender.put_data(&name, 1024), Ok(()));
+        for element in sender.retrieve_all_and_reset_bincode(&vec![name.clone()]) {
+            assert_eq!(element.type_tag(), ::pmid_manager::PMID_MANAGER_ACCOUNT_TAG);
+            assert_eq!(receiver.handle_account_transfer_bincode(element.name(), &element.serialised_contents()), Ok(()));
+        }
+        assert!(receiver.handle_account_transfer_bincode(name.clone(), &[0u8]).is_err());
+        assert_eq!(sender.exist(&name), false);
+        assert_eq!(sender.metrics().last_transfer_accounts, 1);
+        assert_eq!(sender.metrics().last_transfer_bytes, 24);
+        assert_eq!(receiver.usage(&name), Some((1024, 0, 1073741824)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let mut account = PmidManagerAccount::new();
+        assert_eq!(account.put_data(1024), Ok(()));
+        account.handle_lost_data(512);
+        let serialised = account.to_serde_cbor();
+        assert_eq!(PmidManagerAccount::from_serde_cbor(&serialised).unwrap(), account);
+        assert!(PmidManagerAccount::from_serde_cbor(&serialised[1..]).is_err());
+
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&account]).unwrap();
+        assert_eq!(serialised, e.as_bytes());
+        let mut d = cbor::Decoder::from_bytes(&serialised[..]);
+        assert_eq!(d.decode::<PmidManagerAccount>().next().unwrap().unwrap(), account);
+    }
+
+    #[test]
+    fn proto_bytes() {
+        let account = PmidManagerAccount::from_parts(1024, 0, 4096);
+        // field 2 is zero so is left out; 1024 and 4096 are the varints 0x80 0x08 and 0x80 0x20
+        assert_eq!(account.to_proto_bytes(), vec![0x06, 0x08, 0x80, 0x08, 0x18, 0x80, 0x20]);
+        assert_eq!(PmidManagerAccount::from_proto_bytes(&account.to_proto_bytes()), Ok(account.clone()));
+
+        for account in vec![PmidManagerAccount::new(), PmidManagerAccount::empty(),
+                            PmidManagerAccount::from_parts(3072, 1536, 2048),
+                            PmidManagerAccount::from_parts(::std::u64::MAX, ::std::u64::MAX, ::std::u64::MAX)] {
+            assert_eq!(PmidManagerAccount::from_proto_bytes(&account.to_proto_bytes()), Ok(account));
+        }
+
+        let serialised = account.to_proto_bytes();
+        assert_eq!(PmidManagerAccount::from_proto_bytes(&[]), Err(DecodeError::Empty));
+        assert!(PmidManagerAccount::from_proto_bytes(&serialised[..serialised.len() - 1]).is_err());
+        assert!(PmidManagerAccount::from_proto_bytes(&serialised[1..]).is_err());
+    }
+
+    #[test]
+    fn recompute() {
+        let mut db = PmidManagerDatabase::new();
+        let names: Vec<routing::NameType> = (0..3).map(|_| routing::test_utils::Random::generate_random()).collect();
+        for name in names.iter() {
+            assert_eq!(db.put_data(name, 1024), Ok(()));
+        }
+        assert_eq!(db.delete_data(&names[0], 512), 512);
+        db.handle_lost_data(&names[1], 256);
+        let _ = db.remove_account(&names[2]);
+        assert_eq!(db.total_stored(), 512 + 768);
+
+        db.total_stored = ::std::u64::MAX;
+        db.recompute();
+        assert_eq!(db.total_stored(), 512 + 768);
+        assert_eq!(db.total_stored(), db.stats().total_stored);
+    }
+
+    #[test]
+    fn display() {
+        let account = PmidManagerAccount::from_parts(536870912, 0, 1073741824);
+        assert_eq!(account.to_string(), "512.00 MiB stored of 1.00 GiB offered (50.0%), 0 B lost");
+        let over_committed = PmidManagerAccount::from_parts(3072, 1536, 2048);
+        assert_eq!(over_committed.to_string(), "3.00 KiB stored of 2.00 KiB offered (150.0%), 1.50 KiB lost");
+    }
+
+    #[test]
+    fn with_offered_size() {
+        let account = PmidManagerAccount::with_offered_size("1GiB".parse().unwrap());
+        assert_eq!(account.get_offered_space(), 1073741824);
+        assert_eq!(account, PmidManagerAccount::new());
+    }
+
+    #[test]
+    fn negotiate() {
+        let policy = CapacityPolicy::new(1073741824);
+        // limited by the request
+        let account = PmidManagerAccount::negotiate(4096, 8192, &policy);
+        assert_eq!(account, PmidManagerAccount::with_offered_space(4096));
+        // by the space the node has free
+        assert_eq!(PmidManagerAccount::negotiate(8192, 4096, &policy).get_offered_space(), 4096);
+        // by the policy
+        assert_eq!(PmidManagerAccount::negotiate(::std::u64::MAX, ::std::u64::MAX, &policy), PmidManagerAccount::new());
+        assert_eq!(PmidManagerAccount::negotiate(2147483648, 4294967296, &policy).get_offered_space(), 1073741824);
+
+        assert_eq!(PmidManagerAccount::negotiate(4096, 4096, &CapacityPolicy::new(4096)).get_offered_space(), 4096);
+        assert_eq!(PmidManagerAccount::negotiate(4096, 0, &policy), PmidManagerAccount::empty());
+        assert_eq!(account.get_stored_total_size(), 0);
+        assert_eq!(account.get_lost_total_size(), 0);
+    }
+
+    #[test]
+    fn from_parts() {
+        let account = PmidManagerAccount::from_parts(1024, 512, 4096);
+        assert_eq!(account.get_stored_total_size(), 1024);
+        assert_eq!(account.get_lost_total_size(), 512);
+        assert_eq!(account.get_offered_space(), 4096);
+        assert_eq!(account.get_status(), AccountStatus::Online);
+        assert_eq!(account.can_put_with_policy(3072, OverCommitPolicy::Strict), true);
+        assert_eq!(account.can_put_with_policy(3073, OverCommitPolicy::Strict), false);
+
+        let over_committed = PmidManagerAccount::from_parts(8192, 0, 4096);
+        assert_eq!(over_committed.get_stored_total_size(), 8192);
+        assert_eq!(over_committed.can_put_with_policy(0, OverCommitPolicy::Strict), false);
+        assert_eq!(PmidManagerAccount::from_parts(0, 0, 0), PmidManagerAccount::empty());
+    }
+
+    #[test]
+    fn empty_account() {
+        let mut account = PmidManagerAccount::empty();
+        assert_eq!(account.get_offered_space(), 0);
+        for &size in [1, 1024, ::std::u64::MAX].iter() {
+            assert_eq!(account.put_data(size), Err(PutError::NotEnoughSpace { requested: size, available: 0 }));
+            assert_eq!(account.can_put_with_policy(size, OverCommitPolicy::Ratio(2.0)), false);
+        }
+        assert_eq!(account.put_data(0), Ok(()));
+        assert_eq!(account.get_stored_total_size(), 0);
+
+        let mut e = cbor::Encoder::from_memory();
+        e.encode(&[&account]).unwrap();
+        let mut d = cbor::Decoder::from_bytes(e.as_bytes());
+        let decoded: PmidManagerAccount = d.decode().next().unwrap().unwrap();
+        assert_eq!(decoded, account);
+        assert_eq!(decoded.get_offered_space(), 0);
     }
 
     #[test]
@@ -18,11 +18,106 @@
 #![allow(dead_code)]
 
 mod database;
+use cbor;
 use routing;
 use routing::NameType;
 use routing::types::DestinationAddress;
 use routing::generic_sendable_type;
-pub use self::database::PmidManagerAccount;
+pub use self::database::{AccountEvent, AccountEventKind, AccountStatus, OverCommitPolicy, PmidManagerAccount,
+                         PmidManagerDatabase};
+use flate2::Crc;
+use std::fmt;
+
+/// Type tag of the serialised PmidManagerAccount sent on during churn.
+pub const PMID_MANAGER_ACCOUNT_TAG: u64 = 1001;
+
+/// Version byte prepended to a serialised PmidManagerAccount by encode_versioned.
+pub const PMID_MANAGER_ACCOUNT_VERSION: u8 = 1;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum DecodeError {
+  Empty,
+  UnknownVersion(u8),
+  InvalidAccount(String),
+  // the trailing checksum added by encode_with_checksum doesn't match the bytes before it
+  ChecksumMismatch,
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DecodeError::Empty => write!(formatter, "no version byte in serialised PmidManagerAccount"),
+      DecodeError::UnknownVersion(version) =>
+          write!(formatter, "unknown PmidManagerAccount version {} (expected {})", version, PMID_MANAGER_ACCOUNT_VERSION),
+      DecodeError::InvalidAccount(ref error) => write!(formatter, "invalid PmidManagerAccount: {}", error),
+      DecodeError::ChecksumMismatch => write!(formatter, "checksum mismatch in serialised PmidManagerAccount"),
+    }
+  }
+}
+
+// the cbor encoding of account, preceded by PMID_MANAGER_ACCOUNT_VERSION
+pub fn encode_versioned(account: &PmidManagerAccount) -> Vec<u8> {
+  let mut e = cbor::Encoder::from_memory();
+  e.encode(&[account]).unwrap();
+  let mut serialised = vec![PMID_MANAGER_ACCOUNT_VERSION];
+  serialised.extend(e.as_bytes().iter().cloned());
+  serialised
+}
+
+pub fn decode_versioned(serialised: &[u8]) -> Result<PmidManagerAccount, DecodeError> {
+  match serialised.first() {
+    None => Err(DecodeError::Empty),
+    Some(&PMID_MANAGER_ACCOUNT_VERSION) => {
+      let mut d = cbor::Decoder::from_bytes(&serialised[1..]);
+      match d.decode().next() {
+        Some(Ok(account)) => Ok(account),
+        Some(Err(error)) => Err(DecodeError::InvalidAccount(error.to_string())),
+        None => Err(DecodeError::InvalidAccount("no account after version byte".to_string())),
+      }
+    }
+    Some(&version) => Err(DecodeError::UnknownVersion(version)),
+  }
+}
+
+// encode_versioned followed by the big endian CRC32 of everything before it
+pub fn encode_with_checksum(account: &PmidManagerAccount) -> Vec<u8> {
+  let mut serialised = encode_versioned(account);
+  let checksum = crc32(&serialised);
+  serialised.extend((0..4).map(|i| (checksum >> (24 - 8 * i)) as u8));
+  serialised
+}
+
+pub fn decode_with_checksum(serialised: &[u8]) -> Result<PmidManagerAccount, DecodeError> {
+  if serialised.is_empty() {
+    return Err(DecodeError::Empty);
+  }
+  if serialised.len() < 4 {
+    return Err(DecodeError::ChecksumMismatch);
+  }
+  let (payload, trailer) = serialised.split_at(serialised.len() - 4);
+  let checksum = trailer.iter().fold(0u32, |checksum, &byte| (checksum << 8) | byte as u32);
+  if crc32(payload) != checksum {
+    return Err(DecodeError::ChecksumMismatch);
+  }
+  decode_versioned(payload)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = Crc::new();
+  crc.update(bytes);
+  crc.sum()
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum PutError {
+  NotEnoughSpace { requested: u64, available: u64 },
+  Overflow,
+  // the name is reserved, see PmidManagerDatabase::with_name_validation
+  InvalidName,
+  // a new account is needed but the database already holds as many as it may, see
+  // PmidManagerDatabase::with_max_accounts
+  AtCapacity,
+}
 
 pub struct PmidManager {
   db_ : database::PmidManagerDatabase
@@ -34,7 +129,7 @@ impl PmidManager {
   }
 
   pub fn handle_put(&mut self, dest_address: &DestinationAddress, data : &Vec<u8>) ->Result<routing::Action, routing::RoutingError> {
-    if self.db_.put_data(&dest_address.dest, data.len() as u64) {
+    if self.db_.put_data(&dest_address.dest, data.len() as u64).is_ok() {
       let mut destinations : Vec<NameType> = Vec::new();
       destinations.push(dest_address.dest.clone());
       Ok(routing::Action::SendOn(destinations))
@@ -52,7 +147,8 @@ impl PmidManager {
 mod test {
   use cbor;
   use routing;
-  use super::{PmidManager};
+  use super::{PmidManager, PmidManagerAccount, DecodeError, PMID_MANAGER_ACCOUNT_VERSION, encode_versioned,
+              decode_versioned, encode_with_checksum, decode_with_checksum};
   use maidsafe_types::*;
   use routing::types::*;
 
@@ -77,4 +173,43 @@ mod test {
       routing::Action::Reply(_) => panic!("Unexpected"),
     }
   }
+
+  #[test]
+  fn versioned_serialisation() {
+    let mut account = PmidManagerAccount::new();
+    assert_eq!(account.put_data(1024), Ok(()));
+    let serialised = encode_versioned(&account);
+    assert_eq!(serialised[0], PMID_MANAGER_ACCOUNT_VERSION);
+    assert_eq!(decode_versioned(&serialised), Ok(account));
+
+    let mut bumped = serialised.clone();
+    bumped[0] = PMID_MANAGER_ACCOUNT_VERSION + 1;
+    let error = decode_versioned(&bumped).unwrap_err();
+    assert_eq!(error, DecodeError::UnknownVersion(PMID_MANAGER_ACCOUNT_VERSION + 1));
+    assert_eq!(error.to_string(), "unknown PmidManagerAccount version 2 (expected 1)");
+
+    assert_eq!(decode_versioned(&[]), Err(DecodeError::Empty));
+    match decode_versioned(&serialised[..serialised.len() - 1]) {
+      Err(DecodeError::InvalidAccount(_)) => (),
+      result => panic!("Unexpected {:?}", result),
+    }
+  }
+
+  #[test]
+  fn checksummed_serialisation() {
+    let mut account = PmidManagerAccount::new();
+    assert_eq!(account.put_data(1024), Ok(()));
+    let serialised = encode_with_checksum(&account);
+    assert_eq!(&serialised[..serialised.len() - 4], &encode_versioned(&account)[..]);
+    assert_eq!(decode_with_checksum(&serialised), Ok(account));
+
+    for index in 0..serialised.len() {
+      let mut corrupted = serialised.clone();
+      corrupted[index] ^= 0x10;
+      assert_eq!(decode_with_checksum(&corrupted), Err(DecodeError::ChecksumMismatch));
+    }
+    assert_eq!(decode_with_checksum(&serialised[..3]), Err(DecodeError::ChecksumMismatch));
+    assert_eq!(decode_with_checksum(&[]), Err(DecodeError::Empty));
+    assert_eq!(DecodeError::ChecksumMismatch.to_string(), "checksum mismatch in serialised PmidManagerAccount");
+  }
 }
@@ -0,0 +1,155 @@
+// Copyright 2015 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.0.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+// just enough of the protobuf wire format for messages of uint64 fields, as used by the accounts'
+// to_proto_bytes. A message is written length-delimited, as by writeDelimitedTo: its length in
+// bytes as a varint, then each field as a varint key of (field number << 3), the varint wire type
+// being 0, followed by the value as a varint.
+
+#![allow(dead_code)]
+
+// fields are written in the order given; as in proto3, those with a value of zero are left out
+pub fn encode_delimited(fields: &[(u32, u64)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    for &(number, value) in fields.iter() {
+        if value != 0 {
+            encode_varint((number as u64) << 3, &mut message);
+            encode_varint(value, &mut message);
+        }
+    }
+    let mut delimited = Vec::with_capacity(message.len() + 1);
+    encode_varint(message.len() as u64, &mut delimited);
+    delimited.extend(message);
+    delimited
+}
+
+// sets values[n - 1] to the value of field n of the message, leaving it as it is if the field is
+// missing and taking the last if it is repeated. Fields numbered beyond values are skipped whatever
+// their wire type, so a message from a newer schema still decodes; nothing may follow the message.
+pub fn decode_delimited(serialised: &[u8], values: &mut [u64]) -> Result<(), String> {
+    let mut position = 0;
+    let length = try!(decode_varint(serialised, &mut position));
+    if length > (serialised.len() - position) as u64 {
+        return Err(format!("message of {} bytes is truncated", length));
+    }
+    let end = position + length as usize;
+    if end != serialised.len() {
+        return Err(format!("{} bytes after the message", serialised.len() - end));
+    }
+    let message = &serialised[..end];
+    while position < end {
+        let key = try!(decode_varint(message, &mut position));
+        let (number, wire_type) = (key >> 3, key & 7);
+        if number == 0 {
+            return Err("field number 0".to_string());
+        }
+        if number <= values.len() as u64 {
+            if wire_type != 0 {
+                return Err(format!("field {} has wire type {} rather than a varint", number, wire_type));
+            }
+            values[number as usize - 1] = try!(decode_varint(message, &mut position));
+            continue;
+        }
+        let skipped = match wire_type {
+            0 => { let _ = try!(decode_varint(message, &mut position)); 0 }
+            1 => 8,
+            2 => try!(decode_varint(message, &mut position)),
+            5 => 4,
+            _ => return Err(format!("field {} has unknown wire type {}", number, wire_type)),
+        };
+        if skipped > (end - position) as u64 {
+            return Err(format!("field {} is truncated", number));
+        }
+        position += skipped as usize;
+    }
+    Ok(())
+}
+
+fn encode_varint(mut value: u64, output: &mut Vec<u8>) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn decode_varint(input: &[u8], position: &mut usize) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..10).map(|i| i * 7) {
+        let byte = match input.get(*position) {
+            Some(&byte) => byte,
+            None => return Err("varint is truncated".to_string()),
+        };
+        *position += 1;
+        // the tenth byte holds only the top bit of a u64
+        if shift == 63 && byte > 1 {
+            return Err("varint is more than 64 bits".to_string());
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is more than 64 bits".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_delimited, decode_varint, encode_delimited, encode_varint};
+
+    #[test]
+    fn varint() {
+        let encoded = |value: u64| { let mut output = Vec::new(); encode_varint(value, &mut output); output };
+        assert_eq!(encoded(0), vec![0x00]);
+        assert_eq!(encoded(127), vec![0x7f]);
+        assert_eq!(encoded(128), vec![0x80, 0x01]);
+        assert_eq!(encoded(300), vec![0xac, 0x02]);
+        assert_eq!(encoded(::std::u64::MAX), vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        for &value in [0u64, 1, 127, 128, 300, 1 << 32, ::std::u64::MAX].iter() {
+            let mut position = 0;
+            assert_eq!(decode_varint(&encoded(value), &mut position), Ok(value));
+            assert_eq!(position, encoded(value).len());
+        }
+        assert!(decode_varint(&[0x80], &mut 0).is_err());
+        assert!(decode_varint(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02], &mut 0).is_err());
+    }
+
+    #[test]
+    fn delimited() {
+        let serialised = encode_delimited(&[(1, 150), (2, 0), (3, 1)]);
+        assert_eq!(serialised, vec![0x05, 0x08, 0x96, 0x01, 0x18, 0x01]);
+        let mut values = [0u64; 3];
+        assert_eq!(decode_delimited(&serialised, &mut values), Ok(()));
+        assert_eq!(values, [150, 0, 1]);
+        assert_eq!(encode_delimited(&[(1, 0)]), vec![0x00]);
+
+        // fields in another order, repeated, or unknown to us
+        let mut values = [0u64; 2];
+        let unknown = [0x0e, 0x10, 0x02, 0x08, 0x01, 0x08, 0x03, 0x1a, 0x02, 0xaa, 0xbb, 0x25, 0, 0, 0, 0];
+        assert_eq!(decode_delimited(&unknown[..15], &mut values), Err("field 4 is truncated".to_string()));
+        let mut unknown = unknown.to_vec();
+        unknown[0] = 0x0f;
+        assert_eq!(decode_delimited(&unknown, &mut values), Ok(()));
+        assert_eq!(values, [3, 2]);
+
+        assert!(decode_delimited(&[], &mut values).is_err());
+        assert!(decode_delimited(&[0x03, 0x08, 0x01], &mut values).is_err());
+        assert!(decode_delimited(&[0x02, 0x08, 0x01, 0x00], &mut values).is_err());
+        assert!(decode_delimited(&[0x02, 0x09, 0x01], &mut values).is_err());
+        assert!(decode_delimited(&[0x02, 0x00, 0x01], &mut values).is_err());
+    }
+}
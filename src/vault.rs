@@ -101,7 +101,7 @@ impl routing::node_interface::Interface for VaultFacade {
 
     fn handle_churn(&mut self, close_group: Vec<NameType>) -> Vec<routing::generic_sendable_type::GenericSendableType> {
         let mut dm = self.data_manager.retrieve_all_and_reset();
-        let mut mm = self.maid_manager.retrieve_all_and_reset();
+        let mut mm = self.maid_manager.retrieve_all_and_reset(&close_group);
         let mut pm = self.pmid_manager.retrieve_all_and_reset(&close_group);
         let mut vh = self.version_handler.retrieve_all_and_reset();
 
@@ -338,18 +338,19 @@ impl VaultFacade {
 
         {// MaidManager - churn handling
             maid_manager_put(&mut vault, from.clone(), dest.clone(), data.name().clone(), data_as_vec.clone());
-            let churn_data = vault.handle_churn(Vec::<NameType>::with_capacity(0));
+            let churn_data = vault.handle_churn(vec![from.clone()]);
             assert!(churn_data.len() == 1);
             assert!(churn_data[0].name() == from);
             // MaidManagerAccount
             let sendable: GenericSendableType = churn_data[0].clone();
             assert_eq!(sendable.name(), from.clone());
+            assert_eq!(sendable.type_tag(), maid_manager::MAID_MANAGER_ACCOUNT_TAG);
 
             let mut decoder = cbor::Decoder::from_bytes(sendable.serialised_contents());
             let maid_manager: maid_manager::MaidManagerAccount = decoder.decode().next().unwrap().unwrap();
             assert_eq!(maid_manager.get_data_stored(), 1024);
 
-            assert!(vault.maid_manager.retrieve_all_and_reset().is_empty());
+            assert!(vault.maid_manager.retrieve_all_and_reset(&vec![from.clone()]).is_empty());
         }
 
         add_nodes_to_table(&mut vault, 10);